@@ -0,0 +1,43 @@
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use super::Backend;
+
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: String) -> LocalBackend {
+        LocalBackend { root: PathBuf::from(root) }
+    }
+}
+
+impl Backend for LocalBackend {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = self.root.join(key);
+
+        if path.exists() {
+            return Ok(());
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write next to the target and rename, so a crash never leaves a truncated file under a valid hash.
+        let partial = path.with_extension("partial");
+        fs::File::create(&partial)?.write_all(bytes)?;
+        fs::rename(&partial, &path)?;
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut bytes = Vec::new();
+        fs::File::open(self.root.join(key))?.read_to_end(&mut bytes)?;
+
+        Ok(bytes)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,152 @@
+extern crate sha2;
+
+mod local;
+mod s3;
+
+use std::error::Error;
+use crate::config::{ArchiveConfig, ArchiveBackend};
+use crate::telegram;
+use crate::data::MediaType;
+use self::sha2::{Sha256, Digest};
+
+pub use self::local::LocalBackend;
+pub use self::s3::S3Backend;
+
+pub trait Backend: Send + Sync {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), Box<dyn Error>>;
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+pub struct Archiver {
+    backend: Box<dyn Backend>,
+    restore_chat_id: Option<i64>,
+}
+
+pub struct Archived {
+    pub path: String,
+    pub hash: String,
+}
+
+impl Archiver {
+    pub fn new(backend: Box<dyn Backend>, restore_chat_id: Option<i64>) -> Archiver {
+        Archiver { backend, restore_chat_id }
+    }
+
+    pub fn from_config(config: &ArchiveConfig) -> Archiver {
+        let backend: Box<dyn Backend> = match config.backend {
+            ArchiveBackend::Local { ref path } => Box::new(LocalBackend::new(path.clone())),
+            ArchiveBackend::S3 { ref endpoint, ref bucket, ref region, ref access_key, ref secret_key } =>
+                Box::new(S3Backend::new(endpoint.clone(), bucket.clone(), region.clone(), access_key.clone(), secret_key.clone())),
+        };
+
+        Archiver::new(backend, config.restore_chat_id)
+    }
+
+    pub fn archive(&self, api: &telegram::blocking::Api, file_id: String) -> Result<Archived, Box<dyn Error>> {
+        let bytes = api.download_file(file_id.clone())?;
+
+        let archived = self.store(&bytes)?;
+
+        info!(file_id:% = file_id; "Archived a file to {}", archived.path);
+
+        Ok(archived)
+    }
+
+    pub fn store(&self, bytes: &[u8]) -> Result<Archived, Box<dyn Error>> {
+        let hash = hex(&Sha256::digest(bytes));
+        let path = format!("{}/{}", &hash[..2], hash);
+
+        self.backend.put(&path, bytes)?;
+
+        Ok(Archived { path, hash })
+    }
+
+    pub fn read(&self, path: &str, hash: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let bytes = self.backend.get(path)?;
+
+        if hex(&Sha256::digest(&bytes)) != hash {
+            return Err(format!("Archived file {} does not match its hash", path).into());
+        }
+
+        Ok(bytes)
+    }
+
+    // Uploads the archived bytes again and returns the fresh file_id Telegram hands out for them.
+    pub fn restore(&self, api: &telegram::blocking::Api, media_type: &MediaType, path: &str, hash: &str) -> Result<String, Box<dyn Error>> {
+        let chat_id = self.restore_chat_id.ok_or("MEHU_ARCHIVE_RESTORE_CHAT_ID is required for restoring media.")?;
+        let bytes = self.read(path, hash)?;
+
+        let file_id = match *media_type {
+            MediaType::Photo => api.upload_photo(chat_id, bytes)?,
+            MediaType::Mpeg4Gif => api.upload_document(chat_id, bytes, format!("{}.mp4", hash))?,
+            MediaType::ImageGif => api.upload_document(chat_id, bytes, format!("{}.gif", hash))?,
+        };
+
+        Ok(file_id)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter()
+         .map(|b| format!("{:02x}", b))
+         .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use super::*;
+
+    static HELLO_HASH: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn empty_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mehubot-archive-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        dir
+    }
+
+    fn local_archiver(dir: &Path) -> Archiver {
+        Archiver::new(Box::new(LocalBackend::new(dir.to_string_lossy().into_owned())), None)
+    }
+
+    #[test]
+    fn local_backend_gets_what_was_put() {
+        let dir = empty_dir("round-trip");
+        let backend = LocalBackend::new(dir.to_string_lossy().into_owned());
+
+        backend.put("ab/abcd", b"hello").unwrap();
+        // The key names the content, so a second put keeps the first file.
+        backend.put("ab/abcd", b"other").unwrap();
+
+        assert_eq!(backend.get("ab/abcd").unwrap(), b"hello");
+        assert!(backend.get("ab/missing").is_err());
+        assert!(!dir.join("ab/abcd.partial").exists());
+    }
+
+    #[test]
+    fn files_are_stored_under_their_hash() {
+        let dir = empty_dir("layout");
+        let archived = local_archiver(&dir).store(b"hello").unwrap();
+
+        assert_eq!(archived.hash, HELLO_HASH);
+        assert_eq!(archived.path, format!("2c/{}", HELLO_HASH));
+        assert_eq!(fs::read(dir.join("2c").join(HELLO_HASH)).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn read_rejects_files_that_do_not_match_their_hash() {
+        let dir = empty_dir("mismatch");
+        let archiver = local_archiver(&dir);
+        let archived = archiver.store(b"hello").unwrap();
+
+        assert_eq!(archiver.read(&archived.path, &archived.hash).unwrap(), b"hello");
+
+        fs::write(dir.join(&archived.path), b"hello!").unwrap();
+
+        assert!(archiver.read(&archived.path, &archived.hash).is_err());
+    }
+}
\ No newline at end of file
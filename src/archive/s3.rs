@@ -0,0 +1,164 @@
+extern crate chrono;
+extern crate hmac;
+extern crate reqwest;
+extern crate sha2;
+
+use std::error::Error;
+use super::{Backend, hex};
+use self::chrono::Utc;
+use self::hmac::{Hmac, Mac};
+use self::reqwest::{Method, Url};
+use self::reqwest::blocking::Client;
+use self::reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use self::sha2::{Sha256, Digest};
+
+static SERVICE: &str = "s3";
+
+// Talks to any S3-compatible store (AWS, MinIO, ...) with path-style URLs and SigV4 signing.
+pub struct S3Backend {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    client: Client,
+}
+
+impl S3Backend {
+    pub fn new(endpoint: String, bucket: String, region: String, access_key: String, secret_key: String) -> S3Backend {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+
+        S3Backend { endpoint, bucket, region, access_key, secret_key, client: Client::new() }
+    }
+
+    fn signed_headers(&self, method: &str, url: &Url, payload: &[u8]) -> Result<HeaderMap, Box<dyn Error>> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(payload));
+
+        let canonical_request = canonical_request(method, url, &payload_hash, &amz_date);
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, SERVICE);
+        let string_to_sign = string_to_sign(&amz_date, &scope, &canonical_request);
+        let signature = hex(&hmac_sha256(&signing_key(&self.secret_key, &date, &self.region, SERVICE), string_to_sign.as_bytes()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-amz-date", HeaderValue::from_str(&amz_date)?);
+        headers.insert("x-amz-content-sha256", HeaderValue::from_str(&payload_hash)?);
+        headers.insert(AUTHORIZATION,
+                       HeaderValue::from_str(&format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                                                      self.access_key, scope, signature))?);
+
+        Ok(headers)
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url, Box<dyn Error>> {
+        Ok(Url::parse(&format!("{}/{}/{}", self.endpoint, self.bucket, key))?)
+    }
+}
+
+impl Backend for S3Backend {
+    fn put(&self, key: &str, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let url = self.object_url(key)?;
+        let headers = self.signed_headers("PUT", &url, bytes)?;
+
+        let response = self.client
+                           .request(Method::PUT, url)
+                           .headers(headers)
+                           .body(bytes.to_vec())
+                           .send()?;
+
+        if !response.status().is_success() {
+            return Err(format!("PUT of {} returned status {}", key, response.status()).into());
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let url = self.object_url(key)?;
+        let headers = self.signed_headers("GET", &url, &[])?;
+
+        let response = self.client
+                           .request(Method::GET, url)
+                           .headers(headers)
+                           .send()?;
+
+        if !response.status().is_success() {
+            return Err(format!("GET of {} returned status {}", key, response.status()).into());
+        }
+
+        Ok(response.bytes()?.to_vec())
+    }
+}
+
+// Signs the host and the two x-amz headers, the object key is already URL-safe.
+fn canonical_request(method: &str, url: &Url, payload_hash: &str, amz_date: &str) -> String {
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        None => url.host_str().unwrap_or("").to_string()
+    };
+
+    format!("{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, url.path(), host, payload_hash, amz_date, payload_hash)
+}
+
+fn string_to_sign(amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical_request.as_bytes())))
+}
+
+// The key for one day, region and service, derived from the secret key.
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+
+    hmac_sha256(&key, b"aws4_request")
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length.");
+    mac.update(message);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The examples from the AWS Signature Version 4 documentation.
+    static SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+    static EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn derives_the_documented_signing_key() {
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+
+        assert_eq!(hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn signs_the_documented_get_object_request() {
+        let canonical_request = format!("GET\n/test.txt\n\nhost:examplebucket.s3.amazonaws.com\nrange:bytes=0-9\nx-amz-content-sha256:{}\n\
+                                         x-amz-date:20130524T000000Z\n\nhost;range;x-amz-content-sha256;x-amz-date\n{}",
+                                        EMPTY_PAYLOAD_HASH, EMPTY_PAYLOAD_HASH);
+        let string_to_sign = string_to_sign("20130524T000000Z", "20130524/us-east-1/s3/aws4_request", &canonical_request);
+
+        assert_eq!(string_to_sign, "AWS4-HMAC-SHA256\n20130524T000000Z\n20130524/us-east-1/s3/aws4_request\n\
+                                    7344ae5b7ee6c3e7e6b0fe0640412a37625d1fbfff95c48bbb2dc43964946972");
+
+        let signature = hex(&hmac_sha256(&signing_key(SECRET_KEY, "20130524", "us-east-1", SERVICE), string_to_sign.as_bytes()));
+
+        assert_eq!(signature, "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41");
+    }
+
+    #[test]
+    fn canonical_request_signs_the_host_with_its_port() {
+        let url = Url::parse("http://localhost:9000/media/ab/abcd").unwrap();
+
+        assert_eq!(canonical_request("GET", &url, EMPTY_PAYLOAD_HASH, "20130524T000000Z"),
+                   format!("GET\n/media/ab/abcd\n\nhost:localhost:9000\nx-amz-content-sha256:{}\nx-amz-date:20130524T000000Z\n\n\
+                            host;x-amz-content-sha256;x-amz-date\n{}", EMPTY_PAYLOAD_HASH, EMPTY_PAYLOAD_HASH));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,299 @@
+extern crate base64;
+extern crate serde_json;
+
+use std::error::Error;
+use std::io::{BufRead, Write};
+use crate::archive::Archiver;
+use crate::data::{self, Entity, MediaType, Visibility};
+
+static BUNDLE_FORMAT: &str = "mehubot";
+static BUNDLE_VERSION: u32 = 2;
+
+// A bundle is JSON Lines: one Header line followed by one MediaRecord line per media.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MediaRecord {
+    file_id: String,
+    media_type: MediaType,
+    #[serde(default)]
+    archive_hash: Option<String>,
+    // Version 1 bundles carry no owner or visibility, their media stays public like all media was then.
+    #[serde(default)]
+    owner_id: Option<i64>,
+    #[serde(default = "public")]
+    visibility: Visibility,
+    #[serde(default)]
+    shared_with: Vec<i64>,
+    #[serde(default)]
+    nsfw: bool,
+    #[serde(default)]
+    pending: bool,
+    #[serde(default)]
+    hidden: bool,
+    #[serde(default)]
+    tags: Vec<TagRecord>,
+    #[serde(default)]
+    data: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TagRecord {
+    tag: String,
+    counter: i64,
+}
+
+pub struct ImportSummary {
+    pub media: usize,
+    pub tags: usize,
+    pub files: usize,
+}
+
+fn public() -> Visibility {
+    Visibility::Public
+}
+
+pub fn export<W: Write>(db: &mut data::DB, archiver: Option<&Archiver>, with_files: bool, out: &mut W) -> Result<usize, Box<dyn Error>> {
+    if with_files && archiver.is_none() {
+        return Err("Exporting files requires a configured archive.".into());
+    }
+
+    writeln!(out, "{}", serde_json::to_string(&Header { format: BUNDLE_FORMAT.to_string(), version: BUNDLE_VERSION })?)?;
+
+    let media = db.read_all_media();
+
+    for m in &media {
+        let tags = db.read_tags_with_mediaid(m.media_id)
+                     .into_iter()
+                     .filter_map(|t| match t {
+                         Entity::Tag { tag, counter, .. } => Some(TagRecord { tag, counter }),
+                         _ => None
+                     })
+                     .collect();
+
+        let data = match (archiver, &m.archive_path, &m.archive_hash) {
+            (Some(archiver), Some(path), Some(hash)) if with_files => Some(base64::encode(&archiver.read(path, hash)?)),
+            _ => None
+        };
+
+        let record = MediaRecord {
+            file_id: m.file_id.clone(),
+            media_type: m.media_type.clone(),
+            archive_hash: m.archive_hash.clone(),
+            owner_id: m.owner_id,
+            visibility: m.visibility,
+            shared_with: m.shared_with.clone(),
+            nsfw: m.nsfw,
+            pending: m.pending,
+            hidden: m.hidden,
+            tags,
+            data,
+        };
+
+        writeln!(out, "{}", serde_json::to_string(&record)?)?;
+    }
+
+    out.flush()?;
+
+    Ok(media.len())
+}
+
+// Merges the bundle into the library: media is matched on file_id, media already stored keeps its owner, visibility
+// and flags, tags are added and counters keep the larger value, so importing the same bundle twice changes nothing.
+pub fn import<R: BufRead>(db: &mut data::DB, archiver: Option<&Archiver>, input: R) -> Result<ImportSummary, Box<dyn Error>> {
+    let mut lines = input.lines();
+
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err("Bundle is empty.".into())
+    };
+
+    if header.format != BUNDLE_FORMAT {
+        return Err(format!("Not a bundle, format is {}", header.format).into());
+    }
+
+    if header.version > BUNDLE_VERSION {
+        return Err(format!("Unsupported bundle version {}", header.version).into());
+    }
+
+    let mut summary = ImportSummary { media: 0, tags: 0, files: 0 };
+
+    for line in lines {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: MediaRecord = serde_json::from_str(&line)?;
+        let media_id = db.restore_media(record.file_id.clone(), record.media_type, record.owner_id, record.visibility, &record.shared_with, record.nsfw, record.pending, record.hidden);
+        summary.media += 1;
+
+        for t in record.tags {
+            db.insert(Entity::Tag { media_id, tag: t.tag.clone(), counter: 0 });
+            db.merge_tag_counter(media_id, &t.tag, t.counter);
+            summary.tags += 1;
+        }
+
+        if let Some(data) = record.data {
+            let archiver = match archiver {
+                Some(a) => a,
+                None => {
+                    warn!(file_id:% = record.file_id; "Skipping a file, no archive configured");
+                    continue;
+                }
+            };
+
+            let archived = archiver.store(&base64::decode(&data)?)?;
+
+            if let Some(ref hash) = record.archive_hash {
+                if *hash != archived.hash {
+                    return Err(format!("File of {} does not match its hash", record.file_id).into());
+                }
+            }
+
+            db.update_media_archive(media_id, &archived.path, &archived.hash);
+            summary.files += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // file_id, media type and (tag, counter) pairs of a media.
+    type MediaSnapshot = (String, String, Vec<(String, i64)>);
+
+    // Every media in insertion order.
+    fn library(db: &mut data::DB) -> Vec<MediaSnapshot> {
+        db.read_all_media()
+          .into_iter()
+          .map(|m| {
+              let tags = db.read_tags_with_mediaid(m.media_id)
+                           .into_iter()
+                           .filter_map(|t| match t {
+                               Entity::Tag { tag, counter, .. } => Some((tag, counter)),
+                               _ => None
+                           })
+                           .collect();
+
+              (m.file_id, format!("{:?}", m.media_type), tags)
+          })
+          .collect()
+    }
+
+    fn add_media(db: &mut data::DB, file_id: &str, media_type: MediaType, tags: &[(&str, i64)]) -> i64 {
+        let media_id = db.insert(Entity::Media { id: 0, file_id: file_id.to_string(), media_type });
+
+        for &(tag, counter) in tags {
+            db.insert(Entity::Tag { media_id, tag: tag.to_string(), counter: 0 });
+            db.merge_tag_counter(media_id, tag, counter);
+        }
+
+        media_id
+    }
+
+    fn export_bundle(db: &mut data::DB) -> Vec<u8> {
+        let mut out = Vec::new();
+        export(db, None, false, &mut out).expect("Failed to export bundle.");
+        out
+    }
+
+    #[test]
+    fn round_trip_keeps_media_tags_and_counters() {
+        let source_conn = data::Connection::new(":memory:".to_string());
+        let mut source = data::DB::new(&source_conn);
+        add_media(&mut source, "photo", MediaType::Photo, &[("cat", 3), ("funny", 0)]);
+        add_media(&mut source, "mp4", MediaType::Mpeg4Gif, &[("dance", 7)]);
+        add_media(&mut source, "gif", MediaType::ImageGif, &[]);
+
+        let bundle = export_bundle(&mut source);
+
+        let target_conn = data::Connection::new(":memory:".to_string());
+        let mut target = data::DB::new(&target_conn);
+        let summary = import(&mut target, None, &bundle[..]).expect("Failed to import bundle.");
+
+        assert_eq!(summary.media, 3);
+        assert_eq!(summary.tags, 3);
+        assert_eq!(summary.files, 0);
+        assert_eq!(library(&mut target), library(&mut source));
+    }
+
+    #[test]
+    fn import_merges_and_never_lowers_counters() {
+        let source_conn = data::Connection::new(":memory:".to_string());
+        let mut source = data::DB::new(&source_conn);
+        add_media(&mut source, "photo", MediaType::Photo, &[("cat", 3), ("funny", 5)]);
+        add_media(&mut source, "gif", MediaType::ImageGif, &[("dance", 1)]);
+
+        let bundle = export_bundle(&mut source);
+
+        let target_conn = data::Connection::new(":memory:".to_string());
+        let mut target = data::DB::new(&target_conn);
+        add_media(&mut target, "photo", MediaType::Photo, &[("cat", 10), ("cute", 2)]);
+
+        import(&mut target, None, &bundle[..]).expect("Failed to import bundle.");
+        let merged = library(&mut target);
+
+        assert_eq!(merged, vec![
+            ("photo".to_string(), "Photo".to_string(), vec![("cat".to_string(), 10), ("cute".to_string(), 2), ("funny".to_string(), 5)]),
+            ("gif".to_string(), "ImageGif".to_string(), vec![("dance".to_string(), 1)]),
+        ]);
+
+        // A second import of the same bundle changes nothing.
+        import(&mut target, None, &bundle[..]).expect("Failed to import bundle again.");
+        assert_eq!(library(&mut target), merged);
+    }
+
+    #[test]
+    fn round_trip_keeps_owner_visibility_and_flags() {
+        let source_conn = data::Connection::new(":memory:".to_string());
+        let mut source = data::DB::new(&source_conn);
+        source.insert_media("shared".to_string(), MediaType::Photo, 5, Visibility::Groups, &[-100, -200], true, false);
+        source.restore_media("queued".to_string(), MediaType::ImageGif, Some(6), Visibility::Private, &[], false, true, false);
+        source.restore_media("reported".to_string(), MediaType::Mpeg4Gif, None, Visibility::Public, &[], false, false, true);
+
+        let bundle = export_bundle(&mut source);
+
+        let target_conn = data::Connection::new(":memory:".to_string());
+        let mut target = data::DB::new(&target_conn);
+        import(&mut target, None, &bundle[..]).expect("Failed to import bundle.");
+
+        let flags = |db: &mut data::DB| -> Vec<_> {
+            db.read_all_media()
+              .into_iter()
+              .map(|m| (m.file_id, m.owner_id, m.visibility, m.shared_with, m.nsfw, m.pending, m.hidden))
+              .collect()
+        };
+
+        assert_eq!(flags(&mut target), vec![
+            ("shared".to_string(), Some(5), Visibility::Groups, vec![-200, -100], true, false, false),
+            ("queued".to_string(), Some(6), Visibility::Private, vec![], false, true, false),
+            ("reported".to_string(), None, Visibility::Public, vec![], false, false, true),
+        ]);
+        assert_eq!(flags(&mut target), flags(&mut source));
+    }
+
+    #[test]
+    fn media_without_visibility_is_imported_public() {
+        let bundle = "{\"format\":\"mehubot\",\"version\":1}\n{\"file_id\":\"old\",\"media_type\":\"Photo\",\"tags\":[{\"tag\":\"cat\",\"counter\":1}]}\n";
+
+        let conn = data::Connection::new(":memory:".to_string());
+        let mut db = data::DB::new(&conn);
+        import(&mut db, None, bundle.as_bytes()).expect("Failed to import bundle.");
+
+        let media = db.read_all_media();
+        assert_eq!(media.len(), 1);
+        assert_eq!(media[0].owner_id, None);
+        assert_eq!(media[0].visibility, Visibility::Public);
+        assert!(media[0].shared_with.is_empty());
+        assert_eq!(db.read_media(1, 10).len(), 1);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,462 @@
+extern crate serde_json;
+
+use std::error::Error;
+use std::io::Write;
+use serde::Serialize;
+use crate::data::{self, Entity};
+use crate::config::{self, Config};
+
+static USAGE: &str = "Usage: mehubot [COMMAND] [--json] [--config <file>] [--<setting> <value>...]
+
+Commands:
+    serve                        Run the bot (default)
+    migrate                      Upgrade the database schema
+    tags list                    List tags with media count and uses
+    tags rename <from> <to>      Rename a tag that does not exist yet
+    tags merge <from> <into>     Merge a tag into another, summing uses
+    media show <media_id>        Show a media with its tags
+    media delete <media_id>      Delete a media and its tags
+    stats                        Show library statistics
+    stats usage [days] [--csv]   Report usage of the last 7 or given days
+    stats misses [days] [--csv]  List queries that found nothing, with suggested tags
+    vacuum                       Compact the database file
+    restore                      Re-upload archived media for fresh file_ids
+    export <file> [--with-files] Export the library as a bundle
+    import <file>                Merge a bundle into the library
+
+Settings are read from --config, $MEHU_CONFIG or ./mehubot.toml, then overridden by the environment and flags
+such as --api-key, --api-url, --database, --polling-timeout, --result-limit, --admin-ids, --allowed-chats and --log-level.";
+
+static EXIT_OK: i32 = 0;
+static EXIT_FAILURE: i32 = 1;
+static EXIT_USAGE: i32 = 2;
+static EXIT_NOT_FOUND: i32 = 3;
+
+struct Options {
+    json: bool,
+    with_files: bool,
+    csv: bool,
+    config_file: Option<String>,
+    flags: Vec<(String, String)>,
+}
+
+enum Failure {
+    Usage,
+    NotFound(String),
+    Error(Box<dyn Error>),
+}
+
+impl From<Box<dyn Error>> for Failure {
+    fn from(e: Box<dyn Error>) -> Failure {
+        Failure::Error(e)
+    }
+}
+
+impl From<config::Error> for Failure {
+    fn from(e: config::Error) -> Failure {
+        Failure::Error(Box::new(e))
+    }
+}
+
+#[derive(Serialize)]
+struct SchemaVersion {
+    schema_version: i64,
+}
+
+#[derive(Serialize)]
+struct Changed {
+    changed: i32,
+}
+
+#[derive(Serialize)]
+struct MediaDetails {
+    media: data::StoredMedia,
+    tags: Vec<TagCounter>,
+}
+
+#[derive(Serialize)]
+struct TagCounter {
+    tag: String,
+    counter: i64,
+}
+
+#[derive(Serialize)]
+struct ErrorMessage {
+    error: String,
+}
+
+// Writes the command's output to `out` and returns the process exit code: 0 on success, 1 on failure, 2 on bad usage
+// and 3 when the target does not exist.
+pub fn run(args: Vec<String>, out: &mut dyn Write) -> i32 {
+    let mut options = Options { json: false, with_files: false, csv: false, config_file: None, flags: Vec::new() };
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+
+        let (flag, inline_value) = match arg.find('=') {
+            Some(i) => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+            None => (arg.clone(), None)
+        };
+
+        match flag.as_str() {
+            "--json" => options.json = true,
+            "--with-files" => options.with_files = true,
+            "--csv" => options.csv = true,
+            f if Config::is_flag(f) => {
+                let value = match inline_value.or_else(|| args.next()) {
+                    Some(v) => v,
+                    None => {
+                        eprintln!("Missing value for {}\n\n{}", flag, USAGE);
+                        return EXIT_USAGE;
+                    }
+                };
+
+                if flag == config::CONFIG_FLAG {
+                    options.config_file = Some(value);
+                } else {
+                    options.flags.push((flag, value));
+                }
+            }
+            _ => {
+                eprintln!("Unknown flag {}\n\n{}", flag, USAGE);
+                return EXIT_USAGE;
+            }
+        }
+    }
+
+    let positional: Vec<&str> = positional.iter().map(|a| a.as_str()).collect();
+    let json = options.json;
+
+    let result = match positional.as_slice() {
+        &[] | &["serve"] => serve(&options),
+        &["migrate"] => migrate(&options),
+        &["tags", "list"] => list_tags(&options),
+        &["tags", "rename", from, to] => rename_tag(&options, from, to),
+        &["tags", "merge", from, into] => merge_tag(&options, from, into),
+        &["media", "show", media_id] => show_media(&options, media_id),
+        &["media", "delete", media_id] => delete_media(&options, media_id),
+        &["stats"] => stats(&options),
+        &["stats", "usage"] => usage_report(&options, None),
+        &["stats", "usage", days] => usage_report(&options, Some(days)),
+        &["stats", "misses"] => misses_report(&options, None),
+        &["stats", "misses", days] => misses_report(&options, Some(days)),
+        &["vacuum"] => vacuum(&options),
+        &["restore"] => restore(&options),
+        &["export", path] => export(&options, path),
+        &["import", path] => import(&options, path),
+        _ => Err(Failure::Usage)
+    };
+
+    match result {
+        Ok(text) => {
+            write!(out, "{}", text).expect("Failed to write output.");
+            EXIT_OK
+        }
+        Err(Failure::Usage) => {
+            eprintln!("{}", USAGE);
+            EXIT_USAGE
+        }
+        Err(Failure::NotFound(message)) => {
+            report_error(out, message, json);
+            EXIT_NOT_FOUND
+        }
+        Err(Failure::Error(e)) => {
+            report_error(out, e.to_string(), json);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn load(options: &Options) -> Result<Config, Failure> {
+    Ok(Config::load(options.config_file.as_deref(), &options.flags)?)
+}
+
+fn serve(options: &Options) -> Result<String, Failure> {
+    crate::run(load(options)?)?;
+    Ok(String::new())
+}
+
+fn restore(options: &Options) -> Result<String, Failure> {
+    crate::restore(load(options)?)?;
+    Ok(String::new())
+}
+
+fn export(options: &Options, path: &str) -> Result<String, Failure> {
+    crate::export(load(options)?, path, options.with_files)?;
+    Ok(String::new())
+}
+
+fn import(options: &Options, path: &str) -> Result<String, Failure> {
+    crate::import(load(options)?, path)?;
+    Ok(String::new())
+}
+
+fn migrate(options: &Options) -> Result<String, Failure> {
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    let schema_version = db.schema_version();
+
+    output(&SchemaVersion { schema_version }, options.json, || format!("Database schema at version {}\n", schema_version))
+}
+
+fn list_tags(options: &Options) -> Result<String, Failure> {
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    let tags = db.read_tag_summaries();
+
+    output(&tags, options.json, || tags.iter()
+                                       .map(|t| format!("{:<32} {:>8} media {:>8} uses\n", t.tag, t.media, t.counter))
+                                       .collect())
+}
+
+fn rename_tag(options: &Options, from: &str, to: &str) -> Result<String, Failure> {
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    let changed = db.rename_tag(from, to).map_err(|e| Failure::Error(e.into()))?;
+
+    if changed == 0 {
+        return Err(Failure::NotFound(format!("Tag {} not found", from)));
+    }
+
+    output(&Changed { changed }, options.json, || format!("Renamed {} tags\n", changed))
+}
+
+fn merge_tag(options: &Options, from: &str, into: &str) -> Result<String, Failure> {
+    // Merging a tag into itself would delete it together with its counters.
+    if from.to_lowercase() == into.to_lowercase() {
+        return Err(Failure::Usage);
+    }
+
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    let changed = db.merge_tag(from, into);
+
+    if changed == 0 {
+        return Err(Failure::NotFound(format!("Tag {} not found", from)));
+    }
+
+    output(&Changed { changed }, options.json, || format!("Merged {} tags\n", changed))
+}
+
+fn show_media(options: &Options, media_id: &str) -> Result<String, Failure> {
+    let media_id = parse_media_id(media_id)?;
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+
+    let media = db.read_stored_media(media_id)
+                  .ok_or(Failure::NotFound(format!("Media {} not found", media_id)))?;
+
+    let tags = db.read_tags_with_mediaid(media_id)
+                 .into_iter()
+                 .filter_map(|t| match t {
+                     Entity::Tag { tag, counter, .. } => Some(TagCounter { tag, counter }),
+                     _ => None
+                 })
+                 .collect();
+
+    let details = MediaDetails { media, tags };
+
+    output(&details, options.json, || {
+        let m = &details.media;
+        let mut text = format!("media_id:     {}\nfile_id:      {}\nmedia_type:   {:?}\narchive_path: {}\n",
+                               m.media_id, m.file_id, m.media_type, m.archive_path.as_deref().unwrap_or("-"));
+
+        for t in &details.tags {
+            text.push_str(&format!("tag:          {} ({} uses)\n", t.tag, t.counter));
+        }
+
+        text
+    })
+}
+
+fn delete_media(options: &Options, media_id: &str) -> Result<String, Failure> {
+    let media_id = parse_media_id(media_id)?;
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+
+    if !db.delete_media(media_id) {
+        return Err(Failure::NotFound(format!("Media {} not found", media_id)));
+    }
+
+    output(&Changed { changed: 1 }, options.json, || format!("Deleted media {}\n", media_id))
+}
+
+fn stats(options: &Options) -> Result<String, Failure> {
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    let stats = db.read_library_stats().map_err(|e| Failure::Error(e.into()))?;
+
+    output(&stats, options.json, || {
+        format!("schema version: {}\nmedia:          {} ({} photos, {} mp4 gifs, {} gifs)\narchived:       {}\ntags:           {}\nuses:           {}\n",
+                stats.schema_version, stats.media, stats.photos, stats.mpeg4_gifs, stats.gifs, stats.archived, stats.tags, stats.uses)
+    })
+}
+
+fn usage_report(options: &Options, days: Option<&str>) -> Result<String, Failure> {
+    let days = crate::stats::parse_days(days).ok_or(Failure::Usage)?;
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    let report = crate::stats::report(&mut db, days);
+
+    if options.csv {
+        return Ok(crate::stats::to_csv(&report));
+    }
+
+    output(&report, options.json, || crate::stats::to_text(&report))
+}
+
+fn misses_report(options: &Options, days: Option<&str>) -> Result<String, Failure> {
+    let days = crate::stats::parse_days(days).ok_or(Failure::Usage)?;
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    let tags: Vec<String> = db.read_tag_summaries().into_iter().map(|t| t.tag).collect();
+    let misses = crate::stats::misses(&mut db, days, &tags);
+
+    if options.csv {
+        return Ok(crate::stats::misses_to_csv(&misses));
+    }
+
+    output(&misses, options.json, || crate::stats::misses_to_text(&misses, days))
+}
+
+fn vacuum(options: &Options) -> Result<String, Failure> {
+    let config = load(options)?;
+    let connection = data::Connection::new(config.database_path);
+    let mut db = data::DB::new(&connection);
+    db.vacuum();
+
+    output(&Changed { changed: 0 }, options.json, || "Database vacuumed\n".to_string())
+}
+
+fn parse_media_id(media_id: &str) -> Result<i64, Failure> {
+    media_id.parse::<i64>().map_err(|_| Failure::Usage)
+}
+
+fn output<T: Serialize, F: FnOnce() -> String>(value: &T, json: bool, text: F) -> Result<String, Failure> {
+    if json {
+        return Ok(format!("{}\n", serde_json::to_string(value).map_err(|e| Failure::Error(Box::new(e)))?));
+    }
+
+    Ok(text())
+}
+
+fn report_error(out: &mut dyn Write, message: String, json: bool) {
+    if json {
+        writeln!(out, "{}", serde_json::to_string(&ErrorMessage { error: message.clone() }).unwrap_or_default()).ok();
+    }
+
+    eprintln!("Error: {}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use crate::data::{MediaType, Visibility};
+
+    // A database with one media tagged "cat" and "kitty", removed again when dropped.
+    struct Library {
+        path: String,
+    }
+
+    impl Library {
+        fn new(name: &str) -> Library {
+            let path = env::temp_dir().join(format!("mehubot-cli-{}-{}.sqlite", name, process::id())).to_string_lossy().into_owned();
+            let _ = fs::remove_file(&path);
+
+            let connection = data::Connection::new(path.clone());
+            let mut db = data::DB::new(&connection);
+            let media_id = db.insert_media("cat".to_string(), MediaType::Photo, 1, Visibility::Public, &[], false, false);
+            db.insert(Entity::Tag { media_id, tag: "cat".to_string(), counter: 0 });
+            db.insert(Entity::Tag { media_id, tag: "kitty".to_string(), counter: 0 });
+            db.merge_tag_counter(media_id, "cat", 2);
+            db.merge_tag_counter(media_id, "kitty", 1);
+
+            Library { path }
+        }
+
+        // The exit code and output of the command run against this library.
+        fn run(&self, args: &[&str]) -> (i32, String) {
+            let mut args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            args.extend(vec!["--database".to_string(), self.path.clone()]);
+
+            let mut out = Vec::new();
+            let code = run(args, &mut out);
+
+            (code, String::from_utf8(out).expect("Failed to read output."))
+        }
+    }
+
+    impl Drop for Library {
+        fn drop(&mut self) {
+            for suffix in &["", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{}{}", self.path, suffix));
+            }
+        }
+    }
+
+    #[test]
+    fn successful_commands_exit_with_0_and_print_json() {
+        let library = Library::new("ok");
+
+        assert_eq!(library.run(&["migrate", "--json"]), (EXIT_OK, format!("{{\"schema_version\":{}}}\n", data::SQL_MIGRATIONS.len())));
+        assert_eq!(library.run(&["tags", "list", "--json"]),
+                   (EXIT_OK, "[{\"tag\":\"cat\",\"media\":1,\"counter\":2},{\"tag\":\"kitty\",\"media\":1,\"counter\":1}]\n".to_string()));
+        assert_eq!(library.run(&["tags", "merge", "kitty", "cat", "--json"]), (EXIT_OK, "{\"changed\":1}\n".to_string()));
+        assert_eq!(library.run(&["tags", "list"]), (EXIT_OK, format!("{:<32} {:>8} media {:>8} uses\n", "cat", 1, 3)));
+        assert_eq!(library.run(&["media", "delete", "1", "--json"]), (EXIT_OK, "{\"changed\":1}\n".to_string()));
+    }
+
+    #[test]
+    fn failures_exit_with_1() {
+        let library = Library::new("failure");
+
+        let (code, out) = library.run(&["tags", "rename", "kitty", "cat", "--json"]);
+        assert_eq!(code, EXIT_FAILURE);
+        assert!(out.starts_with("{\"error\":"));
+
+        assert_eq!(library.run(&["stats", "--workers", "0", "--json"]),
+                   (EXIT_FAILURE, "{\"error\":\"invalid value for bot.workers (from --workers): must be between 1 and 64\"}\n".to_string()));
+    }
+
+    #[test]
+    fn bad_usage_exits_with_2() {
+        let library = Library::new("usage");
+
+        // Merging a tag into itself would delete it.
+        assert_eq!(library.run(&["tags", "merge", "cat", "Cat"]), (EXIT_USAGE, String::new()));
+        assert_eq!(library.run(&["tags", "list"]).1.lines().count(), 2);
+
+        assert_eq!(library.run(&["media", "show", "first"]), (EXIT_USAGE, String::new()));
+        assert_eq!(library.run(&["stats", "usage", "-1"]), (EXIT_USAGE, String::new()));
+        assert_eq!(library.run(&["tags"]), (EXIT_USAGE, String::new()));
+        assert_eq!(library.run(&["tags", "list", "--colour"]), (EXIT_USAGE, String::new()));
+        assert_eq!(library.run(&["tags", "list", "--database"]), (EXIT_USAGE, String::new()));
+    }
+
+    #[test]
+    fn missing_targets_exit_with_3() {
+        let library = Library::new("not-found");
+
+        assert_eq!(library.run(&["media", "show", "2", "--json"]), (EXIT_NOT_FOUND, "{\"error\":\"Media 2 not found\"}\n".to_string()));
+        assert_eq!(library.run(&["media", "delete", "2"]), (EXIT_NOT_FOUND, String::new()));
+        assert_eq!(library.run(&["tags", "rename", "dog", "puppy", "--json"]), (EXIT_NOT_FOUND, "{\"error\":\"Tag dog not found\"}\n".to_string()));
+        assert_eq!(library.run(&["tags", "merge", "dog", "cat"]), (EXIT_NOT_FOUND, String::new()));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,508 @@
+extern crate toml;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use log::LevelFilter;
+use self::toml::Value;
+use crate::data::Role;
+use crate::logging::LogFormat;
+
+static DEFAULT_CONFIG_FILE: &str = "mehubot.toml";
+static CONFIG_FILE_ENV: &str = "MEHU_CONFIG";
+static DEFAULT_API_URL: &str = "https://api.telegram.org";
+static DEFAULT_DATABASE_PATH: &str = "database.sqlite";
+static MEMORY_DATABASE: &str = ":memory:";
+static URI_DATABASE_PREFIX: &str = "file:";
+static URI_MEMORY_MODE: &str = "mode=memory";
+static DEFAULT_POLLING_TIMEOUT: i64 = 600;
+static READY_GRACE_SEC: u64 = 60;
+static DEFAULT_RESULT_LIMIT: i64 = 50;
+static MAX_RESULT_LIMIT: i64 = 50;
+static DEFAULT_WORKERS: i64 = 4;
+static MAX_WORKERS: i64 = 64;
+static DEFAULT_QUEUE_SIZE: i64 = 100;
+static DEFAULT_ROLE: &str = "contributor";
+static DEFAULT_REPORT_THRESHOLD: i64 = 3;
+static DEFAULT_LOG_LEVEL: &str = "info";
+static DEFAULT_LOG_FORMAT: &str = "text";
+static DEFAULT_S3_REGION: &str = "us-east-1";
+
+// Every setting can come from the TOML file (by key), the environment or a command-line flag, the latter winning.
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    flag: &'static str,
+}
+
+static SETTINGS: &[Setting] = &[
+    Setting { key: "telegram.api_key", env: "MEHU_TELEGRAM_APIKEY", flag: "--api-key" },
+    Setting { key: "telegram.api_url", env: "MEHU_TELEGRAM_API_URL", flag: "--api-url" },
+    Setting { key: "telegram.polling_timeout", env: "MEHU_POLLING_TIMEOUT", flag: "--polling-timeout" },
+    Setting { key: "database.path", env: "MEHU_DATASTORE_PATH", flag: "--database" },
+    Setting { key: "bot.result_limit", env: "MEHU_RESULT_LIMIT", flag: "--result-limit" },
+    Setting { key: "bot.admin_ids", env: "MEHU_ADMIN_IDS", flag: "--admin-ids" },
+    Setting { key: "bot.allowed_chats", env: "MEHU_ALLOWED_CHATS", flag: "--allowed-chats" },
+    Setting { key: "bot.default_role", env: "MEHU_DEFAULT_ROLE", flag: "--default-role" },
+    Setting { key: "bot.approval", env: "MEHU_APPROVAL", flag: "--approval" },
+    Setting { key: "bot.report_threshold", env: "MEHU_REPORT_THRESHOLD", flag: "--report-threshold" },
+    Setting { key: "bot.workers", env: "MEHU_WORKERS", flag: "--workers" },
+    Setting { key: "bot.queue_size", env: "MEHU_QUEUE_SIZE", flag: "--queue-size" },
+    Setting { key: "log.level", env: "MEHU_LOG_LEVEL", flag: "--log-level" },
+    Setting { key: "log.format", env: "MEHU_LOG_FORMAT", flag: "--log-format" },
+    Setting { key: "log.scrub_pii", env: "MEHU_LOG_SCRUB_PII", flag: "--log-scrub-pii" },
+    Setting { key: "log.pseudonym_key", env: "MEHU_LOG_PSEUDONYM_KEY", flag: "--log-pseudonym-key" },
+    Setting { key: "http.address", env: "MEHU_HTTP_ADDRESS", flag: "--http-address" },
+    Setting { key: "http.ready_threshold", env: "MEHU_HTTP_READY_THRESHOLD", flag: "--http-ready-threshold" },
+    Setting { key: "archive.path", env: "MEHU_ARCHIVE_PATH", flag: "--archive-path" },
+    Setting { key: "archive.restore_chat_id", env: "MEHU_ARCHIVE_RESTORE_CHAT_ID", flag: "--archive-restore-chat-id" },
+    Setting { key: "archive.s3.endpoint", env: "MEHU_ARCHIVE_S3_ENDPOINT", flag: "--archive-s3-endpoint" },
+    Setting { key: "archive.s3.bucket", env: "MEHU_ARCHIVE_S3_BUCKET", flag: "--archive-s3-bucket" },
+    Setting { key: "archive.s3.region", env: "MEHU_ARCHIVE_S3_REGION", flag: "--archive-s3-region" },
+    Setting { key: "archive.s3.access_key", env: "MEHU_ARCHIVE_S3_ACCESS_KEY", flag: "--archive-s3-access-key" },
+    Setting { key: "archive.s3.secret_key", env: "MEHU_ARCHIVE_S3_SECRET_KEY", flag: "--archive-s3-secret-key" },
+];
+
+pub static CONFIG_FLAG: &str = "--config";
+
+pub struct Config {
+    pub api_key: Option<String>,
+    pub api_url: String,
+    pub database_path: String,
+    pub polling_timeout: u16,
+    pub result_limit: i64,
+    pub admin_ids: Vec<i64>,
+    pub allowed_chats: Vec<i64>,
+    // Role of users that were never granted one, admin_ids are always admins.
+    pub default_role: Role,
+    // Viewers may upload too, their media waits for an admin's approval before anyone finds it.
+    pub approval: bool,
+    // Media reported by this many users is hidden until an admin looks at it, 0 never hides.
+    pub report_threshold: i64,
+    pub workers: usize,
+    pub queue_size: usize,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    // Hides user ids and what users wrote from the log.
+    pub log_scrub_pii: bool,
+    // Keys the pseudonyms of scrubbed ids, without it they only match within one run.
+    pub log_pseudonym_key: Option<String>,
+    // Where to serve /metrics, nothing is served when unset.
+    pub http_address: Option<String>,
+    // Seconds since the last successful getUpdates before /ready fails.
+    pub ready_threshold: u64,
+    pub archive: Option<ArchiveConfig>,
+}
+
+pub struct ArchiveConfig {
+    pub backend: ArchiveBackend,
+    pub restore_chat_id: Option<i64>,
+}
+
+pub enum ArchiveBackend {
+    Local { path: String },
+    S3 { endpoint: String, bucket: String, region: String, access_key: String, secret_key: String },
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io { path: String, error: io::Error },
+    Parse { path: String, message: String },
+    UnknownSetting { key: String, origin: String },
+    Missing { key: &'static str, env: &'static str, flag: &'static str },
+    Invalid { key: &'static str, origin: String, reason: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } => write!(f, "cannot read config file {}: {}", path, error),
+            Error::Parse { path, message } => write!(f, "invalid config file {}: {}", path, message),
+            Error::UnknownSetting { key, origin } => write!(f, "unknown setting {} in {}", key, origin),
+            &Error::Missing { key, env, flag } => write!(f, "missing setting {}, set it in the config file, with {} or with {}", key, env, flag),
+            &Error::Invalid { key, ref origin, ref reason } => write!(f, "invalid value for {} (from {}): {}", key, origin, reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "invalid configuration"
+    }
+}
+
+struct Layers {
+    values: HashMap<&'static str, (Value, String)>,
+}
+
+impl Config {
+    // `file` is the path given with --config, `flags` the (flag, value) pairs given on the command line.
+    pub fn load(file: Option<&str>, flags: &[(String, String)]) -> Result<Config, Error> {
+        dotenv::dotenv().ok();
+
+        Config::layered(file, &|name| dotenv::var(name).ok(), flags)
+    }
+
+    // Layers the config file, the environment variables looked up with `env` and the flags.
+    fn layered(file: Option<&str>, env: &dyn Fn(&str) -> Option<String>, flags: &[(String, String)]) -> Result<Config, Error> {
+        let mut layers = Layers { values: HashMap::new() };
+
+        let file = file.map(|f| f.to_string()).or(env(CONFIG_FILE_ENV));
+
+        match file {
+            Some(ref path) => layers.read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => layers.read_file(DEFAULT_CONFIG_FILE)?,
+            None => ()
+        }
+
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.env) {
+                layers.values.insert(setting.key, (Value::String(value), setting.env.to_string()));
+            }
+        }
+
+        for (flag, value) in flags {
+            match SETTINGS.iter().find(|s| s.flag == flag) {
+                Some(setting) => {
+                    layers.values.insert(setting.key, (Value::String(value.clone()), flag.clone()));
+                }
+                None => return Err(Error::UnknownSetting { key: flag.clone(), origin: "command line".to_string() })
+            }
+        }
+
+        layers.build()
+    }
+
+    pub fn is_flag(flag: &str) -> bool {
+        flag == CONFIG_FLAG || SETTINGS.iter().any(|s| s.flag == flag)
+    }
+
+    pub fn require_api_key(&self) -> Result<String, Error> {
+        let setting = setting("telegram.api_key");
+
+        self.api_key.clone().ok_or(Error::Missing { key: setting.key, env: setting.env, flag: setting.flag })
+    }
+
+    // Every connection to an in-memory database gets a database of its own.
+    pub fn is_database_in_memory(&self) -> bool {
+        self.database_path == MEMORY_DATABASE || (self.database_path.starts_with(URI_DATABASE_PREFIX) && self.database_path.contains(URI_MEMORY_MODE))
+    }
+
+    pub fn is_chat_allowed(&self, chat_id: i64) -> bool {
+        self.allowed_chats.is_empty() || self.allowed_chats.contains(&chat_id)
+    }
+}
+
+impl Layers {
+    fn read_file(&mut self, path: &str) -> Result<(), Error> {
+        let mut contents = String::new();
+
+        File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+                        .map_err(|error| Error::Io { path: path.to_string(), error })?;
+
+        let table: Value = contents.parse()
+                                   .map_err(|e: toml::de::Error| Error::Parse { path: path.to_string(), message: e.to_string() })?;
+
+        let mut flattened = Vec::new();
+        flatten("", table, &mut flattened);
+
+        for (key, value) in flattened {
+            match SETTINGS.iter().find(|s| s.key == key) {
+                Some(setting) => {
+                    self.values.insert(setting.key, (value, path.to_string()));
+                }
+                None => return Err(Error::UnknownSetting { key, origin: path.to_string() })
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build(&self) -> Result<Config, Error> {
+        let api_url = self.string("telegram.api_url")?.unwrap_or(DEFAULT_API_URL.to_string());
+
+        if !api_url.starts_with("http://") && !api_url.starts_with("https://") {
+            return Err(self.invalid("telegram.api_url", "must be an http:// or https:// URL"));
+        }
+
+        let database_path = self.database_path()?;
+
+        let log_level = self.string("log.level")?.unwrap_or(DEFAULT_LOG_LEVEL.to_string());
+        let log_level = LevelFilter::from_str(&log_level)
+            .map_err(|_| self.invalid("log.level", "must be one of off, error, warn, info, debug or trace"))?;
+
+        let log_format = self.string("log.format")?.unwrap_or(DEFAULT_LOG_FORMAT.to_string());
+        let log_format = LogFormat::from_str(&log_format).map_err(|e| self.invalid("log.format", &e))?;
+
+        let http_address = self.string("http.address")?;
+
+        if let Some(ref address) = http_address {
+            address.parse::<SocketAddr>().map_err(|_| self.invalid("http.address", "must be an address and port such as 127.0.0.1:9090"))?;
+        }
+
+        let polling_timeout = self.integer("telegram.polling_timeout", 0, u16::MAX as i64)?.unwrap_or(DEFAULT_POLLING_TIMEOUT) as u16;
+
+        // A long poll without updates only returns after polling_timeout.
+        let ready_threshold = self.integer("http.ready_threshold", 1, i32::MAX as i64)?
+                                  .map(|t| t as u64)
+                                  .unwrap_or(polling_timeout as u64 + READY_GRACE_SEC);
+
+        let default_role = self.string("bot.default_role")?.unwrap_or(DEFAULT_ROLE.to_string());
+        let default_role = Role::from_str(&default_role).map_err(|e| self.invalid("bot.default_role", &e))?;
+
+        Ok(Config {
+            api_key: self.string("telegram.api_key")?.filter(|k| !k.is_empty()),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            database_path,
+            polling_timeout,
+            result_limit: self.integer("bot.result_limit", 1, MAX_RESULT_LIMIT)?.unwrap_or(DEFAULT_RESULT_LIMIT),
+            admin_ids: self.ids("bot.admin_ids")?,
+            allowed_chats: self.ids("bot.allowed_chats")?,
+            default_role,
+            approval: self.boolean("bot.approval")?.unwrap_or(false),
+            report_threshold: self.integer("bot.report_threshold", 0, i32::MAX as i64)?.unwrap_or(DEFAULT_REPORT_THRESHOLD),
+            workers: self.integer("bot.workers", 1, MAX_WORKERS)?.unwrap_or(DEFAULT_WORKERS) as usize,
+            queue_size: self.integer("bot.queue_size", 1, i32::MAX as i64)?.unwrap_or(DEFAULT_QUEUE_SIZE) as usize,
+            log_level,
+            log_format,
+            log_scrub_pii: self.boolean("log.scrub_pii")?.unwrap_or(false),
+            log_pseudonym_key: self.string("log.pseudonym_key")?.filter(|k| !k.is_empty()),
+            http_address,
+            ready_threshold,
+            archive: self.archive()?,
+        })
+    }
+
+    // Older setups point at a directory, the database file in it keeps its original name.
+    fn database_path(&self) -> Result<String, Error> {
+        let path = self.string("database.path")?.unwrap_or(DEFAULT_DATABASE_PATH.to_string());
+
+        if path == MEMORY_DATABASE || path.starts_with(URI_DATABASE_PREFIX) {
+            return Ok(path);
+        }
+
+        if Path::new(&path).is_dir() {
+            return Ok(Path::new(&path).join(DEFAULT_DATABASE_PATH).to_string_lossy().into_owned());
+        }
+
+        match Path::new(&path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() =>
+                Err(self.invalid("database.path", &format!("directory {} does not exist", parent.display()))),
+            _ => Ok(path)
+        }
+    }
+
+    fn archive(&self) -> Result<Option<ArchiveConfig>, Error> {
+        let restore_chat_id = self.integer("archive.restore_chat_id", i64::MIN, i64::MAX)?;
+
+        let backend = match (self.string("archive.path")?, self.string("archive.s3.endpoint")?) {
+            (Some(_), Some(_)) => return Err(self.invalid("archive.s3.endpoint", "archive.path and archive.s3 are mutually exclusive")),
+            (Some(path), None) => ArchiveBackend::Local { path },
+            (None, Some(endpoint)) => ArchiveBackend::S3 {
+                endpoint,
+                bucket: self.required("archive.s3.bucket")?,
+                region: self.string("archive.s3.region")?.unwrap_or(DEFAULT_S3_REGION.to_string()),
+                access_key: self.required("archive.s3.access_key")?,
+                secret_key: self.required("archive.s3.secret_key")?,
+            },
+            (None, None) => return Ok(None)
+        };
+
+        Ok(Some(ArchiveConfig { backend, restore_chat_id }))
+    }
+
+    fn string(&self, key: &'static str) -> Result<Option<String>, Error> {
+        match self.values.get(key) {
+            Some(&(Value::String(ref s), _)) => Ok(Some(s.clone())),
+            Some(_) => Err(self.invalid(key, "must be a string")),
+            None => Ok(None)
+        }
+    }
+
+    fn required(&self, key: &'static str) -> Result<String, Error> {
+        let setting = setting(key);
+
+        self.string(key)?.ok_or(Error::Missing { key: setting.key, env: setting.env, flag: setting.flag })
+    }
+
+    fn integer(&self, key: &'static str, min: i64, max: i64) -> Result<Option<i64>, Error> {
+        let value = match self.values.get(key) {
+            Some(&(Value::Integer(i), _)) => i,
+            Some(&(Value::String(ref s), _)) => s.trim().parse::<i64>().map_err(|_| self.invalid(key, "must be an integer"))?,
+            Some(_) => return Err(self.invalid(key, "must be an integer")),
+            None => return Ok(None)
+        };
+
+        if value < min || value > max {
+            return Err(self.invalid(key, &format!("must be between {} and {}", min, max)));
+        }
+
+        Ok(Some(value))
+    }
+
+    fn boolean(&self, key: &'static str) -> Result<Option<bool>, Error> {
+        match self.values.get(key) {
+            Some(&(Value::Boolean(b), _)) => Ok(Some(b)),
+            Some(&(Value::String(ref s), _)) => match s.trim().to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(Some(true)),
+                "false" | "no" | "off" | "0" => Ok(Some(false)),
+                _ => Err(self.invalid(key, "must be true or false"))
+            },
+            Some(_) => Err(self.invalid(key, "must be true or false")),
+            None => Ok(None)
+        }
+    }
+
+    // Accepts a TOML array of integers or a comma separated string from the environment or command line.
+    fn ids(&self, key: &'static str) -> Result<Vec<i64>, Error> {
+        match self.values.get(key) {
+            Some(&(Value::Array(ref a), _)) => a.iter()
+                                                .map(|v| v.as_integer().ok_or(self.invalid(key, "must be a list of integer ids")))
+                                                .collect(),
+            Some(&(Value::String(ref s), _)) => s.split(',')
+                                                 .map(|id| id.trim())
+                                                 .filter(|id| !id.is_empty())
+                                                 .map(|id| id.parse::<i64>().map_err(|_| self.invalid(key, &format!("{} is not an integer id", id))))
+                                                 .collect(),
+            Some(_) => Err(self.invalid(key, "must be a list of integer ids")),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn invalid(&self, key: &'static str, reason: &str) -> Error {
+        let origin = self.values.get(key).map(|(_, o)| o.clone()).unwrap_or("defaults".to_string());
+
+        Error::Invalid { key, origin, reason: reason.to_string() }
+    }
+}
+
+fn setting(key: &str) -> &'static Setting {
+    SETTINGS.iter().find(|s| s.key == key).expect("Unknown setting key.")
+}
+
+fn flatten(prefix: &str, value: Value, out: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Table(table) => for (k, v) in table {
+            let key = if prefix.is_empty() { k } else { format!("{}.{}", prefix, k) };
+            flatten(&key, v, out);
+        },
+        v => out.push((prefix.to_string(), v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("mehubot-config-{}-{}.toml", name, process::id()));
+        fs::write(&path, contents).expect("Failed to write config file.");
+        path.to_string_lossy().into_owned()
+    }
+
+    fn flags(flags: &[(&str, &str)]) -> Vec<(String, String)> {
+        flags.iter().map(|&(f, v)| (f.to_string(), v.to_string())).collect()
+    }
+
+    fn load(file: Option<&str>, env: &[(&str, &str)], flags: &[(String, String)]) -> Result<Config, Error> {
+        let env: HashMap<String, String> = env.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+
+        Config::layered(file, &|name| env.get(name).cloned(), flags)
+    }
+
+    fn loaded(config: Result<Config, Error>) -> Config {
+        match config {
+            Ok(config) => config,
+            Err(e) => panic!("Failed to load config: {}", e)
+        }
+    }
+
+    fn error<T>(config: Result<T, Error>) -> String {
+        match config {
+            Ok(_) => panic!("The config loaded."),
+            Err(e) => e.to_string()
+        }
+    }
+
+    #[test]
+    fn flags_override_the_environment_and_the_environment_overrides_the_file() {
+        let path = file("layers", "[bot]\nresult_limit = 10\nworkers = 2\nqueue_size = 5\n");
+
+        let config = loaded(load(Some(&path),
+                                 &[("MEHU_RESULT_LIMIT", "20"), ("MEHU_WORKERS", "3")],
+                                 &flags(&[("--result-limit", "30")])));
+
+        assert_eq!(config.result_limit, 30);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.queue_size, 5);
+        assert_eq!(config.report_threshold, DEFAULT_REPORT_THRESHOLD);
+    }
+
+    #[test]
+    fn the_config_file_can_come_from_the_environment() {
+        let path = file("from-env", "[bot]\nworkers = 7\n");
+
+        let config = loaded(load(None, &[(CONFIG_FILE_ENV, &path)], &[]));
+
+        assert_eq!(config.workers, 7);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let path = file("unknown", "[bot]\nworkers = 2\nfavourite_colour = \"red\"\n");
+
+        assert_eq!(error(load(Some(&path), &[], &[])), format!("unknown setting bot.favourite_colour in {}", path));
+        assert_eq!(error(load(None, &[], &flags(&[("--colour", "red")]))), "unknown setting --colour in command line");
+    }
+
+    #[test]
+    fn invalid_values_name_the_setting_where_it_came_from_and_why() {
+        let path = file("invalid", "[bot]\nworkers = 1000\nresult_limit = true\n");
+
+        assert_eq!(error(load(Some(&path), &[], &flags(&[("--result-limit", "5")]))),
+                   format!("invalid value for bot.workers (from {}): must be between 1 and 64", path));
+        assert_eq!(error(load(Some(&path), &[], &flags(&[("--workers", "8")]))),
+                   format!("invalid value for bot.result_limit (from {}): must be an integer", path));
+        assert_eq!(error(load(None, &[("MEHU_WORKERS", "many")], &[])),
+                   "invalid value for bot.workers (from MEHU_WORKERS): must be an integer");
+        assert_eq!(error(load(None, &[], &flags(&[("--approval", "maybe")]))),
+                   "invalid value for bot.approval (from --approval): must be true or false");
+        assert_eq!(error(load(None, &[], &flags(&[("--api-url", "api.telegram.org")]))),
+                   "invalid value for telegram.api_url (from --api-url): must be an http:// or https:// URL");
+        assert!(error(load(Some(&file("broken", "[bot\n")), &[], &[])).starts_with("invalid config file "));
+    }
+
+    #[test]
+    fn an_empty_api_key_is_missing() {
+        let config = loaded(load(None, &[("MEHU_TELEGRAM_APIKEY", "")], &[]));
+
+        assert_eq!(config.require_api_key().unwrap_err().to_string(),
+                   "missing setting telegram.api_key, set it in the config file, with MEHU_TELEGRAM_APIKEY or with --api-key");
+    }
+
+    #[test]
+    fn a_database_directory_holds_the_database_file() {
+        let directory = env::temp_dir().join(format!("mehubot-config-database-{}", process::id()));
+        fs::create_dir_all(&directory).expect("Failed to create directory.");
+        let directory = directory.to_string_lossy().into_owned();
+
+        let database_path = |path: &str| load(None, &[], &flags(&[("--database", path)])).map(|c| c.database_path);
+
+        assert_eq!(database_path(&directory).ok(), Some(Path::new(&directory).join("database.sqlite").to_string_lossy().into_owned()));
+        assert_eq!(database_path(":memory:").ok(), Some(":memory:".to_string()));
+        assert_eq!(database_path("mehu.sqlite").ok(), Some("mehu.sqlite".to_string()));
+
+        let missing = Path::new(&directory).join("missing").join("mehu.sqlite");
+        assert_eq!(error(database_path(&missing.to_string_lossy())),
+                   format!("invalid value for database.path (from --database): directory {} does not exist",
+                           Path::new(&directory).join("missing").display()));
+    }
+}
\ No newline at end of file
@@ -1,28 +1,135 @@
 extern crate rusqlite;
 
-use self::rusqlite::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use self::rusqlite::{params, Error, OptionalExtension};
 use self::rusqlite::types::{Value, ValueRef, ToSql, ToSqlOutput, FromSql, FromSqlResult};
+use crate::metrics;
 
-static DB_NAME: &'static str = "/database.sqlite";
-static SQL_CREATE_TABLE_MEDIA: &'static str = "CREATE TABLE IF NOT EXISTS media (media_id INTEGER PRIMARY KEY NOT NULL, file_id TEXT UNIQUE NOT NULL, media_type INTEGER NOT NULL);";
-static SQL_CREATE_TABLE_TAG: &'static str = "CREATE TABLE IF NOT EXISTS tag (media_id INTEGER NOT NULL, tag TEXT NOT NULL, counter INT NOT NULL DEFAULT 0, FOREIGN KEY(media_id) REFERENCES media(media_id), PRIMARY KEY(media_id, tag));";
+static BUSY_TIMEOUT_MSEC: u32 = 5000;
+static SQL_CREATE_TABLE_MEDIA: &str = "CREATE TABLE IF NOT EXISTS media (media_id INTEGER PRIMARY KEY NOT NULL, file_id TEXT UNIQUE NOT NULL, media_type INTEGER NOT NULL);";
+static SQL_CREATE_TABLE_TAG: &str = "CREATE TABLE IF NOT EXISTS tag (media_id INTEGER NOT NULL, tag TEXT NOT NULL, counter INT NOT NULL DEFAULT 0, FOREIGN KEY(media_id) REFERENCES media(media_id), PRIMARY KEY(media_id, tag));";
+static SQL_CREATE_TABLE_STATE: &str = "CREATE TABLE IF NOT EXISTS state (key TEXT PRIMARY KEY NOT NULL, value INTEGER NOT NULL);";
+static SQL_CREATE_TABLE_HANDLED_UPDATE: &str = "CREATE TABLE IF NOT EXISTS handled_update (update_id INTEGER PRIMARY KEY NOT NULL);";
+static SQL_CREATE_TABLE_CHAT_SETTING: &str = "CREATE TABLE IF NOT EXISTS chat_setting (chat_id INTEGER PRIMARY KEY NOT NULL, shared_library INTEGER NOT NULL DEFAULT 0);";
+static SQL_CREATE_TABLE_MEDIA_SHARE: &str = "CREATE TABLE IF NOT EXISTS media_share (media_id INTEGER NOT NULL, chat_id INTEGER NOT NULL, FOREIGN KEY(media_id) REFERENCES media(media_id), PRIMARY KEY(media_id, chat_id));";
+static SQL_CREATE_TABLE_USER_ROLE: &str = "CREATE TABLE IF NOT EXISTS user_role (user_id INTEGER PRIMARY KEY NOT NULL, role INTEGER NOT NULL);";
+static SQL_CREATE_TABLE_CHAT_MEMBER: &str = "CREATE TABLE IF NOT EXISTS chat_member (chat_id INTEGER NOT NULL, user_id INTEGER NOT NULL, PRIMARY KEY(chat_id, user_id));";
+static SQL_CREATE_TABLE_REPORT: &str = "CREATE TABLE IF NOT EXISTS report (media_id INTEGER NOT NULL, user_id INTEGER NOT NULL, reason INTEGER NOT NULL, resolved INTEGER NOT NULL DEFAULT 0, created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')), FOREIGN KEY(media_id) REFERENCES media(media_id), PRIMARY KEY(media_id, user_id));";
+// No foreign key, the history outlives deleted media.
+static SQL_CREATE_TABLE_USAGE_EVENT: &str = "CREATE TABLE IF NOT EXISTS usage_event (event_id INTEGER PRIMARY KEY NOT NULL, kind INTEGER NOT NULL, user_id INTEGER NOT NULL, media_id INTEGER, query TEXT, results INTEGER, created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')));";
 
-static SQL_INSERT_MEDIA: &'static str = "INSERT INTO media (file_id, media_type) VALUES(?, ?);";
-static SQL_INSERT_TAG: &'static str = "INSERT INTO tag (media_id, tag) VALUES (?, ?);";
+static STATE_UPDATE_OFFSET: &str = "update_offset";
 
-static SQL_READ_TAG: &'static str = "SELECT media_id FROM tag WHERE media_id = ? AND tag = ?;";
+static SQL_INSERT_MEDIA: &str = "INSERT INTO media (file_id, media_type, owner_id, visibility, nsfw, pending) VALUES(?, ?, ?, ?, ?, ?);";
+static SQL_INSERT_TAG: &str = "INSERT INTO tag (media_id, tag) VALUES (?, ?);";
+static SQL_INSERT_HANDLED_UPDATE: &str = "INSERT OR IGNORE INTO handled_update (update_id) VALUES (?);";
+static SQL_INSERT_CHAT_MEMBER: &str = "INSERT OR IGNORE INTO chat_member (chat_id, user_id) VALUES (?, ?);";
+static SQL_INSERT_CHAT_SETTING: &str = "INSERT OR IGNORE INTO chat_setting (chat_id) VALUES (?);";
+static SQL_INSERT_MEDIA_SHARE: &str = "INSERT OR IGNORE INTO media_share (media_id, chat_id) VALUES (?, ?);";
+static SQL_INSERT_REPORT: &str = "INSERT OR IGNORE INTO report (media_id, user_id, reason) VALUES (?, ?, ?);";
+static SQL_INSERT_USAGE_EVENT: &str = "INSERT INTO usage_event (kind, user_id, media_id, query, results) VALUES (?, ?, ?, ?, ?);";
 
-static SQL_READ_MEDIA: &'static str = "SELECT DISTINCT a.media_id, file_id, media_type FROM media AS a, tag AS b WHERE a.media_id = b.media_id ORDER BY counter DESC;";
-static SQL_READ_MEDIA_WITH_MEDIAID: &'static str = "SELECT media_id, file_id, media_type FROM media AS a WHERE media_id = ?;";
-static SQL_READ_MEDIA_WITH_FILEID_AND_TYPE: &'static str = "SELECT media_id FROM media WHERE file_id = ? AND media_type = ?;";
-static SQL_READ_MEDIA_WITH_USER_AND_QUERY: &'static str = "SELECT DISTINCT a.media_id, file_id, media_type FROM media AS a, tag AS b WHERE a.media_id = b.media_id AND tag LIKE ? ORDER BY counter DESC;";
+static SQL_READ_TAG: &str = "SELECT media_id FROM tag WHERE media_id = ? AND tag = ?;";
+static SQL_READ_TAGS_WITH_MEDIAID: &str = "SELECT media_id, tag, counter FROM tag WHERE media_id = ? ORDER BY tag;";
+static SQL_READ_TAG_SUMMARIES: &str = "SELECT tag, COUNT(*), SUM(counter) FROM tag GROUP BY tag ORDER BY tag;";
+static SQL_READ_VISIBLE_TAGS: &str = "SELECT DISTINCT tag FROM media AS a, tag AS b WHERE a.media_id = b.media_id AND broken = 0 AND pending = 0 AND hidden = 0 AND (nsfw = 0 OR EXISTS (SELECT 1 FROM chat_setting WHERE chat_id = ?1 AND safe_search = 0)) AND (visibility = 0 OR owner_id = ?1 OR (visibility = 2 AND a.media_id IN (SELECT s.media_id FROM media_share AS s, chat_member AS m WHERE s.chat_id = m.chat_id AND m.user_id = ?1)));";
+static SQL_COUNT_TAG: &str = "SELECT COUNT(*) FROM tag WHERE tag = ?;";
 
-static SQL_INCREASE_TAG_COUNTER: &'static str = "UPDATE tag SET counter = counter + 1 WHERE media_id = ? AND tag LIKE ?;";
+static SQL_READ_MEDIA: &str = "SELECT DISTINCT a.media_id, file_id, media_type FROM media AS a, tag AS b WHERE a.media_id = b.media_id AND broken = 0 AND pending = 0 AND hidden = 0 AND (nsfw = 0 OR EXISTS (SELECT 1 FROM chat_setting WHERE chat_id = ?1 AND safe_search = 0)) AND (visibility = 0 OR owner_id = ?1 OR (visibility = 2 AND a.media_id IN (SELECT s.media_id FROM media_share AS s, chat_member AS m WHERE s.chat_id = m.chat_id AND m.user_id = ?1))) ORDER BY counter DESC LIMIT ?2;";
+static SQL_READ_MEDIA_WITH_MEDIAID: &str = "SELECT media_id, file_id, media_type FROM media AS a WHERE media_id = ?2 AND broken = 0 AND pending = 0 AND hidden = 0 AND (visibility = 0 OR owner_id = ?1 OR (visibility = 2 AND a.media_id IN (SELECT s.media_id FROM media_share AS s, chat_member AS m WHERE s.chat_id = m.chat_id AND m.user_id = ?1)));";
+static SQL_READ_MEDIA_WITH_FILEID_AND_TYPE: &str = "SELECT media_id FROM media WHERE file_id = ? AND media_type = ?;";
+static SQL_READ_MEDIA_WITH_USER_AND_QUERY: &str = "SELECT DISTINCT a.media_id, file_id, media_type FROM media AS a, tag AS b WHERE a.media_id = b.media_id AND tag LIKE ?2 AND broken = 0 AND pending = 0 AND hidden = 0 AND (nsfw = 0 OR EXISTS (SELECT 1 FROM chat_setting WHERE chat_id = ?1 AND safe_search = 0)) AND (visibility = 0 OR owner_id = ?1 OR (visibility = 2 AND a.media_id IN (SELECT s.media_id FROM media_share AS s, chat_member AS m WHERE s.chat_id = m.chat_id AND m.user_id = ?1))) ORDER BY counter DESC LIMIT ?3;";
+static SQL_READ_MEDIA_OWNER: &str = "SELECT owner_id FROM media WHERE media_id = ?;";
+static SQL_READ_MEDIA_VISIBLE: &str = "SELECT COUNT(*) FROM media AS a WHERE media_id = ?2 AND pending = 0 AND (visibility = 0 OR owner_id = ?1 OR (visibility = 2 AND a.media_id IN (SELECT s.media_id FROM media_share AS s, chat_member AS m WHERE s.chat_id = m.chat_id AND m.user_id = ?1)));";
+static SQL_READ_MEDIA_NSFW: &str = "SELECT nsfw FROM media WHERE media_id = ?;";
+static SQL_READ_MEDIA_PENDING: &str = "SELECT pending FROM media WHERE media_id = ?;";
 
-static SQL_TRANSACTION_BEGIN: &'static str = "BEGIN TRANSACTION;";
-static SQL_TRANSACTION_END: &'static str = "END TRANSACTION;";
+static SQL_READ_MEDIA_WITHOUT_ARCHIVE: &str = "SELECT media_id, file_id, media_type FROM media WHERE archive_hash IS NULL;";
+static SQL_READ_ARCHIVED_MEDIA: &str = "SELECT media_id, media_type, archive_path, archive_hash FROM media WHERE archive_hash IS NOT NULL;";
+static SQL_READ_ALL_MEDIA: &str = "SELECT media_id, file_id, media_type, archive_path, archive_hash, owner_id, visibility, nsfw, pending, hidden FROM media ORDER BY media_id;";
+static SQL_READ_STORED_MEDIA: &str = "SELECT media_id, file_id, media_type, archive_path, archive_hash, owner_id, visibility, nsfw, pending, hidden FROM media WHERE media_id = ?;";
+static SQL_READ_MEDIA_SHARES: &str = "SELECT chat_id FROM media_share WHERE media_id = ? ORDER BY chat_id;";
+static SQL_READ_BROKEN_MEDIA: &str = "SELECT media_id, file_id, media_type FROM media WHERE broken = 1 ORDER BY media_id;";
+static SQL_READ_LIBRARY_STATS: &str = "SELECT (SELECT COUNT(*) FROM media), (SELECT COUNT(*) FROM media WHERE media_type = 0), (SELECT COUNT(*) FROM media WHERE media_type = 1), (SELECT COUNT(*) FROM media WHERE media_type = 2), (SELECT COUNT(*) FROM media WHERE archive_hash IS NOT NULL), (SELECT COUNT(DISTINCT tag) FROM tag), (SELECT COALESCE(SUM(counter), 0) FROM tag);";
+static SQL_READ_STATE: &str = "SELECT value FROM state WHERE key = ?;";
+static SQL_READ_HANDLED_UPDATE: &str = "SELECT update_id FROM handled_update WHERE update_id = ?;";
+static SQL_READ_CHAT_SETTING: &str = "SELECT shared_library, safe_search FROM chat_setting WHERE chat_id = ?;";
+static SQL_READ_USER_ROLE: &str = "SELECT role FROM user_role WHERE user_id = ?;";
+static SQL_READ_USERS_WITH_ROLE: &str = "SELECT user_id FROM user_role WHERE role = ? ORDER BY user_id;";
+static SQL_READ_USER_GROUPS: &str = "SELECT chat_id FROM chat_member WHERE user_id = ? ORDER BY chat_id;";
+static SQL_COUNT_REPORTS: &str = "SELECT COUNT(*) FROM report WHERE media_id = ? AND resolved = 0;";
+static SQL_READ_REPORTS: &str = "SELECT r.media_id, m.media_type, m.hidden, r.reason, COUNT(*) FROM report AS r, media AS m WHERE r.media_id = m.media_id AND r.resolved = 0 GROUP BY r.media_id, r.reason ORDER BY r.media_id, r.reason;";
+static SQL_READ_TOP_MEDIA: &str = "SELECT e.media_id, m.media_type, COUNT(*) FROM usage_event AS e, media AS m WHERE e.media_id = m.media_id AND e.kind = 1 AND e.created_at >= ? GROUP BY e.media_id ORDER BY COUNT(*) DESC, e.media_id LIMIT ?;";
+static SQL_READ_TOP_TAGS: &str = "SELECT t.tag, COUNT(*) FROM usage_event AS e, tag AS t WHERE e.media_id = t.media_id AND substr(t.tag, 1, length(e.query)) = e.query AND e.kind = 1 AND e.created_at >= ? GROUP BY t.tag ORDER BY COUNT(*) DESC, t.tag LIMIT ?;";
+static SQL_READ_MISSED_QUERIES: &str = "SELECT query, COUNT(*), COUNT(DISTINCT user_id) FROM usage_event WHERE kind = 0 AND results = 0 AND query != '' AND created_at >= ? GROUP BY query ORDER BY COUNT(*) DESC, query LIMIT ?;";
+static SQL_READ_ACTIVE_USERS: &str = "SELECT COUNT(DISTINCT user_id) FROM usage_event WHERE created_at >= ?;";
+static SQL_READ_DAILY_ACTIVITY: &str = "SELECT day, SUM(kind = 2), SUM(kind = 0), SUM(kind = 1), COUNT(DISTINCT user_id), (SELECT COUNT(*) FROM (SELECT MIN(created_at) AS first_seen FROM usage_event GROUP BY user_id) WHERE date(first_seen, 'unixepoch') = day) FROM (SELECT date(created_at, 'unixepoch') AS day, kind, user_id FROM usage_event WHERE created_at >= ?) GROUP BY day ORDER BY day;";
 
-#[derive(Debug)]
+static SQL_UPDATE_MEDIA_ARCHIVE: &str = "UPDATE media SET archive_path = ?, archive_hash = ? WHERE media_id = ?;";
+static SQL_UPDATE_MEDIA_FILEID: &str = "UPDATE media SET file_id = ?, broken = 0 WHERE media_id = ?;";
+static SQL_UPDATE_MEDIA_BROKEN: &str = "UPDATE media SET broken = 1 WHERE media_id = ? AND broken = 0;";
+static SQL_UPDATE_MEDIA_VISIBILITY: &str = "UPDATE media SET visibility = ? WHERE media_id = ?;";
+static SQL_UPDATE_MEDIA_NSFW: &str = "UPDATE media SET nsfw = ? WHERE media_id = ?;";
+static SQL_APPROVE_MEDIA: &str = "UPDATE media SET pending = 0 WHERE media_id = ? AND pending = 1;";
+static SQL_HIDE_MEDIA: &str = "UPDATE media SET hidden = 1 WHERE media_id = ? AND hidden = 0;";
+static SQL_UPDATE_MEDIA_HIDDEN: &str = "UPDATE media SET hidden = ? WHERE media_id = ?;";
+static SQL_ADVANCE_STATE: &str = "INSERT OR REPLACE INTO state (key, value) VALUES (?1, MAX(?2, COALESCE((SELECT value FROM state WHERE key = ?1), ?2)));";
+static SQL_UPDATE_CHAT_SHARED_LIBRARY: &str = "UPDATE chat_setting SET shared_library = ? WHERE chat_id = ?;";
+static SQL_UPDATE_CHAT_SAFE_SEARCH: &str = "UPDATE chat_setting SET safe_search = ? WHERE chat_id = ?;";
+static SQL_UPDATE_USER_ROLE: &str = "INSERT OR REPLACE INTO user_role (user_id, role) VALUES (?, ?);";
+static SQL_RESOLVE_REPORTS: &str = "UPDATE report SET resolved = 1 WHERE media_id = ? AND resolved = 0;";
+
+static SQL_INCREASE_TAG_COUNTER: &str = "UPDATE tag SET counter = counter + 1 WHERE media_id = ? AND tag LIKE ?;";
+static SQL_MERGE_TAG_COUNTER: &str = "UPDATE tag SET counter = MAX(counter, ?) WHERE media_id = ? AND tag = ?;";
+static SQL_MERGE_TAG_COUNTERS: &str = "UPDATE tag SET counter = counter + (SELECT b.counter FROM tag AS b WHERE b.media_id = tag.media_id AND b.tag = ?1) WHERE tag = ?2 AND media_id IN (SELECT media_id FROM tag WHERE tag = ?1);";
+static SQL_RENAME_TAG: &str = "UPDATE tag SET tag = ?2 WHERE tag = ?1;";
+
+static SQL_DELETE_MERGED_TAGS: &str = "DELETE FROM tag WHERE tag = ?1 AND media_id IN (SELECT media_id FROM tag WHERE tag = ?2);";
+static SQL_DELETE_TAGS_WITH_MEDIAID: &str = "DELETE FROM tag WHERE media_id = ?;";
+static SQL_DELETE_MEDIA_SHARES: &str = "DELETE FROM media_share WHERE media_id = ?;";
+static SQL_DELETE_MEDIA_REPORTS: &str = "DELETE FROM report WHERE media_id = ?;";
+static SQL_DELETE_MEDIA: &str = "DELETE FROM media WHERE media_id = ?;";
+static SQL_DELETE_PENDING_MEDIA: &str = "DELETE FROM media WHERE media_id = ? AND pending = 1;";
+static SQL_DELETE_HANDLED_UPDATES: &str = "DELETE FROM handled_update WHERE update_id < ?;";
+static SQL_DELETE_CHAT_MEMBER: &str = "DELETE FROM chat_member WHERE chat_id = ? AND user_id = ?;";
+static SQL_DELETE_USER_ROLE: &str = "DELETE FROM user_role WHERE user_id = ?;";
+
+static SQL_VACUUM: &str = "VACUUM;";
+
+static SQL_ENABLE_WAL: &str = "PRAGMA journal_mode = WAL;";
+static SQL_ENABLE_FOREIGN_KEYS: &str = "PRAGMA foreign_keys = ON;";
+static SQL_READ_USER_VERSION: &str = "PRAGMA user_version;";
+
+// Applied in order on top of the base tables, the index being the schema version it upgrades from.
+pub(crate) static SQL_MIGRATIONS: &[&str] = &[
+    "ALTER TABLE media ADD COLUMN archive_path TEXT; ALTER TABLE media ADD COLUMN archive_hash TEXT;",
+    "ALTER TABLE media ADD COLUMN broken INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE media ADD COLUMN library_id INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE media ADD COLUMN owner_id INTEGER; ALTER TABLE media ADD COLUMN visibility INTEGER NOT NULL DEFAULT 0; \
+     INSERT OR IGNORE INTO media_share (media_id, chat_id) SELECT media_id, library_id FROM media WHERE library_id != 0; \
+     UPDATE media SET visibility = 2 WHERE library_id != 0;",
+    "ALTER TABLE media ADD COLUMN nsfw INTEGER NOT NULL DEFAULT 0; ALTER TABLE chat_setting ADD COLUMN safe_search INTEGER NOT NULL DEFAULT 1;",
+    "ALTER TABLE media ADD COLUMN pending INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE media ADD COLUMN hidden INTEGER NOT NULL DEFAULT 0;",
+    // Resolved reports stay as history, a user may report the media again after them.
+    "CREATE TABLE report_new (media_id INTEGER NOT NULL, user_id INTEGER NOT NULL, reason INTEGER NOT NULL, resolved INTEGER NOT NULL DEFAULT 0, \
+     created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')), FOREIGN KEY(media_id) REFERENCES media(media_id)); \
+     INSERT INTO report_new (media_id, user_id, reason, resolved, created_at) SELECT media_id, user_id, reason, resolved, created_at FROM report; \
+     DROP TABLE report; ALTER TABLE report_new RENAME TO report; \
+     CREATE UNIQUE INDEX report_unresolved ON report (media_id, user_id) WHERE resolved = 0;",
+    // The chat a media was shared with moved to media_share three versions ago.
+    "ALTER TABLE media DROP COLUMN library_id;",
+];
+
+// Workers have their own connections, a deferred transaction that reads before it writes fails with SQLITE_BUSY
+// instead of waiting when another worker is writing. Taking the write lock up front waits out busy_timeout.
+static SQL_TRANSACTION_BEGIN: &str = "BEGIN IMMEDIATE TRANSACTION;";
+static SQL_TRANSACTION_END: &str = "END TRANSACTION;";
+static SQL_TRANSACTION_ROLLBACK: &str = "ROLLBACK TRANSACTION;";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MediaType {
     Photo,
     Mpeg4Gif,
@@ -30,11 +137,11 @@ pub enum MediaType {
 }
 
 impl ToSql for MediaType {
-    fn to_sql(&self) -> Result<ToSqlOutput, Error> {
-        match self {
-            &MediaType::Photo => Ok(ToSqlOutput::Owned(Value::Integer(0))),
-            &MediaType::Mpeg4Gif => Ok(ToSqlOutput::Owned(Value::Integer(1))),
-            &MediaType::ImageGif => Ok(ToSqlOutput::Owned(Value::Integer(2))),
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, Error> {
+        match *self {
+            MediaType::Photo => Ok(ToSqlOutput::Owned(Value::Integer(0))),
+            MediaType::Mpeg4Gif => Ok(ToSqlOutput::Owned(Value::Integer(1))),
+            MediaType::ImageGif => Ok(ToSqlOutput::Owned(Value::Integer(2))),
         }
     }
 }
@@ -51,6 +158,177 @@ impl FromSql for MediaType {
     }
 }
 
+// Who finds a media in inline results, its owner always does.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    Private,
+    // Members of the groups it is shared with.
+    Groups,
+}
+
+impl ToSql for Visibility {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, Error> {
+        match *self {
+            Visibility::Public => Ok(ToSqlOutput::Owned(Value::Integer(0))),
+            Visibility::Private => Ok(ToSqlOutput::Owned(Value::Integer(1))),
+            Visibility::Groups => Ok(ToSqlOutput::Owned(Value::Integer(2))),
+        }
+    }
+}
+
+impl FromSql for Visibility {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match value.as_i64() {
+            Ok(0) => Ok(Visibility::Public),
+            Ok(1) => Ok(Visibility::Private),
+            Ok(2) => Ok(Visibility::Groups),
+            Ok(_) => panic!("Unknown visibility"),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Visibility, String> {
+        match s.to_lowercase().as_ref() {
+            "public" => Ok(Visibility::Public),
+            "private" => Ok(Visibility::Private),
+            "groups" => Ok(Visibility::Groups),
+            _ => Err(format!("{} is not one of public, private or groups", s))
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Visibility::Public => write!(f, "public"),
+            Visibility::Private => write!(f, "private"),
+            Visibility::Groups => write!(f, "groups"),
+        }
+    }
+}
+
+// Ordered by what a user may do, each role can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Role {
+    Banned,
+    Viewer,
+    Contributor,
+    Admin,
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, Error> {
+        match *self {
+            Role::Banned => Ok(ToSqlOutput::Owned(Value::Integer(0))),
+            Role::Viewer => Ok(ToSqlOutput::Owned(Value::Integer(1))),
+            Role::Contributor => Ok(ToSqlOutput::Owned(Value::Integer(2))),
+            Role::Admin => Ok(ToSqlOutput::Owned(Value::Integer(3))),
+        }
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match value.as_i64() {
+            Ok(0) => Ok(Role::Banned),
+            Ok(1) => Ok(Role::Viewer),
+            Ok(2) => Ok(Role::Contributor),
+            Ok(3) => Ok(Role::Admin),
+            Ok(_) => panic!("Unknown role"),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Role, String> {
+        match s.to_lowercase().as_ref() {
+            "banned" => Ok(Role::Banned),
+            "viewer" => Ok(Role::Viewer),
+            "contributor" => Ok(Role::Contributor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("{} is not one of admin, contributor, viewer or banned", s))
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Role::Banned => write!(f, "banned"),
+            Role::Viewer => write!(f, "viewer"),
+            Role::Contributor => write!(f, "contributor"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+// Why a user reported a media.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportReason {
+    Offensive,
+    Spam,
+    Broken,
+    Other,
+}
+
+impl ToSql for ReportReason {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, Error> {
+        match *self {
+            ReportReason::Offensive => Ok(ToSqlOutput::Owned(Value::Integer(0))),
+            ReportReason::Spam => Ok(ToSqlOutput::Owned(Value::Integer(1))),
+            ReportReason::Broken => Ok(ToSqlOutput::Owned(Value::Integer(2))),
+            ReportReason::Other => Ok(ToSqlOutput::Owned(Value::Integer(3))),
+        }
+    }
+}
+
+impl FromSql for ReportReason {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match value.as_i64() {
+            Ok(0) => Ok(ReportReason::Offensive),
+            Ok(1) => Ok(ReportReason::Spam),
+            Ok(2) => Ok(ReportReason::Broken),
+            Ok(3) => Ok(ReportReason::Other),
+            Ok(_) => panic!("Unknown report reason"),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+impl FromStr for ReportReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ReportReason, String> {
+        match s.to_lowercase().as_ref() {
+            "offensive" => Ok(ReportReason::Offensive),
+            "spam" => Ok(ReportReason::Spam),
+            "broken" => Ok(ReportReason::Broken),
+            "other" => Ok(ReportReason::Other),
+            _ => Err(format!("{} is not one of offensive, spam, broken or other", s))
+        }
+    }
+}
+
+impl fmt::Display for ReportReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReportReason::Offensive => write!(f, "offensive"),
+            ReportReason::Spam => write!(f, "spam"),
+            ReportReason::Broken => write!(f, "broken"),
+            ReportReason::Other => write!(f, "other"),
+        }
+    }
+}
+
 pub struct Connection {
     sqlite_conn: rusqlite::Connection,
 }
@@ -66,35 +344,248 @@ struct StatementCache<'a> {
     read_media_with_fileid_and_type: rusqlite::Statement<'a>,
     read_media_with_mediaid: rusqlite::Statement<'a>,
     read_media_with_query: rusqlite::Statement<'a>,
+    read_media_without_archive: rusqlite::Statement<'a>,
+    read_archived_media: rusqlite::Statement<'a>,
     read_tag: rusqlite::Statement<'a>,
+    update_media_archive: rusqlite::Statement<'a>,
+    update_media_fileid: rusqlite::Statement<'a>,
     increase_tag_counter: rusqlite::Statement<'a>,
+    read_all_media: rusqlite::Statement<'a>,
+    read_tags_with_mediaid: rusqlite::Statement<'a>,
+    merge_tag_counter: rusqlite::Statement<'a>,
+    read_stored_media: rusqlite::Statement<'a>,
+    read_tag_summaries: rusqlite::Statement<'a>,
+    count_tag: rusqlite::Statement<'a>,
+    merge_tag_counters: rusqlite::Statement<'a>,
+    delete_merged_tags: rusqlite::Statement<'a>,
+    rename_tag: rusqlite::Statement<'a>,
+    delete_tags_with_mediaid: rusqlite::Statement<'a>,
+    delete_media: rusqlite::Statement<'a>,
+    read_library_stats: rusqlite::Statement<'a>,
+    vacuum: rusqlite::Statement<'a>,
+    read_user_version: rusqlite::Statement<'a>,
+    read_state: rusqlite::Statement<'a>,
+    advance_state: rusqlite::Statement<'a>,
+    insert_handled_update: rusqlite::Statement<'a>,
+    read_handled_update: rusqlite::Statement<'a>,
+    delete_handled_updates: rusqlite::Statement<'a>,
+    update_media_broken: rusqlite::Statement<'a>,
+    read_broken_media: rusqlite::Statement<'a>,
+    insert_chat_member: rusqlite::Statement<'a>,
+    insert_chat_setting: rusqlite::Statement<'a>,
+    read_chat_setting: rusqlite::Statement<'a>,
+    update_chat_shared_library: rusqlite::Statement<'a>,
+    delete_chat_member: rusqlite::Statement<'a>,
+    read_user_role: rusqlite::Statement<'a>,
+    read_users_with_role: rusqlite::Statement<'a>,
+    update_user_role: rusqlite::Statement<'a>,
+    delete_user_role: rusqlite::Statement<'a>,
+    insert_media_share: rusqlite::Statement<'a>,
+    read_media_owner: rusqlite::Statement<'a>,
+    read_user_groups: rusqlite::Statement<'a>,
+    update_media_visibility: rusqlite::Statement<'a>,
+    delete_media_shares: rusqlite::Statement<'a>,
+    update_chat_safe_search: rusqlite::Statement<'a>,
+    update_media_nsfw: rusqlite::Statement<'a>,
+    read_media_nsfw: rusqlite::Statement<'a>,
+    read_media_pending: rusqlite::Statement<'a>,
+    approve_media: rusqlite::Statement<'a>,
+    insert_report: rusqlite::Statement<'a>,
+    count_reports: rusqlite::Statement<'a>,
+    read_reports: rusqlite::Statement<'a>,
+    hide_media: rusqlite::Statement<'a>,
+    resolve_reports: rusqlite::Statement<'a>,
+    delete_media_reports: rusqlite::Statement<'a>,
+    insert_usage_event: rusqlite::Statement<'a>,
+    read_top_media: rusqlite::Statement<'a>,
+    read_top_tags: rusqlite::Statement<'a>,
+    read_missed_queries: rusqlite::Statement<'a>,
+    read_active_users: rusqlite::Statement<'a>,
+    read_daily_activity: rusqlite::Statement<'a>,
+    read_visible_tags: rusqlite::Statement<'a>,
+    read_media_shares: rusqlite::Statement<'a>,
+    delete_pending_media: rusqlite::Statement<'a>,
+    read_media_visible: rusqlite::Statement<'a>,
+    update_media_hidden: rusqlite::Statement<'a>,
     transaction_begin: rusqlite::Statement<'a>,
     transaction_end: rusqlite::Statement<'a>,
+    transaction_rollback: rusqlite::Statement<'a>,
 }
 
 pub enum Entity {
     Media { id: i64, file_id: String, media_type: MediaType },
-    Tag { id: i64, media_id: i64, tag: String, counter: i64 },
+    Tag { media_id: i64, tag: String, counter: i64 },
+}
+
+#[derive(Serialize)]
+pub struct StoredMedia {
+    pub media_id: i64,
+    pub file_id: String,
+    pub media_type: MediaType,
+    pub archive_path: Option<String>,
+    pub archive_hash: Option<String>,
+    pub owner_id: Option<i64>,
+    pub visibility: Visibility,
+    pub shared_with: Vec<i64>,
+    pub nsfw: bool,
+    pub pending: bool,
+    pub hidden: bool,
+}
+
+#[derive(Serialize)]
+pub struct TagSummary {
+    pub tag: String,
+    pub media: i64,
+    pub counter: i64,
+}
+
+#[derive(Serialize)]
+pub struct LibraryStats {
+    pub schema_version: i64,
+    pub media: i64,
+    pub photos: i64,
+    pub mpeg4_gifs: i64,
+    pub gifs: i64,
+    pub archived: i64,
+    pub tags: i64,
+    pub uses: i64,
+}
+
+pub struct ChatSettings {
+    // Media saved in the group is shared with the group only.
+    pub shared_library: bool,
+    // Leaves NSFW media out of the user's inline results, only meaningful for private chats.
+    pub safe_search: bool,
+}
+
+// What a user did, recorded for the usage reports.
+pub enum UsageEvent<'a> {
+    // An inline query and how many results it got.
+    Query { query: &'a str, results: i64 },
+    // An inline result the user sent and the query it was found with.
+    Send { media_id: i64, query: &'a str },
+    // Media the user added to the library.
+    Upload { media_id: i64 },
+}
+
+#[derive(Serialize)]
+pub struct MediaUses {
+    pub media_id: i64,
+    pub media_type: MediaType,
+    pub uses: i64,
+}
+
+#[derive(Serialize)]
+pub struct TagUses {
+    pub tag: String,
+    pub uses: i64,
+}
+
+#[derive(Serialize)]
+pub struct MissedQuery {
+    pub query: String,
+    pub misses: i64,
+    pub users: i64,
+}
+
+// Activity of one UTC day, new users are the ones seen for the first time that day.
+#[derive(Serialize)]
+pub struct DailyActivity {
+    pub day: String,
+    pub uploads: i64,
+    pub queries: i64,
+    pub sends: i64,
+    pub active_users: i64,
+    pub new_users: i64,
+}
+
+impl Default for ChatSettings {
+    fn default() -> ChatSettings {
+        ChatSettings { shared_library: false, safe_search: true }
+    }
+}
+
+// Unresolved reports of one media.
+pub struct ReportSummary {
+    pub media_id: i64,
+    pub media_type: MediaType,
+    pub hidden: bool,
+    pub reasons: Vec<(ReportReason, i64)>,
+}
+
+pub struct ArchivedMedia {
+    pub media_id: i64,
+    pub media_type: MediaType,
+    pub archive_path: String,
+    pub archive_hash: String,
 }
 
 impl Connection {
+    // `path` is a file path, a `file:` URI or `:memory:`.
     pub fn new(path: String) -> Connection {
-        Connection {
-            sqlite_conn: rusqlite::Connection::open(path + DB_NAME).expect("Failed to open database.")
+        let mut sqlite_conn = rusqlite::Connection::open(&path).expect("Failed to open database.");
+
+        sqlite_conn.profile(Some(profile_statement));
+
+        // Read-only URIs can't switch the journal mode, which is not a reason to refuse them.
+        if let Err(e) = sqlite_conn.execute_batch(SQL_ENABLE_WAL) {
+            warn!("Failed to enable write-ahead logging: {}", e);
         }
+
+        sqlite_conn.execute_batch(&format!("PRAGMA busy_timeout = {};", BUSY_TIMEOUT_MSEC))
+                   .expect("Failed to set busy timeout.");
+
+        sqlite_conn.execute_batch(SQL_ENABLE_FOREIGN_KEYS)
+                   .expect("Failed to enable foreign keys.");
+
+        Connection { sqlite_conn }
     }
 }
 
 impl<'a> DB<'a> {
     pub fn new(c: &'a Connection) -> DB<'a> {
         c.sqlite_conn
-         .execute(SQL_CREATE_TABLE_MEDIA, &[])
+         .execute(SQL_CREATE_TABLE_MEDIA, [])
          .expect("Unable to create table media.");
 
         c.sqlite_conn
-         .execute(SQL_CREATE_TABLE_TAG, &[])
+         .execute(SQL_CREATE_TABLE_TAG, [])
          .expect("Unable to create table tag.");
 
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_STATE, [])
+         .expect("Unable to create table state.");
+
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_HANDLED_UPDATE, [])
+         .expect("Unable to create table handled_update.");
+
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_CHAT_SETTING, [])
+         .expect("Unable to create table chat_setting.");
+
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_CHAT_MEMBER, [])
+         .expect("Unable to create table chat_member.");
+
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_MEDIA_SHARE, [])
+         .expect("Unable to create table media_share.");
+
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_USER_ROLE, [])
+         .expect("Unable to create table user_role.");
+
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_REPORT, [])
+         .expect("Unable to create table report.");
+
+        c.sqlite_conn
+         .execute(SQL_CREATE_TABLE_USAGE_EVENT, [])
+         .expect("Unable to create table usage_event.");
+
+        migrate(&c.sqlite_conn);
+
         let insert_media = c.sqlite_conn
                             .prepare(SQL_INSERT_MEDIA)
                             .expect("Failed preparing media insert statement.");
@@ -119,6 +610,22 @@ impl<'a> DB<'a> {
                                      .prepare(SQL_READ_MEDIA_WITH_USER_AND_QUERY)
                                      .expect("Failed preparing media read with owner and query statement.");
 
+        let read_media_without_archive = c.sqlite_conn
+                                          .prepare(SQL_READ_MEDIA_WITHOUT_ARCHIVE)
+                                          .expect("Failed preparing media read without archive statement.");
+
+        let read_archived_media = c.sqlite_conn
+                                   .prepare(SQL_READ_ARCHIVED_MEDIA)
+                                   .expect("Failed preparing archived media read statement.");
+
+        let update_media_archive = c.sqlite_conn
+                                    .prepare(SQL_UPDATE_MEDIA_ARCHIVE)
+                                    .expect("Failed preparing media archive update statement.");
+
+        let update_media_fileid = c.sqlite_conn
+                                   .prepare(SQL_UPDATE_MEDIA_FILEID)
+                                   .expect("Failed preparing media file_id update statement.");
+
         let read_tag = c.sqlite_conn
                         .prepare(SQL_READ_TAG)
                         .expect("Failed preparing tag read statement.");
@@ -127,6 +634,234 @@ impl<'a> DB<'a> {
                                     .prepare(SQL_INCREASE_TAG_COUNTER)
                                     .expect("Failed preparing increase tag counter statement.");
 
+        let read_all_media = c.sqlite_conn
+                              .prepare(SQL_READ_ALL_MEDIA)
+                              .expect("Failed preparing all media read statement.");
+
+        let read_tags_with_mediaid = c.sqlite_conn
+                                      .prepare(SQL_READ_TAGS_WITH_MEDIAID)
+                                      .expect("Failed preparing tags read with mediaid statement.");
+
+        let merge_tag_counter = c.sqlite_conn
+                                 .prepare(SQL_MERGE_TAG_COUNTER)
+                                 .expect("Failed preparing merge tag counter statement.");
+
+        let read_stored_media = c.sqlite_conn
+                                 .prepare(SQL_READ_STORED_MEDIA)
+                                 .expect("Failed preparing stored media read statement.");
+
+        let read_tag_summaries = c.sqlite_conn
+                                  .prepare(SQL_READ_TAG_SUMMARIES)
+                                  .expect("Failed preparing tag summaries read statement.");
+
+        let count_tag = c.sqlite_conn
+                         .prepare(SQL_COUNT_TAG)
+                         .expect("Failed preparing tag count statement.");
+
+        let merge_tag_counters = c.sqlite_conn
+                                  .prepare(SQL_MERGE_TAG_COUNTERS)
+                                  .expect("Failed preparing tag counters merge statement.");
+
+        let delete_merged_tags = c.sqlite_conn
+                                  .prepare(SQL_DELETE_MERGED_TAGS)
+                                  .expect("Failed preparing merged tags delete statement.");
+
+        let rename_tag = c.sqlite_conn
+                          .prepare(SQL_RENAME_TAG)
+                          .expect("Failed preparing tag rename statement.");
+
+        let delete_tags_with_mediaid = c.sqlite_conn
+                                        .prepare(SQL_DELETE_TAGS_WITH_MEDIAID)
+                                        .expect("Failed preparing tags delete with mediaid statement.");
+
+        let delete_media = c.sqlite_conn
+                            .prepare(SQL_DELETE_MEDIA)
+                            .expect("Failed preparing media delete statement.");
+
+        let read_library_stats = c.sqlite_conn
+                                  .prepare(SQL_READ_LIBRARY_STATS)
+                                  .expect("Failed preparing library stats read statement.");
+
+        let vacuum = c.sqlite_conn
+                      .prepare(SQL_VACUUM)
+                      .expect("Failed preparing vacuum statement.");
+
+        let read_user_version = c.sqlite_conn
+                                 .prepare(SQL_READ_USER_VERSION)
+                                 .expect("Failed preparing schema version read statement.");
+
+        let read_state = c.sqlite_conn
+                          .prepare(SQL_READ_STATE)
+                          .expect("Failed preparing state read statement.");
+
+        let advance_state = c.sqlite_conn
+                             .prepare(SQL_ADVANCE_STATE)
+                             .expect("Failed preparing state advance statement.");
+
+        let insert_handled_update = c.sqlite_conn
+                                     .prepare(SQL_INSERT_HANDLED_UPDATE)
+                                     .expect("Failed preparing handled update insert statement.");
+
+        let read_handled_update = c.sqlite_conn
+                                   .prepare(SQL_READ_HANDLED_UPDATE)
+                                   .expect("Failed preparing handled update read statement.");
+
+        let delete_handled_updates = c.sqlite_conn
+                                      .prepare(SQL_DELETE_HANDLED_UPDATES)
+                                      .expect("Failed preparing handled updates delete statement.");
+
+        let update_media_broken = c.sqlite_conn
+                                   .prepare(SQL_UPDATE_MEDIA_BROKEN)
+                                   .expect("Failed preparing media broken update statement.");
+
+        let read_broken_media = c.sqlite_conn
+                                 .prepare(SQL_READ_BROKEN_MEDIA)
+                                 .expect("Failed preparing broken media read statement.");
+
+        let insert_chat_member = c.sqlite_conn
+                                  .prepare(SQL_INSERT_CHAT_MEMBER)
+                                  .expect("Failed preparing chat member insert statement.");
+
+        let insert_chat_setting = c.sqlite_conn
+                                   .prepare(SQL_INSERT_CHAT_SETTING)
+                                   .expect("Failed preparing chat setting insert statement.");
+
+        let read_chat_setting = c.sqlite_conn
+                                 .prepare(SQL_READ_CHAT_SETTING)
+                                 .expect("Failed preparing chat setting read statement.");
+
+        let update_chat_shared_library = c.sqlite_conn
+                                          .prepare(SQL_UPDATE_CHAT_SHARED_LIBRARY)
+                                          .expect("Failed preparing chat shared library update statement.");
+
+        let delete_chat_member = c.sqlite_conn
+                                  .prepare(SQL_DELETE_CHAT_MEMBER)
+                                  .expect("Failed preparing chat member delete statement.");
+
+        let read_user_role = c.sqlite_conn
+                              .prepare(SQL_READ_USER_ROLE)
+                              .expect("Failed preparing user role read statement.");
+
+        let read_users_with_role = c.sqlite_conn
+                                    .prepare(SQL_READ_USERS_WITH_ROLE)
+                                    .expect("Failed preparing users with role read statement.");
+
+        let update_user_role = c.sqlite_conn
+                                .prepare(SQL_UPDATE_USER_ROLE)
+                                .expect("Failed preparing user role update statement.");
+
+        let delete_user_role = c.sqlite_conn
+                                .prepare(SQL_DELETE_USER_ROLE)
+                                .expect("Failed preparing user role delete statement.");
+
+        let insert_media_share = c.sqlite_conn
+                                  .prepare(SQL_INSERT_MEDIA_SHARE)
+                                  .expect("Failed preparing media share insert statement.");
+
+        let read_media_owner = c.sqlite_conn
+                                .prepare(SQL_READ_MEDIA_OWNER)
+                                .expect("Failed preparing media owner read statement.");
+
+        let read_user_groups = c.sqlite_conn
+                                .prepare(SQL_READ_USER_GROUPS)
+                                .expect("Failed preparing user groups read statement.");
+
+        let update_media_visibility = c.sqlite_conn
+                                       .prepare(SQL_UPDATE_MEDIA_VISIBILITY)
+                                       .expect("Failed preparing media visibility update statement.");
+
+        let delete_media_shares = c.sqlite_conn
+                                   .prepare(SQL_DELETE_MEDIA_SHARES)
+                                   .expect("Failed preparing media shares delete statement.");
+
+        let update_chat_safe_search = c.sqlite_conn
+                                       .prepare(SQL_UPDATE_CHAT_SAFE_SEARCH)
+                                       .expect("Failed preparing chat safe search update statement.");
+
+        let update_media_nsfw = c.sqlite_conn
+                                 .prepare(SQL_UPDATE_MEDIA_NSFW)
+                                 .expect("Failed preparing media nsfw update statement.");
+
+        let read_media_nsfw = c.sqlite_conn
+                               .prepare(SQL_READ_MEDIA_NSFW)
+                               .expect("Failed preparing media nsfw read statement.");
+
+        let read_media_pending = c.sqlite_conn
+                                  .prepare(SQL_READ_MEDIA_PENDING)
+                                  .expect("Failed preparing media pending read statement.");
+
+        let approve_media = c.sqlite_conn
+                             .prepare(SQL_APPROVE_MEDIA)
+                             .expect("Failed preparing media approval statement.");
+
+        let insert_report = c.sqlite_conn
+                             .prepare(SQL_INSERT_REPORT)
+                             .expect("Failed preparing report insert statement.");
+
+        let count_reports = c.sqlite_conn
+                             .prepare(SQL_COUNT_REPORTS)
+                             .expect("Failed preparing report count statement.");
+
+        let read_reports = c.sqlite_conn
+                            .prepare(SQL_READ_REPORTS)
+                            .expect("Failed preparing reports read statement.");
+
+        let hide_media = c.sqlite_conn
+                          .prepare(SQL_HIDE_MEDIA)
+                          .expect("Failed preparing media hide statement.");
+
+        let resolve_reports = c.sqlite_conn
+                               .prepare(SQL_RESOLVE_REPORTS)
+                               .expect("Failed preparing reports resolve statement.");
+
+        let delete_media_reports = c.sqlite_conn
+                                    .prepare(SQL_DELETE_MEDIA_REPORTS)
+                                    .expect("Failed preparing media reports delete statement.");
+
+        let insert_usage_event = c.sqlite_conn
+                                  .prepare(SQL_INSERT_USAGE_EVENT)
+                                  .expect("Failed preparing usage event insert statement.");
+
+        let read_top_media = c.sqlite_conn
+                              .prepare(SQL_READ_TOP_MEDIA)
+                              .expect("Failed preparing top media statement.");
+
+        let read_top_tags = c.sqlite_conn
+                             .prepare(SQL_READ_TOP_TAGS)
+                             .expect("Failed preparing top tags statement.");
+
+        let read_missed_queries = c.sqlite_conn
+                                   .prepare(SQL_READ_MISSED_QUERIES)
+                                   .expect("Failed preparing missed queries statement.");
+
+        let read_active_users = c.sqlite_conn
+                                 .prepare(SQL_READ_ACTIVE_USERS)
+                                 .expect("Failed preparing active users statement.");
+
+        let read_daily_activity = c.sqlite_conn
+                                   .prepare(SQL_READ_DAILY_ACTIVITY)
+                                   .expect("Failed preparing daily activity statement.");
+
+        let read_visible_tags = c.sqlite_conn
+                                 .prepare(SQL_READ_VISIBLE_TAGS)
+                                 .expect("Failed preparing visible tags statement.");
+
+        let read_media_shares = c.sqlite_conn
+                                 .prepare(SQL_READ_MEDIA_SHARES)
+                                 .expect("Failed preparing media shares statement.");
+
+        let delete_pending_media = c.sqlite_conn
+                                    .prepare(SQL_DELETE_PENDING_MEDIA)
+                                    .expect("Failed preparing pending media delete statement.");
+
+        let read_media_visible = c.sqlite_conn
+                                  .prepare(SQL_READ_MEDIA_VISIBLE)
+                                  .expect("Failed preparing media visible read statement.");
+
+        let update_media_hidden = c.sqlite_conn
+                                   .prepare(SQL_UPDATE_MEDIA_HIDDEN)
+                                   .expect("Failed preparing media hidden update statement.");
+
         let transaction_begin = c.sqlite_conn
                                  .prepare(SQL_TRANSACTION_BEGIN)
                                  .expect("Failed preparing transaction begin statement.");
@@ -135,6 +870,10 @@ impl<'a> DB<'a> {
                                .prepare(SQL_TRANSACTION_END)
                                .expect("Failed preparing transaction begin statement.");
 
+        let transaction_rollback = c.sqlite_conn
+                                    .prepare(SQL_TRANSACTION_ROLLBACK)
+                                    .expect("Failed preparing transaction rollback statement.");
+
         let statement_cache = StatementCache {
             insert_media,
             insert_tag,
@@ -142,117 +881,1341 @@ impl<'a> DB<'a> {
             read_media_with_fileid_and_type,
             read_media_with_mediaid,
             read_media_with_query,
+            read_media_without_archive,
+            read_archived_media,
             read_tag,
+            update_media_archive,
+            update_media_fileid,
             increase_tag_counter,
+            read_all_media,
+            read_tags_with_mediaid,
+            merge_tag_counter,
+            read_stored_media,
+            read_tag_summaries,
+            count_tag,
+            merge_tag_counters,
+            delete_merged_tags,
+            rename_tag,
+            delete_tags_with_mediaid,
+            delete_media,
+            read_library_stats,
+            vacuum,
+            read_user_version,
+            read_state,
+            advance_state,
+            insert_handled_update,
+            read_handled_update,
+            delete_handled_updates,
+            update_media_broken,
+            read_broken_media,
+            insert_chat_member,
+            insert_chat_setting,
+            read_chat_setting,
+            update_chat_shared_library,
+            delete_chat_member,
+            read_user_role,
+            read_users_with_role,
+            update_user_role,
+            delete_user_role,
+            insert_media_share,
+            read_media_owner,
+            read_user_groups,
+            update_media_visibility,
+            delete_media_shares,
+            update_chat_safe_search,
+            update_media_nsfw,
+            read_media_nsfw,
+            read_media_pending,
+            approve_media,
+            insert_report,
+            count_reports,
+            read_reports,
+            hide_media,
+            resolve_reports,
+            delete_media_reports,
+            insert_usage_event,
+            read_top_media,
+            read_top_tags,
+            read_missed_queries,
+            read_active_users,
+            read_daily_activity,
+            read_visible_tags,
+            read_media_shares,
+            delete_pending_media,
+            read_media_visible,
+            update_media_hidden,
             transaction_begin,
             transaction_end,
+            transaction_rollback,
         };
 
         DB { statement_cache }
     }
 
-    pub fn read_media(&mut self) -> Vec<Entity> {
+    // Media in the public library and the group libraries `user_id` is a member of.
+    pub fn read_media(&mut self, user_id: i64, limit: i64) -> Vec<Entity> {
         self.statement_cache
             .read_media
-            .query_map(&[],
-                       |row| Entity::Media {
-                           id: row.get(0),
-                           file_id: row.get(1),
-                           media_type: row.get(2),
-                       })
+            .query_map(params![user_id, limit],
+                       |row| Ok(Entity::Media {
+                           id: row.get(0)?,
+                           file_id: row.get(1)?,
+                           media_type: row.get(2)?,
+                       }))
             .expect("Failed to read media.")
             .filter_map(|r| r.ok())
             .collect()
     }
 
-    pub fn read_media_with_mediaid(&mut self, media_id: i64) -> Entity {
+    // None when there is no such media or `user_id` may not see it.
+    pub fn read_media_with_mediaid(&mut self, user_id: i64, media_id: i64) -> Option<Entity> {
         self.statement_cache
             .read_media_with_mediaid
-            .query_map(&[&media_id],
-                       |row| Entity::Media {
-                           id: row.get(0),
-                           file_id: row.get(1),
-                           media_type: row.get(2),
-                       })
+            .query_map(params![user_id, media_id],
+                       |row| Ok(Entity::Media {
+                           id: row.get(0)?,
+                           file_id: row.get(1)?,
+                           media_type: row.get(2)?,
+                       }))
             .expect("Failed to read media with media_id.")
             .filter_map(|r| r.ok())
             .last()
-            .unwrap()
     }
 
-    pub fn read_media_with_query(&mut self, query: String) -> Vec<Entity> {
-        let query = query + "%";
+    // Some(None) for media stored before uploaders were recorded.
+    pub fn read_media_owner(&mut self, media_id: i64) -> Option<Option<i64>> {
+        self.statement_cache
+            .read_media_owner
+            .query_map(params![media_id], |row| row.get(0))
+            .expect("Failed to read media owner.")
+            .filter_map(|r| r.ok())
+            .next()
+    }
 
+    // Whether `user_id` may see the media, by the same rule as the read queries. Hidden and broken media still count,
+    // it was in someone's results before.
+    pub fn is_media_visible(&mut self, user_id: i64, media_id: i64) -> bool {
         self.statement_cache
-            .read_media_with_query
-            .query_map(&[&query],
-                       |row| Entity::Media {
-                           id: row.get(0),
-                           file_id: row.get(1),
-                           media_type: row.get(2),
-                       })
-            .expect("Failed to read media with query.")
+            .read_media_visible
+            .query_row(params![user_id, media_id], |row| row.get::<_, i64>(0))
+            .expect("Failed to read media visibility.") > 0
+    }
+
+    pub fn is_media_nsfw(&mut self, media_id: i64) -> bool {
+        self.statement_cache
+            .read_media_nsfw
+            .query_map(params![media_id], |row| row.get(0))
+            .expect("Failed to read media nsfw.")
             .filter_map(|r| r.ok())
-            .collect()
+            .next()
+            .unwrap_or(false)
     }
 
-    pub fn increase_tag_counter(&mut self, media_id: i64, query: String) {
-        let query = query + "%";
+    pub fn update_media_nsfw(&mut self, media_id: i64, nsfw: bool) {
+        self.statement_cache
+            .update_media_nsfw
+            .execute(params![nsfw, media_id])
+            .expect("Failed to update media nsfw.");
+    }
+
+    // None when there is no such media.
+    pub fn is_media_pending(&mut self, media_id: i64) -> Option<bool> {
+        self.statement_cache
+            .read_media_pending
+            .query_map(params![media_id], |row| row.get(0))
+            .expect("Failed to read media pending.")
+            .filter_map(|r| r.ok())
+            .next()
+    }
 
+    // Pending media waits for an admin's approval and stays out of results until then. Returns false when it is
+    // no longer pending, another admin got to it first.
+    pub fn approve_media(&mut self, media_id: i64) -> bool {
         self.statement_cache
-            .increase_tag_counter
-            .execute(&[&media_id, &query])
-            .expect("Failed to update tag counter.");
+            .approve_media
+            .execute(params![media_id])
+            .expect("Failed to approve media.") == 1
     }
 
-    pub fn insert(&mut self, entity: Entity) -> i64 {
+    // Deletes pending media with its tags, returns false when it is no longer pending.
+    pub fn reject_media(&mut self, media_id: i64) -> bool {
+        // Taking the write lock first keeps another admin's decision from landing between the check and the delete.
         self.statement_cache
             .transaction_begin
-            .execute(&[])
+            .execute([])
             .expect("Failed to begin transaction.");
 
-        let media_id = match entity {
-            Entity::Media { file_id, media_type, .. } => {
-                if let Some(row) = self.statement_cache
-                                       .read_media_with_fileid_and_type
-                                       .query(&[&file_id, &media_type])
-                                       .expect("Failed to run read_media statement.")
-                                       .next() {
-                    row.unwrap().get(0)
-                } else {
-                    info!("Inserting media with file_id = {} media_type = {:?}", file_id, media_type);
+        let deleted = if self.is_media_pending(media_id) == Some(true) {
+            self.statement_cache
+                .delete_tags_with_mediaid
+                .execute(params![media_id])
+                .expect("Failed to delete tags with media_id.");
 
-                    self.statement_cache
-                        .insert_media
-                        .insert(&[&file_id, &media_type])
-                        .expect("Failed to run insert_media statement.")
-                }
-            }
-            Entity::Tag { media_id, tag, .. } => {
-                let tag = tag.to_lowercase();
+            self.statement_cache
+                .delete_media_shares
+                .execute(params![media_id])
+                .expect("Failed to delete media shares.");
 
-                if let Some(row) = self.statement_cache
-                                       .read_tag
-                                       .query(&[&media_id, &tag])
-                                       .expect("Failed to run read_tag statement.")
-                                       .next() {
-                    row.unwrap().get(0)
-                } else {
-                    info!("Inserting tag {} to media_id {}", tag, media_id);
+            self.statement_cache
+                .delete_media_reports
+                .execute(params![media_id])
+                .expect("Failed to delete media reports.");
 
-                    self.statement_cache
-                        .insert_tag
-                        .insert(&[&media_id, &tag])
-                        .expect("Failed to run insert_tag statement.")
-                }
-            }
+            self.statement_cache
+                .delete_pending_media
+                .execute(params![media_id])
+                .expect("Failed to delete pending media.") == 1
+        } else {
+            false
         };
 
         self.statement_cache
             .transaction_end
-            .execute(&[])
+            .execute([])
             .expect("Failed to end transaction.");
 
-        media_id
+        deleted
+    }
+
+    // Hidden media stays out of results until an admin resolves its reports.
+    pub fn update_media_hidden(&mut self, media_id: i64, hidden: bool) {
+        self.statement_cache
+            .update_media_hidden
+            .execute(params![hidden, media_id])
+            .expect("Failed to update media hidden.");
+    }
+
+    // Returns false when the media already was hidden.
+    pub fn hide_media(&mut self, media_id: i64) -> bool {
+        self.statement_cache
+            .hide_media
+            .execute(params![media_id])
+            .expect("Failed to hide media.") == 1
+    }
+
+    // Each user has one unresolved report per media, None when they have one, otherwise the number of unresolved reports.
+    pub fn insert_report(&mut self, media_id: i64, user_id: i64, reason: ReportReason) -> Option<i64> {
+        let inserted = self.statement_cache
+                           .insert_report
+                           .execute(params![media_id, user_id, reason])
+                           .expect("Failed to insert report.");
+
+        if inserted == 0 {
+            return None;
+        }
+
+        self.statement_cache
+            .count_reports
+            .query_row(params![media_id], |row| row.get(0))
+            .ok()
+    }
+
+    pub fn insert_usage_event(&mut self, user_id: i64, event: UsageEvent) {
+        let (kind, media_id, query, results) = match event {
+            UsageEvent::Query { query, results } => (0, None, Some(query), Some(results)),
+            UsageEvent::Send { media_id, query } => (1, Some(media_id), Some(query), None),
+            UsageEvent::Upload { media_id } => (2, Some(media_id), None, None),
+        };
+
+        self.statement_cache
+            .insert_usage_event
+            .execute(params![kind, user_id, media_id, query, results])
+            .expect("Failed to insert usage event.");
+    }
+
+    // The reads below count events at or after `since`, in seconds since the epoch.
+    pub fn read_top_media(&mut self, since: i64, limit: i64) -> Vec<MediaUses> {
+        self.statement_cache
+            .read_top_media
+            .query_map(params![since, limit], |row| Ok(MediaUses { media_id: row.get(0)?, media_type: row.get(1)?, uses: row.get(2)? }))
+            .expect("Failed to read top media.")
+            .filter_map(|m| m.ok())
+            .collect()
+    }
+
+    // A send counts for every tag of the media that starts with the query it was sent from.
+    pub fn read_top_tags(&mut self, since: i64, limit: i64) -> Vec<TagUses> {
+        self.statement_cache
+            .read_top_tags
+            .query_map(params![since, limit], |row| Ok(TagUses { tag: row.get(0)?, uses: row.get(1)? }))
+            .expect("Failed to read top tags.")
+            .filter_map(|t| t.ok())
+            .collect()
+    }
+
+    pub fn read_missed_queries(&mut self, since: i64, limit: i64) -> Vec<MissedQuery> {
+        self.statement_cache
+            .read_missed_queries
+            .query_map(params![since, limit], |row| Ok(MissedQuery { query: row.get(0)?, misses: row.get(1)?, users: row.get(2)? }))
+            .expect("Failed to read missed queries.")
+            .filter_map(|q| q.ok())
+            .collect()
+    }
+
+    pub fn read_active_users(&mut self, since: i64) -> i64 {
+        self.statement_cache
+            .read_active_users
+            .query_row(params![since], |row| row.get(0))
+            .expect("Failed to read active users.")
+    }
+
+    pub fn read_daily_activity(&mut self, since: i64) -> Vec<DailyActivity> {
+        self.statement_cache
+            .read_daily_activity
+            .query_map(params![since],
+                       |row| Ok(DailyActivity {
+                           day: row.get(0)?,
+                           uploads: row.get(1)?,
+                           queries: row.get(2)?,
+                           sends: row.get(3)?,
+                           active_users: row.get(4)?,
+                           new_users: row.get(5)?,
+                       }))
+            .expect("Failed to read daily activity.")
+            .filter_map(|d| d.ok())
+            .collect()
+    }
+
+    pub fn read_reports(&mut self) -> Vec<ReportSummary> {
+        let rows: Vec<(i64, MediaType, bool, ReportReason, i64)> = self.statement_cache
+                                                                        .read_reports
+                                                                        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+                                                                        .expect("Failed to read reports.")
+                                                                        .filter_map(|r| r.ok())
+                                                                        .collect();
+
+        let mut reports: Vec<ReportSummary> = Vec::new();
+
+        for (media_id, media_type, hidden, reason, count) in rows {
+            match reports.last_mut() {
+                Some(ref mut report) if report.media_id == media_id => report.reasons.push((reason, count)),
+                _ => reports.push(ReportSummary { media_id, media_type, hidden, reasons: vec![(reason, count)] })
+            }
+        }
+
+        reports
+    }
+
+    // Marks the reports of a media handled and shows it again, false when it had none.
+    pub fn resolve_reports(&mut self, media_id: i64) -> bool {
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        let resolved = self.statement_cache
+                           .resolve_reports
+                           .execute(params![media_id])
+                           .expect("Failed to resolve reports.");
+
+        self.update_media_hidden(media_id, false);
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+
+        resolved > 0
+    }
+
+    // `shared_with` replaces the groups the media was shared with before.
+    pub fn update_media_visibility(&mut self, media_id: i64, visibility: Visibility, shared_with: &[i64]) {
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        self.statement_cache
+            .update_media_visibility
+            .execute(params![visibility, media_id])
+            .expect("Failed to update media visibility.");
+
+        self.statement_cache
+            .delete_media_shares
+            .execute(params![media_id])
+            .expect("Failed to delete media shares.");
+
+        for chat_id in shared_with {
+            self.statement_cache
+                .insert_media_share
+                .execute(params![media_id, chat_id])
+                .expect("Failed to insert media share.");
+        }
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+    }
+
+    pub fn read_media_with_query(&mut self, user_id: i64, query: String, limit: i64) -> Vec<Entity> {
+        let query = query + "%";
+
+        self.statement_cache
+            .read_media_with_query
+            .query_map(params![user_id, query, limit],
+                       |row| Ok(Entity::Media {
+                           id: row.get(0)?,
+                           file_id: row.get(1)?,
+                           media_type: row.get(2)?,
+                       }))
+            .expect("Failed to read media with query.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    // The tags of every media read_media_with_query could return to `user_id`.
+    pub fn read_visible_tags(&mut self, user_id: i64) -> Vec<String> {
+        self.statement_cache
+            .read_visible_tags
+            .query_map(params![user_id], |row| row.get(0))
+            .expect("Failed to read visible tags.")
+            .filter_map(|t| t.ok())
+            .collect()
+    }
+
+    pub fn read_media_without_archive(&mut self) -> Vec<Entity> {
+        self.statement_cache
+            .read_media_without_archive
+            .query_map([],
+                       |row| Ok(Entity::Media {
+                           id: row.get(0)?,
+                           file_id: row.get(1)?,
+                           media_type: row.get(2)?,
+                       }))
+            .expect("Failed to read media without archive.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    pub fn read_all_media(&mut self) -> Vec<StoredMedia> {
+        let mut media: Vec<StoredMedia> = self.statement_cache
+                                              .read_all_media
+                                              .query_map([], stored_media)
+                                              .expect("Failed to read all media.")
+                                              .filter_map(|r| r.ok())
+                                              .collect();
+
+        for m in &mut media {
+            m.shared_with = self.read_media_shares(m.media_id);
+        }
+
+        media
+    }
+
+    pub fn read_media_shares(&mut self, media_id: i64) -> Vec<i64> {
+        self.statement_cache
+            .read_media_shares
+            .query_map(params![media_id], |row| row.get(0))
+            .expect("Failed to read media shares.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    pub fn read_tags_with_mediaid(&mut self, media_id: i64) -> Vec<Entity> {
+        self.statement_cache
+            .read_tags_with_mediaid
+            .query_map(params![media_id],
+                       |row| Ok(Entity::Tag {
+                           media_id: row.get(0)?,
+                           tag: row.get(1)?,
+                           counter: row.get(2)?,
+                       }))
+            .expect("Failed to read tags with media_id.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    pub fn merge_tag_counter(&mut self, media_id: i64, tag: &str, counter: i64) {
+        self.statement_cache
+            .merge_tag_counter
+            .execute(params![counter, media_id, tag.to_lowercase()])
+            .expect("Failed to merge tag counter.");
+    }
+
+    pub fn read_stored_media(&mut self, media_id: i64) -> Option<StoredMedia> {
+        let mut media = self.statement_cache
+                            .read_stored_media
+                            .query_map(params![media_id], stored_media)
+                            .expect("Failed to read stored media.")
+                            .filter_map(|r| r.ok())
+                            .next()?;
+
+        media.shared_with = self.read_media_shares(media_id);
+
+        Some(media)
+    }
+
+    pub fn read_tag_summaries(&mut self) -> Vec<TagSummary> {
+        self.statement_cache
+            .read_tag_summaries
+            .query_map([],
+                       |row| Ok(TagSummary {
+                           tag: row.get(0)?,
+                           media: row.get(1)?,
+                           counter: row.get(2)?,
+                       }))
+            .expect("Failed to read tag summaries.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    // Errors instead of panicking, a failed scrape must not take the metrics endpoint down.
+    pub fn read_library_stats(&mut self) -> Result<LibraryStats, Error> {
+        let schema_version = self.statement_cache
+                                 .read_user_version
+                                 .query_row([], |row| row.get(0))?;
+
+        self.statement_cache
+            .read_library_stats
+            .query_row([],
+                       |row| Ok(LibraryStats {
+                           schema_version,
+                           media: row.get(0)?,
+                           photos: row.get(1)?,
+                           mpeg4_gifs: row.get(2)?,
+                           gifs: row.get(3)?,
+                           archived: row.get(4)?,
+                           tags: row.get(5)?,
+                           uses: row.get(6)?,
+                       }))
+    }
+
+    pub fn schema_version(&mut self) -> i64 {
+        self.statement_cache
+            .read_user_version
+            .query_row([], |row| row.get(0))
+            .expect("Failed to read schema version.")
+    }
+
+    // Errors instead of panicking, the health check has to answer even when the database doesn't.
+    pub fn is_reachable(&mut self) -> bool {
+        self.statement_cache
+            .read_user_version
+            .query_row([], |row| row.get::<_, i64>(0))
+            .is_ok()
+    }
+
+    // Fails when the new name is already in use, merging is then the right tool.
+    pub fn rename_tag(&mut self, from: &str, to: &str) -> Result<i32, String> {
+        let (from, to) = (from.to_lowercase(), to.to_lowercase());
+
+        let existing: i64 = self.statement_cache
+                                .count_tag
+                                .query_row(params![to], |row| row.get(0))
+                                .expect("Failed to count tag.");
+
+        if existing > 0 {
+            return Err(format!("Tag {} already exists", to));
+        }
+
+        Ok(self.statement_cache
+               .rename_tag
+               .execute(params![from, to])
+               .expect("Failed to rename tag.") as i32)
+    }
+
+    // Moves every `from` tag onto `into`, summing the counters where a media already has both.
+    pub fn merge_tag(&mut self, from: &str, into: &str) -> i32 {
+        let (from, into) = (from.to_lowercase(), into.to_lowercase());
+
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        self.statement_cache
+            .merge_tag_counters
+            .execute(params![from, into])
+            .expect("Failed to merge tag counters.");
+
+        let merged = self.statement_cache
+                         .delete_merged_tags
+                         .execute(params![from, into])
+                         .expect("Failed to delete merged tags.");
+
+        let renamed = self.statement_cache
+                          .rename_tag
+                          .execute(params![from, into])
+                          .expect("Failed to rename tag.");
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+
+        (merged + renamed) as i32
+    }
+
+    pub fn delete_media(&mut self, media_id: i64) -> bool {
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        self.statement_cache
+            .delete_tags_with_mediaid
+            .execute(params![media_id])
+            .expect("Failed to delete tags with media_id.");
+
+        self.statement_cache
+            .delete_media_shares
+            .execute(params![media_id])
+            .expect("Failed to delete media shares.");
+
+        self.statement_cache
+            .delete_media_reports
+            .execute(params![media_id])
+            .expect("Failed to delete media reports.");
+
+        let deleted = self.statement_cache
+                          .delete_media
+                          .execute(params![media_id])
+                          .expect("Failed to delete media.");
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+
+        deleted > 0
+    }
+
+    // Ends a transaction that a panicking handler left open, so the connection can be used again.
+    pub fn rollback(&mut self) {
+        // Fails when no transaction is open, which is the usual case.
+        let _ = self.statement_cache.transaction_rollback.execute([]);
+    }
+
+    pub fn vacuum(&mut self) {
+        self.statement_cache
+            .vacuum
+            .execute([])
+            .expect("Failed to vacuum database.");
+    }
+
+    pub fn read_archived_media(&mut self) -> Vec<ArchivedMedia> {
+        self.statement_cache
+            .read_archived_media
+            .query_map([],
+                       |row| Ok(ArchivedMedia {
+                           media_id: row.get(0)?,
+                           media_type: row.get(1)?,
+                           archive_path: row.get(2)?,
+                           archive_hash: row.get(3)?,
+                       }))
+            .expect("Failed to read archived media.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    pub fn update_media_archive(&mut self, media_id: i64, archive_path: &str, archive_hash: &str) {
+        self.statement_cache
+            .update_media_archive
+            .execute(params![archive_path, archive_hash, media_id])
+            .expect("Failed to update media archive.");
+    }
+
+    // Hides the media from results until it gets a working file_id, false when it was already marked.
+    pub fn mark_media_broken(&mut self, media_id: i64) -> bool {
+        self.statement_cache
+            .update_media_broken
+            .execute(params![media_id])
+            .expect("Failed to mark media broken.") > 0
+    }
+
+    pub fn read_broken_media(&mut self) -> Vec<Entity> {
+        self.statement_cache
+            .read_broken_media
+            .query_map([],
+                       |row| Ok(Entity::Media {
+                           id: row.get(0)?,
+                           file_id: row.get(1)?,
+                           media_type: row.get(2)?,
+                       }))
+            .expect("Failed to read broken media.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    // Also clears the broken mark, the new file_id is assumed to work.
+    pub fn update_media_file_id(&mut self, media_id: i64, file_id: &str) {
+        self.statement_cache
+            .update_media_fileid
+            .execute(params![file_id, media_id])
+            .expect("Failed to update media file_id.");
+    }
+
+    pub fn read_chat_settings(&mut self, chat_id: i64) -> ChatSettings {
+        self.statement_cache
+            .read_chat_setting
+            .query_map(params![chat_id], |row| Ok(ChatSettings { shared_library: row.get(0)?, safe_search: row.get(1)? }))
+            .expect("Failed to read chat settings.")
+            .filter_map(|r| r.ok())
+            .next()
+            .unwrap_or_default()
+    }
+
+    pub fn update_chat_shared_library(&mut self, chat_id: i64, shared_library: bool) {
+        self.statement_cache
+            .insert_chat_setting
+            .execute(params![chat_id])
+            .expect("Failed to insert chat settings.");
+
+        self.statement_cache
+            .update_chat_shared_library
+            .execute(params![shared_library, chat_id])
+            .expect("Failed to update chat shared library.");
+    }
+
+    pub fn read_user_role(&mut self, user_id: i64) -> Option<Role> {
+        self.statement_cache
+            .read_user_role
+            .query_map(params![user_id], |row| row.get(0))
+            .expect("Failed to read user role.")
+            .filter_map(|r| r.ok())
+            .next()
+    }
+
+    pub fn read_users_with_role(&mut self, role: Role) -> Vec<i64> {
+        self.statement_cache
+            .read_users_with_role
+            .query_map(params![role], |row| row.get(0))
+            .expect("Failed to read users with role.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    pub fn update_user_role(&mut self, user_id: i64, role: Role) {
+        self.statement_cache
+            .update_user_role
+            .execute(params![user_id, role])
+            .expect("Failed to update user role.");
+    }
+
+    // The user falls back to the configured default role, false when no role was stored.
+    pub fn delete_user_role(&mut self, user_id: i64) -> bool {
+        self.statement_cache
+            .delete_user_role
+            .execute(params![user_id])
+            .expect("Failed to delete user role.") > 0
+    }
+
+    pub fn read_user_groups(&mut self, user_id: i64) -> Vec<i64> {
+        self.statement_cache
+            .read_user_groups
+            .query_map(params![user_id], |row| row.get(0))
+            .expect("Failed to read user groups.")
+            .filter_map(|r| r.ok())
+            .collect()
+    }
+
+    pub fn update_chat_safe_search(&mut self, chat_id: i64, safe_search: bool) {
+        self.statement_cache
+            .insert_chat_setting
+            .execute(params![chat_id])
+            .expect("Failed to insert chat settings.");
+
+        self.statement_cache
+            .update_chat_safe_search
+            .execute(params![safe_search, chat_id])
+            .expect("Failed to update chat safe search.");
+    }
+
+    // Members are learned from their messages in the group, they can search its library from then on.
+    pub fn insert_chat_member(&mut self, chat_id: i64, user_id: i64) {
+        self.statement_cache
+            .insert_chat_member
+            .execute(params![chat_id, user_id])
+            .expect("Failed to insert chat member.");
+    }
+
+    pub fn delete_chat_member(&mut self, chat_id: i64, user_id: i64) {
+        self.statement_cache
+            .delete_chat_member
+            .execute(params![chat_id, user_id])
+            .expect("Failed to delete chat member.");
+    }
+
+    // The getUpdates offset to resume from, every update before it has been handled.
+    pub fn read_update_offset(&mut self) -> Option<i64> {
+        self.statement_cache
+            .read_state
+            .query_map(params![STATE_UPDATE_OFFSET], |row| row.get(0))
+            .expect("Failed to read update offset.")
+            .filter_map(|r| r.ok())
+            .next()
+    }
+
+    // Never moves the offset back, updates before it are not delivered again so their records can go.
+    pub fn update_update_offset(&mut self, offset: i64) {
+        self.statement_cache
+            .advance_state
+            .execute(params![STATE_UPDATE_OFFSET, offset])
+            .expect("Failed to update update offset.");
+
+        self.statement_cache
+            .delete_handled_updates
+            .execute(params![offset])
+            .expect("Failed to delete handled updates.");
+    }
+
+    pub fn is_update_handled(&mut self, update_id: i64) -> bool {
+        self.statement_cache
+            .read_handled_update
+            .query_map(params![update_id], |row| row.get::<_, i64>(0))
+            .expect("Failed to read handled update.")
+            .filter_map(|r| r.ok())
+            .next()
+            .is_some()
+    }
+
+    // Returns false if the update was handled already.
+    pub fn mark_update_handled(&mut self, update_id: i64) -> bool {
+        self.statement_cache
+            .insert_handled_update
+            .execute(params![update_id])
+            .expect("Failed to mark update handled.") > 0
+    }
+
+    // Counts each update once, however often it is delivered.
+    pub fn increase_tag_counter(&mut self, update_id: i64, media_id: i64, query: String) {
+        let query = query + "%";
+
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        if self.mark_update_handled(update_id) {
+            self.statement_cache
+                .increase_tag_counter
+                .execute(params![media_id, query])
+                .expect("Failed to update tag counter.");
+        }
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+    }
+
+    // Media that is already stored keeps its owner, visibility and approval.
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert_media(&mut self, file_id: String, media_type: MediaType, owner_id: i64, visibility: Visibility, shared_with: &[i64], nsfw: bool, pending: bool) -> i64 {
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        let media_id = match self.read_media_id(&file_id, &media_type) {
+            Some(media_id) => media_id,
+            None => {
+                let media_id = self.insert_media_row(file_id, media_type, Some(owner_id), visibility, nsfw, pending);
+                self.insert_usage_event(owner_id, UsageEvent::Upload { media_id });
+
+                for chat_id in shared_with {
+                    self.statement_cache
+                        .insert_media_share
+                        .execute(params![media_id, chat_id])
+                        .expect("Failed to insert media share.");
+                }
+
+                media_id
+            }
+        };
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+
+        media_id
+    }
+
+    // Restores media from a bundle with its owner, visibility and flags. Media that is already stored keeps its own.
+    #[allow(clippy::too_many_arguments)]
+    pub fn restore_media(&mut self, file_id: String, media_type: MediaType, owner_id: Option<i64>, visibility: Visibility, shared_with: &[i64], nsfw: bool, pending: bool, hidden: bool) -> i64 {
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        let media_id = match self.read_media_id(&file_id, &media_type) {
+            Some(media_id) => media_id,
+            None => {
+                let media_id = self.insert_media_row(file_id, media_type, owner_id, visibility, nsfw, pending);
+
+                for chat_id in shared_with {
+                    self.statement_cache
+                        .insert_media_share
+                        .execute(params![media_id, chat_id])
+                        .expect("Failed to insert media share.");
+                }
+
+                if hidden {
+                    self.update_media_hidden(media_id, true);
+                }
+
+                media_id
+            }
+        };
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+
+        media_id
+    }
+
+    // Inserts public media without an owner, use insert_media for an uploader's media.
+    pub fn insert(&mut self, entity: Entity) -> i64 {
+        self.statement_cache
+            .transaction_begin
+            .execute([])
+            .expect("Failed to begin transaction.");
+
+        let media_id = match entity {
+            Entity::Media { file_id, media_type, .. } => match self.read_media_id(&file_id, &media_type) {
+                Some(media_id) => media_id,
+                None => self.insert_media_row(file_id, media_type, None, Visibility::Public, false, false)
+            },
+            Entity::Tag { media_id, tag, .. } => {
+                let tag = tag.to_lowercase();
+
+                if let Some(media_id) = self.statement_cache
+                                            .read_tag
+                                            .query_row(params![media_id, tag], |row| row.get(0))
+                                            .optional()
+                                            .expect("Failed to run read_tag statement.") {
+                    media_id
+                } else {
+                    info!(tag:% = tag; "Inserting tag to media_id {}", media_id);
+
+                    self.statement_cache
+                        .insert_tag
+                        .insert(params![media_id, tag])
+                        .expect("Failed to run insert_tag statement.")
+                }
+            }
+        };
+
+        self.statement_cache
+            .transaction_end
+            .execute([])
+            .expect("Failed to end transaction.");
+
+        media_id
+    }
+
+    fn read_media_id(&mut self, file_id: &str, media_type: &MediaType) -> Option<i64> {
+        self.statement_cache
+            .read_media_with_fileid_and_type
+            .query_row(params![file_id, media_type], |row| row.get(0))
+            .optional()
+            .expect("Failed to run read_media statement.")
+    }
+
+    fn insert_media_row(&mut self, file_id: String, media_type: MediaType, owner_id: Option<i64>, visibility: Visibility, nsfw: bool, pending: bool) -> i64 {
+        info!(file_id:% = file_id, visibility:% = visibility; "Inserting media with media_type {:?}", media_type);
+
+        self.statement_cache
+            .insert_media
+            .insert(params![file_id, media_type, owner_id, visibility, nsfw, pending])
+            .expect("Failed to run insert_media statement.")
+    }
+}
+
+// Shares are read separately.
+fn stored_media(row: &rusqlite::Row) -> rusqlite::Result<StoredMedia> {
+    Ok(StoredMedia {
+        media_id: row.get(0)?,
+        file_id: row.get(1)?,
+        media_type: row.get(2)?,
+        archive_path: row.get(3)?,
+        archive_hash: row.get(4)?,
+        owner_id: row.get(5)?,
+        visibility: row.get(6)?,
+        shared_with: Vec::new(),
+        nsfw: row.get(7)?,
+        pending: row.get(8)?,
+        hidden: row.get(9)?,
+    })
+}
+
+fn migrate(conn: &rusqlite::Connection) {
+    let version: i64 = conn.query_row(SQL_READ_USER_VERSION, [], |row| row.get(0))
+                           .expect("Failed to read schema version.");
+
+    for (i, migration) in SQL_MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Migrating database schema to version {}", i + 1);
+
+        conn.execute_batch(&format!("BEGIN TRANSACTION; {} PRAGMA user_version = {}; END TRANSACTION;", migration, i + 1))
+            .expect("Failed to migrate database schema.");
+    }
+}
+
+// Times every statement for the metrics endpoint, by the name of the StatementCache field that runs it.
+fn profile_statement(sql: &str, duration: Duration) {
+    if metrics::is_enabled() {
+        metrics::observe_duration(metrics::DB_STATEMENT_DURATION, &[("statement", statement_name(sql))], duration);
+    }
+}
+
+fn statement_name(sql: &str) -> &'static str {
+    let statements = [
+                  ("insert_media", SQL_INSERT_MEDIA),
+                  ("insert_tag", SQL_INSERT_TAG),
+                  ("read_media_with_mediaid", SQL_READ_MEDIA_WITH_MEDIAID),
+                  ("read_media_with_fileid_and_type", SQL_READ_MEDIA_WITH_FILEID_AND_TYPE),
+                  ("read_media", SQL_READ_MEDIA),
+                  ("read_media_with_query", SQL_READ_MEDIA_WITH_USER_AND_QUERY),
+                  ("read_media_without_archive", SQL_READ_MEDIA_WITHOUT_ARCHIVE),
+                  ("read_archived_media", SQL_READ_ARCHIVED_MEDIA),
+                  ("update_media_archive", SQL_UPDATE_MEDIA_ARCHIVE),
+                  ("update_media_fileid", SQL_UPDATE_MEDIA_FILEID),
+                  ("read_tag", SQL_READ_TAG),
+                  ("increase_tag_counter", SQL_INCREASE_TAG_COUNTER),
+                  ("read_all_media", SQL_READ_ALL_MEDIA),
+                  ("read_tags_with_mediaid", SQL_READ_TAGS_WITH_MEDIAID),
+                  ("merge_tag_counter", SQL_MERGE_TAG_COUNTER),
+                  ("read_stored_media", SQL_READ_STORED_MEDIA),
+                  ("read_tag_summaries", SQL_READ_TAG_SUMMARIES),
+                  ("count_tag", SQL_COUNT_TAG),
+                  ("merge_tag_counters", SQL_MERGE_TAG_COUNTERS),
+                  ("delete_merged_tags", SQL_DELETE_MERGED_TAGS),
+                  ("rename_tag", SQL_RENAME_TAG),
+                  ("delete_tags_with_mediaid", SQL_DELETE_TAGS_WITH_MEDIAID),
+                  ("delete_media", SQL_DELETE_MEDIA),
+                  ("read_library_stats", SQL_READ_LIBRARY_STATS),
+                  ("vacuum", SQL_VACUUM),
+                  ("read_user_version", SQL_READ_USER_VERSION),
+                  ("read_state", SQL_READ_STATE),
+                  ("advance_state", SQL_ADVANCE_STATE),
+                  ("insert_handled_update", SQL_INSERT_HANDLED_UPDATE),
+                  ("read_handled_update", SQL_READ_HANDLED_UPDATE),
+                  ("delete_handled_updates", SQL_DELETE_HANDLED_UPDATES),
+                  ("update_media_broken", SQL_UPDATE_MEDIA_BROKEN),
+                  ("read_broken_media", SQL_READ_BROKEN_MEDIA),
+                  ("insert_chat_member", SQL_INSERT_CHAT_MEMBER),
+                  ("insert_chat_setting", SQL_INSERT_CHAT_SETTING),
+                  ("read_chat_setting", SQL_READ_CHAT_SETTING),
+                  ("update_chat_shared_library", SQL_UPDATE_CHAT_SHARED_LIBRARY),
+                  ("delete_chat_member", SQL_DELETE_CHAT_MEMBER),
+                  ("read_user_role", SQL_READ_USER_ROLE),
+                  ("read_users_with_role", SQL_READ_USERS_WITH_ROLE),
+                  ("update_user_role", SQL_UPDATE_USER_ROLE),
+                  ("delete_user_role", SQL_DELETE_USER_ROLE),
+                  ("insert_media_share", SQL_INSERT_MEDIA_SHARE),
+                  ("read_media_owner", SQL_READ_MEDIA_OWNER),
+                  ("read_user_groups", SQL_READ_USER_GROUPS),
+                  ("update_media_visibility", SQL_UPDATE_MEDIA_VISIBILITY),
+                  ("delete_media_shares", SQL_DELETE_MEDIA_SHARES),
+                  ("update_chat_safe_search", SQL_UPDATE_CHAT_SAFE_SEARCH),
+                  ("update_media_nsfw", SQL_UPDATE_MEDIA_NSFW),
+                  ("read_media_nsfw", SQL_READ_MEDIA_NSFW),
+                  ("read_media_pending", SQL_READ_MEDIA_PENDING),
+                  ("approve_media", SQL_APPROVE_MEDIA),
+                  ("insert_report", SQL_INSERT_REPORT),
+                  ("count_reports", SQL_COUNT_REPORTS),
+                  ("read_reports", SQL_READ_REPORTS),
+                  ("hide_media", SQL_HIDE_MEDIA),
+                  ("resolve_reports", SQL_RESOLVE_REPORTS),
+                  ("delete_media_reports", SQL_DELETE_MEDIA_REPORTS),
+                  ("transaction_begin", SQL_TRANSACTION_BEGIN),
+                  ("transaction_end", SQL_TRANSACTION_END),
+                  ("insert_usage_event", SQL_INSERT_USAGE_EVENT),
+                  ("read_top_media", SQL_READ_TOP_MEDIA),
+                  ("read_top_tags", SQL_READ_TOP_TAGS),
+                  ("read_missed_queries", SQL_READ_MISSED_QUERIES),
+                  ("read_active_users", SQL_READ_ACTIVE_USERS),
+                  ("read_daily_activity", SQL_READ_DAILY_ACTIVITY),
+                  ("read_visible_tags", SQL_READ_VISIBLE_TAGS),
+                  ("transaction_rollback", SQL_TRANSACTION_ROLLBACK),
+                  ("read_media_shares", SQL_READ_MEDIA_SHARES),
+                  ("delete_pending_media", SQL_DELETE_PENDING_MEDIA),
+                  ("read_media_visible", SQL_READ_MEDIA_VISIBLE),
+                  ("update_media_hidden", SQL_UPDATE_MEDIA_HIDDEN),
+    ];
+
+    statements.iter().find(|&&(_, s)| s == sql).map_or("other", |&(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static OWNER: i64 = 1;
+    static MEMBER: i64 = 2;
+    static STRANGER: i64 = 3;
+    static GROUP: i64 = -100;
+
+    struct Library {
+        public: i64,
+        private: i64,
+        shared: i64,
+    }
+
+    // One media of each visibility, all owned by OWNER and tagged "cat", the shared one with GROUP where MEMBER is.
+    fn library(db: &mut DB) -> Library {
+        let mut add = |file_id: &str, visibility: Visibility, shared_with: &[i64], tag: &str| {
+            let media_id = db.insert_media(file_id.to_string(), MediaType::Photo, OWNER, visibility, shared_with, false, false);
+            db.insert(Entity::Tag { media_id, tag: "cat".to_string(), counter: 0 });
+            db.insert(Entity::Tag { media_id, tag: tag.to_string(), counter: 0 });
+            media_id
+        };
+
+        let library = Library {
+            public: add("public", Visibility::Public, &[], "everyone"),
+            private: add("private", Visibility::Private, &[], "secret"),
+            shared: add("shared", Visibility::Groups, &[GROUP], "team"),
+        };
+
+        db.insert_chat_member(GROUP, MEMBER);
+
+        library
+    }
+
+    fn ids(media: Vec<Entity>) -> Vec<i64> {
+        let mut ids: Vec<i64> = media.into_iter()
+                                     .filter_map(|m| match m {
+                                         Entity::Media { id, .. } => Some(id),
+                                         _ => None
+                                     })
+                                     .collect();
+        ids.sort();
+        ids
+    }
+
+    fn by_id(db: &mut DB, user_id: i64, media: &[i64]) -> Vec<i64> {
+        media.iter().cloned().filter(|&media_id| db.read_media_with_mediaid(user_id, media_id).is_some()).collect()
+    }
+
+    fn tags(db: &mut DB, user_id: i64) -> Vec<String> {
+        let mut tags = db.read_visible_tags(user_id);
+        tags.sort();
+        tags
+    }
+
+    #[test]
+    fn reads_only_return_media_the_user_may_see() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let l = library(&mut db);
+        let all = [l.public, l.private, l.shared];
+
+        let expected = [
+            (OWNER, vec![l.public, l.private, l.shared], vec!["cat", "everyone", "secret", "team"]),
+            (MEMBER, vec![l.public, l.shared], vec!["cat", "everyone", "team"]),
+            (STRANGER, vec![l.public], vec!["cat", "everyone"]),
+        ];
+
+        for &(user_id, ref media, ref visible_tags) in &expected {
+            assert_eq!(&ids(db.read_media(user_id, 10)), media, "read_media for {}", user_id);
+            assert_eq!(&ids(db.read_media_with_query(user_id, "cat".to_string(), 10)), media, "read_media_with_query for {}", user_id);
+            assert_eq!(&by_id(&mut db, user_id, &all), media, "read_media_with_mediaid for {}", user_id);
+            assert_eq!(tags(&mut db, user_id), *visible_tags, "read_visible_tags for {}", user_id);
+        }
+    }
+
+    #[test]
+    fn tag_queries_do_not_reveal_hidden_media() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let l = library(&mut db);
+
+        assert!(ids(db.read_media_with_query(STRANGER, "secret".to_string(), 10)).is_empty());
+        assert!(ids(db.read_media_with_query(STRANGER, "team".to_string(), 10)).is_empty());
+        assert_eq!(ids(db.read_media_with_query(MEMBER, "team".to_string(), 10)), vec![l.shared]);
+        assert!(ids(db.read_media_with_query(MEMBER, "secret".to_string(), 10)).is_empty());
+    }
+
+    #[test]
+    fn leaving_the_group_hides_its_media() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let l = library(&mut db);
+
+        db.delete_chat_member(GROUP, MEMBER);
+
+        assert_eq!(ids(db.read_media(MEMBER, 10)), vec![l.public]);
+        assert!(db.read_media_with_mediaid(MEMBER, l.shared).is_none());
+        assert_eq!(tags(&mut db, MEMBER), vec!["cat", "everyone"]);
+    }
+
+    #[test]
+    fn making_media_private_hides_it_from_the_group() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let l = library(&mut db);
+
+        db.update_media_visibility(l.shared, Visibility::Private, &[]);
+
+        assert_eq!(ids(db.read_media(MEMBER, 10)), vec![l.public]);
+        assert_eq!(ids(db.read_media(OWNER, 10)), vec![l.public, l.private, l.shared]);
+    }
+
+    #[test]
+    fn reports_need_visible_media_and_hide_it_once() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let l = library(&mut db);
+
+        assert!(db.is_media_visible(MEMBER, l.shared));
+        assert!(!db.is_media_visible(STRANGER, l.shared));
+        assert!(!db.is_media_visible(MEMBER, l.private));
+
+        assert!(db.hide_media(l.public));
+        assert!(!db.hide_media(l.public));
+        assert!(db.is_media_visible(STRANGER, l.public));
+    }
+
+    #[test]
+    fn safe_search_hides_nsfw_media_and_tags_until_turned_off() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let l = library(&mut db);
+        let nsfw = db.insert_media("nsfw".to_string(), MediaType::Photo, OWNER, Visibility::Public, &[], true, false);
+        db.insert(Entity::Tag { media_id: nsfw, tag: "cat".to_string(), counter: 0 });
+        db.insert(Entity::Tag { media_id: nsfw, tag: "spicy".to_string(), counter: 0 });
+
+        // STRANGER never changed a setting, so there is no chat_setting row for them.
+        assert!(db.read_chat_settings(STRANGER).safe_search);
+        assert_eq!(ids(db.read_media(STRANGER, 10)), vec![l.public]);
+        assert_eq!(ids(db.read_media_with_query(STRANGER, "cat".to_string(), 10)), vec![l.public]);
+        assert!(db.read_media_with_query(STRANGER, "spicy".to_string(), 10).is_empty());
+        assert_eq!(tags(&mut db, STRANGER), vec!["cat", "everyone"]);
+
+        db.update_chat_safe_search(STRANGER, false);
+
+        assert_eq!(ids(db.read_media(STRANGER, 10)), vec![l.public, nsfw]);
+        assert_eq!(ids(db.read_media_with_query(STRANGER, "spicy".to_string(), 10)), vec![nsfw]);
+        assert_eq!(tags(&mut db, STRANGER), vec!["cat", "everyone", "spicy"]);
+        assert_eq!(ids(db.read_media(MEMBER, 10)), vec![l.public, l.shared]);
+    }
+
+    #[test]
+    fn media_can_be_reported_again_after_its_reports_are_resolved() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let l = library(&mut db);
+
+        assert_eq!(db.insert_report(l.public, MEMBER, ReportReason::Spam), Some(1));
+        assert_eq!(db.insert_report(l.public, MEMBER, ReportReason::Offensive), None);
+        assert_eq!(db.insert_report(l.public, STRANGER, ReportReason::Spam), Some(2));
+
+        assert!(db.resolve_reports(l.public));
+        assert!(!db.resolve_reports(l.public));
+        assert!(db.read_reports().is_empty());
+
+        assert_eq!(db.insert_report(l.public, MEMBER, ReportReason::Broken), Some(1));
+        assert_eq!(db.insert_report(l.public, MEMBER, ReportReason::Broken), None);
+        assert_eq!(db.read_reports().len(), 1);
+    }
+
+    #[test]
+    fn only_the_first_moderation_decision_counts() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let approved = db.insert_media("approved".to_string(), MediaType::Photo, OWNER, Visibility::Public, &[], false, true);
+        let rejected = db.insert_media("rejected".to_string(), MediaType::Photo, OWNER, Visibility::Public, &[], false, true);
+        db.insert(Entity::Tag { media_id: approved, tag: "cat".to_string(), counter: 0 });
+        db.insert(Entity::Tag { media_id: rejected, tag: "dog".to_string(), counter: 0 });
+
+        assert!(db.approve_media(approved));
+        assert!(!db.approve_media(approved));
+        assert!(!db.reject_media(approved));
+        assert_eq!(ids(db.read_media(STRANGER, 10)), vec![approved]);
+        assert_eq!(db.read_tags_with_mediaid(approved).len(), 1);
+
+        assert!(db.reject_media(rejected));
+        assert!(!db.reject_media(rejected));
+        assert!(!db.approve_media(rejected));
+        assert_eq!(db.is_media_pending(rejected), None);
+    }
+
+    #[test]
+    fn media_shared_before_visibility_stays_shared_with_its_chat() {
+        let conn = Connection::new(":memory:".to_string());
+        conn.sqlite_conn
+            .execute_batch(&format!("{} {} {} {} {} PRAGMA user_version = 3; INSERT INTO media (file_id, media_type, library_id) VALUES ('shared', 0, {});",
+                                    SQL_CREATE_TABLE_MEDIA, SQL_CREATE_TABLE_MEDIA_SHARE, SQL_MIGRATIONS[0], SQL_MIGRATIONS[1], SQL_MIGRATIONS[2], GROUP))
+            .expect("Failed to create a version 3 database.");
+
+        let mut db = DB::new(&conn);
+        db.insert_chat_member(GROUP, MEMBER);
+
+        assert_eq!(db.schema_version(), SQL_MIGRATIONS.len() as i64);
+        assert_eq!(by_id(&mut db, MEMBER, &[1]), vec![1]);
+        assert!(by_id(&mut db, STRANGER, &[1]).is_empty());
+
+        let columns: Vec<String> = conn.sqlite_conn
+                                       .prepare("SELECT name FROM pragma_table_info('media');")
+                                       .expect("Failed to read media columns.")
+                                       .query_map([], |row| row.get(0))
+                                       .expect("Failed to read media columns.")
+                                       .filter_map(|c| c.ok())
+                                       .collect();
+        assert!(!columns.contains(&"library_id".to_string()));
+    }
+
+    // Midnight UTC of 2023-11-14.
+    static DAY: i64 = 1_699_920_000;
+
+    // Moves the last recorded usage event to `time`.
+    fn recorded_at(conn: &Connection, time: i64) {
+        conn.sqlite_conn
+            .execute("UPDATE usage_event SET created_at = ? WHERE event_id = (SELECT MAX(event_id) FROM usage_event);", params![time])
+            .expect("Failed to move usage event.");
+    }
+
+    #[test]
+    fn usage_stats_count_the_events_since_the_given_time() {
+        let conn = Connection::new(":memory:".to_string());
+        let mut db = DB::new(&conn);
+        let (yesterday, today, tomorrow) = (DAY - 86_400 + 60, DAY + 60, DAY + 86_400 + 60);
+
+        db.insert_usage_event(4, UsageEvent::Query { query: "old", results: 0 });
+        recorded_at(&conn, yesterday);
+
+        let media_id = db.insert_media("cat".to_string(), MediaType::Photo, 1, Visibility::Public, &[], false, false);
+        recorded_at(&conn, today);
+        db.insert(Entity::Tag { media_id, tag: "cat".to_string(), counter: 0 });
+        db.insert(Entity::Tag { media_id, tag: "cute".to_string(), counter: 0 });
+        db.insert_usage_event(2, UsageEvent::Query { query: "ca", results: 1 });
+        recorded_at(&conn, today);
+        db.insert_usage_event(2, UsageEvent::Send { media_id, query: "ca" });
+        recorded_at(&conn, today);
+
+        for &(user_id, query, results) in &[(3, "dog", 0), (3, "dog", 0), (2, "dog", 0), (4, "c", 1)] {
+            db.insert_usage_event(user_id, UsageEvent::Query { query, results });
+            recorded_at(&conn, tomorrow);
+        }
+        // Wildcards in a query are not wildcards for the tags it matched.
+        for &(user_id, query) in &[(3, "c"), (2, "%"), (2, "c_t")] {
+            db.insert_usage_event(user_id, UsageEvent::Send { media_id, query });
+            recorded_at(&conn, tomorrow);
+        }
+
+        let top_media: Vec<(i64, i64)> = db.read_top_media(DAY, 10).into_iter().map(|m| (m.media_id, m.uses)).collect();
+        assert_eq!(top_media, vec![(media_id, 4)]);
+
+        let top_tags: Vec<(String, i64)> = db.read_top_tags(DAY, 10).into_iter().map(|t| (t.tag, t.uses)).collect();
+        assert_eq!(top_tags, vec![("cat".to_string(), 2), ("cute".to_string(), 1)]);
+
+        let missed: Vec<(String, i64, i64)> = db.read_missed_queries(DAY, 10).into_iter().map(|q| (q.query, q.misses, q.users)).collect();
+        assert_eq!(missed, vec![("dog".to_string(), 3, 2)]);
+
+        assert_eq!(db.read_active_users(DAY), 4);
+        assert_eq!(db.read_active_users(DAY + 86_400), 3);
+
+        // User 4 was first seen before the period, so it is active but not new on the second day.
+        let days: Vec<(String, i64, i64, i64, i64, i64)> = db.read_daily_activity(DAY)
+                                                             .into_iter()
+                                                             .map(|d| (d.day, d.uploads, d.queries, d.sends, d.active_users, d.new_users))
+                                                             .collect();
+        assert_eq!(days, vec![("2023-11-14".to_string(), 1, 1, 1, 2, 2),
+                              ("2023-11-15".to_string(), 0, 4, 3, 3, 1)]);
     }
 }
\ No newline at end of file
@@ -0,0 +1,171 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+// Updates for the same chat that depend on each other go through an ordered lane, the rest may run in parallel.
+pub enum Lane {
+    Ordered(i64),
+    Parallel,
+}
+
+// Spreads jobs over two bounded pools: ordered workers each own a queue so one chat's jobs run in sequence,
+// parallel workers share a single queue and pick up whatever comes next.
+pub struct Dispatcher<T> {
+    ordered: Vec<SyncSender<T>>,
+    parallel: SyncSender<T>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+pub enum Jobs<T> {
+    Own(Receiver<T>),
+    Shared(Arc<Mutex<Receiver<T>>>),
+}
+
+impl<T: Send + 'static> Dispatcher<T> {
+    // Every worker thread runs `worker` once with its job queue, so per-thread state such as a database
+    // connection lives inside `worker`. Dispatching blocks while the target queue holds `capacity` jobs.
+    pub fn new<F>(workers: usize, capacity: usize, worker: F) -> Dispatcher<T>
+        where F: Fn(Jobs<T>) + Send + Sync + 'static
+    {
+        let worker = Arc::new(worker);
+        let mut ordered = Vec::new();
+        let mut handles = Vec::new();
+
+        for i in 0..workers {
+            let (tx, rx) = mpsc::sync_channel(capacity);
+            ordered.push(tx);
+            handles.push(spawn_worker(format!("ordered-{}", i), worker.clone(), Jobs::Own(rx)));
+        }
+
+        let (parallel, rx) = mpsc::sync_channel(capacity);
+        let rx = Arc::new(Mutex::new(rx));
+
+        for i in 0..workers {
+            handles.push(spawn_worker(format!("parallel-{}", i), worker.clone(), Jobs::Shared(rx.clone())));
+        }
+
+        Dispatcher { ordered, parallel, workers: handles }
+    }
+
+    pub fn dispatch(&self, lane: Lane, job: T) {
+        let sent = match lane {
+            Lane::Ordered(key) => self.ordered[self.ordered_queue(key)].send(job),
+            Lane::Parallel => self.parallel.send(job)
+        };
+
+        if sent.is_err() {
+            error!("Dropped a job, its worker has stopped");
+        }
+    }
+
+    // Same keys always share a queue, different keys may too.
+    fn ordered_queue(&self, key: i64) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.ordered.len() as u64) as usize
+    }
+
+    // Stops accepting jobs and waits until the workers have finished everything already queued.
+    pub fn shutdown(self) {
+        drop(self.ordered);
+        drop(self.parallel);
+
+        for handle in self.workers {
+            if handle.join().is_err() {
+                error!("A worker panicked while finishing its queue");
+            }
+        }
+    }
+}
+
+impl<T> Iterator for Jobs<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match *self {
+            Jobs::Own(ref rx) => rx.recv().ok(),
+            Jobs::Shared(ref rx) => rx.lock().ok().and_then(|rx| rx.recv().ok())
+        }
+    }
+}
+
+fn spawn_worker<T, F>(name: String, worker: Arc<F>, jobs: Jobs<T>) -> JoinHandle<()>
+    where T: Send + 'static, F: Fn(Jobs<T>) + Send + Sync + 'static
+{
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || worker(jobs))
+        .expect("Failed to spawn worker thread.")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+    use super::*;
+
+    type Job = Box<dyn FnOnce() + Send>;
+
+    static WAIT_SEC: u64 = 5;
+
+    fn dispatcher(workers: usize) -> Dispatcher<Job> {
+        Dispatcher::new(workers, 100, |jobs: Jobs<Job>| for job in jobs { job() })
+    }
+
+    #[test]
+    fn jobs_with_the_same_key_run_in_order() {
+        let dispatcher = dispatcher(4);
+        let done = Arc::new(Mutex::new(Vec::new()));
+
+        // Earlier jobs take longer, they would finish last if they ran side by side.
+        for i in 0..20u64 {
+            let done = done.clone();
+            dispatcher.dispatch(Lane::Ordered(7), Box::new(move || {
+                thread::sleep(Duration::from_millis(20 - i));
+                done.lock().unwrap().push(i);
+            }));
+        }
+
+        dispatcher.shutdown();
+
+        assert_eq!(*done.lock().unwrap(), (0..20).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn jobs_with_different_keys_run_in_parallel() {
+        let dispatcher = dispatcher(4);
+        let key = 7;
+        let other = (key + 1..).find(|&k| dispatcher.ordered_queue(k) != dispatcher.ordered_queue(key)).unwrap();
+        let (signal, signaled) = mpsc::channel();
+        let (result, waited) = mpsc::channel();
+
+        // The first job only finishes once the second has run, which it can't while they share a worker.
+        dispatcher.dispatch(Lane::Ordered(key), Box::new(move || {
+            result.send(signaled.recv_timeout(Duration::from_secs(WAIT_SEC))).unwrap();
+        }));
+        dispatcher.dispatch(Lane::Ordered(other), Box::new(move || signal.send(()).unwrap()));
+
+        assert_eq!(waited.recv().unwrap(), Ok(()));
+
+        dispatcher.shutdown();
+    }
+
+    #[test]
+    fn a_waiting_key_holds_up_its_own_later_jobs() {
+        let dispatcher = dispatcher(4);
+        let (signal, signaled) = mpsc::channel();
+        let (result, waited) = mpsc::channel();
+
+        dispatcher.dispatch(Lane::Ordered(7), Box::new(move || {
+            result.send(signaled.recv_timeout(Duration::from_millis(200))).unwrap();
+        }));
+        dispatcher.dispatch(Lane::Ordered(7), Box::new(move || { let _ = signal.send(()); }));
+
+        assert_eq!(waited.recv().unwrap(), Err(RecvTimeoutError::Timeout));
+
+        dispatcher.shutdown();
+    }
+}
\ No newline at end of file
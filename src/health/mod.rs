@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// The main loop wakes up at least once a second, a minute without a tick means it is stuck.
+static LIVENESS_THRESHOLD_SEC: u64 = 60;
+
+pub static VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set MEHU_BUILD_COMMIT when building to report which commit is running.
+pub static COMMIT: Option<&'static str> = option_env!("MEHU_BUILD_COMMIT");
+
+// Seconds since the epoch, zero until the first time.
+static STARTED_AT: AtomicU64 = AtomicU64::new(0);
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+static LAST_POLL: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize)]
+pub struct Liveness {
+    pub alive: bool,
+    pub version: &'static str,
+    pub commit: Option<&'static str>,
+    pub uptime_sec: u64,
+    pub last_tick_sec_ago: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: bool,
+    pub last_poll_sec_ago: Option<u64>,
+}
+
+pub fn start() {
+    STARTED_AT.store(now(), Ordering::Relaxed);
+    tick();
+}
+
+// Called by the main loop on every turn.
+pub fn tick() {
+    LAST_TICK.store(now(), Ordering::Relaxed);
+}
+
+// Called after every getUpdates that reached Telegram.
+pub fn polled() {
+    LAST_POLL.store(now(), Ordering::Relaxed);
+}
+
+pub fn liveness() -> Liveness {
+    let last_tick_sec_ago = since(&LAST_TICK);
+    let started_at = STARTED_AT.load(Ordering::Relaxed);
+
+    Liveness {
+        alive: last_tick_sec_ago.is_some_and(|ago| ago <= LIVENESS_THRESHOLD_SEC),
+        version: VERSION,
+        commit: COMMIT,
+        uptime_sec: if started_at == 0 { 0 } else { now().saturating_sub(started_at) },
+        last_tick_sec_ago,
+    }
+}
+
+// Ready once the `database` answers and getUpdates succeeded within `threshold`.
+pub fn readiness(database: bool, threshold: Duration) -> Readiness {
+    let last_poll_sec_ago = since(&LAST_POLL);
+
+    Readiness {
+        ready: database && last_poll_sec_ago.is_some_and(|ago| ago <= threshold.as_secs()),
+        database,
+        last_poll_sec_ago,
+    }
+}
+
+fn since(timestamp: &AtomicU64) -> Option<u64> {
+    match timestamp.load(Ordering::Relaxed) {
+        0 => None,
+        t => Some(now().saturating_sub(t))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
\ No newline at end of file
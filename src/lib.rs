@@ -5,125 +5,1297 @@ extern crate serde_derive;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate signal_hook;
 
 mod telegram;
 mod data;
+mod archive;
+mod bundle;
+mod config;
+mod dispatcher;
+mod health;
+mod logging;
+mod metrics;
+mod stats;
+mod suggest;
+pub mod cli;
 
 use std::error::Error;
-use std::thread;
-use std::time::Duration;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::collections::HashMap;
-use telegram::{UpdateMessage, AnswerMessage, CallbackCommand};
-use data::{Entity, MediaType};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::telegram::{UpdateMessage, AnswerMessage, CallbackCommand};
+use crate::data::{Entity, MediaType, ReportReason, Role, UsageEvent, Visibility};
+use crate::dispatcher::{Dispatcher, Lane};
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+pub use config::Config;
+
+static RECEIVE_TIMEOUT_SEC: u64 = 1;
+static NSFW_FLAG: &str = "#nsfw";
+static MAX_FILE_CHECKS: usize = 5;
+
+// State shared by every worker, each worker keeps its own database connection.
+#[derive(Clone)]
+struct Context {
+    config: Arc<Config>,
+    api: telegram::blocking::Api,
+    archiver: Option<Arc<archive::Archiver>>,
+    // Media sent for tagging by (chat_id, message_id) of the message, message ids are only unique within a chat.
+    cache: Arc<Mutex<HashMap<(i64, i64), i64>>>,
+    progress: telegram::Progress,
+}
+
+// `file` is an explicit config file path and `flags` are (flag, value) overrides from the command line.
+pub fn configure(file: Option<&str>, flags: &[(String, String)]) -> Result<Config, Box<dyn Error>> {
+    Ok(Config::load(file, flags)?)
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    // Workers and the HTTP endpoint open their own connections, a shared cache would fail them with SQLITE_LOCKED.
+    if config.is_database_in_memory() {
+        return Err("The bot needs a database file, an in-memory database can't be shared between its connections.".into());
+    }
+
+    let connection = data::Connection::new(config.database_path.clone());
+    let mut db = data::DB::new(&connection);
+    let progress = telegram::Progress::new(db.read_update_offset());
+    let mut client = telegram::blocking::Client::new(&config.api_url, config.require_api_key()?, config.polling_timeout, progress.clone())?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    // A second signal while draining exits right away.
+    for signal in &[SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(*signal, 1, shutdown.clone())?;
+        signal_hook::flag::register(*signal, shutdown.clone())?;
+    }
+
+    let archiver = config.archive.as_ref().map(|c| Arc::new(archive::Archiver::from_config(c)));
+
+    logging::init(&config);
+
+    if let Some(ref address) = config.http_address {
+        metrics::serve(address, config.database_path.clone(), Duration::from_secs(config.ready_threshold))?;
+    }
+
+    // Backfilling a large library takes a while, it runs next to polling instead of holding up the main loop.
+    if let Some(ref archiver) = archiver {
+        let (api, archiver, shutdown) = (client.api().clone(), archiver.clone(), shutdown.clone());
+        let database_path = config.database_path.clone();
+
+        thread::spawn(move || {
+            let connection = data::Connection::new(database_path);
+            let mut db = data::DB::new(&connection);
+            archive_unarchived_media(&mut db, &api, &archiver, &shutdown);
+        });
+    }
+
+    let context = Context {
+        config: Arc::new(config),
+        api: client.api().clone(),
+        archiver,
+        cache: Arc::new(Mutex::new(HashMap::new())),
+        progress,
+    };
+
+    let worker_context = context.clone();
+    let dispatcher = Dispatcher::new(context.config.workers, context.config.queue_size, move |jobs| {
+        let connection = data::Connection::new(worker_context.config.database_path.clone());
+        let mut db = data::DB::new(&connection);
+
+        for (update_id, update) in jobs {
+            if db.is_update_handled(update_id) {
+                info!(update_id; "Skipping update, it was handled before");
+            } else {
+                let started = Instant::now();
+                let (update_type, user_id) = (update_type(&update), sender(&update));
+
+                // A panicking handler loses its update, not the worker, and the offset still moves past it.
+                if panic::catch_unwind(AssertUnwindSafe(|| handle_update(&mut db, &worker_context, update_id, update))).is_err() {
+                    error!(update_id, update_type; "Handling update panicked, skipping it");
+                    db.rollback();
+                }
+
+                db.mark_update_handled(update_id);
+
+                debug!(update_id, update_type, user_id, latency_ms = started.elapsed().as_millis() as u64; "Handled update");
+            }
+
+            finish_update(&mut db, &worker_context.progress, update_id);
+        }
+    });
+
+    health::start();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        health::tick();
+
+        let (update_id, update) = match client.receive_update(Duration::from_secs(RECEIVE_TIMEOUT_SEC)) {
+            Some(update) => update,
+            None => continue
+        };
+
+        metrics::increment(metrics::UPDATES, &[("type", update_type(&update))]);
+
+        if !is_allowed(&context.config, &update) {
+            info!("Ignoring update from a chat not in allowed_chats");
+            finish_update(&mut db, &context.progress, update_id);
+            continue;
+        }
+
+        match lane(&update) {
+            Some(lane) => dispatcher.dispatch(lane, (update_id, update)),
+            None => finish_update(&mut db, &context.progress, update_id)
+        }
+    }
+
+    info!("Shutting down, finishing queued updates");
+
+    client.shutdown();
+    dispatcher.shutdown();
+
+    if let Some(offset) = context.progress.offset() {
+        if let Err(e) = client.acknowledge(offset) {
+            warn!(error:% = e; "Failed to acknowledge updates before {}", offset);
+        }
+    }
+
+    info!("Shut down cleanly");
+
+    Ok(())
+}
+
+pub fn restore(config: Config) -> Result<(), Box<dyn Error>> {
+    let api = telegram::blocking::Api::new(&config.api_url, config.require_api_key()?)?;
+    let connection = data::Connection::new(config.database_path.clone());
+    let mut db = data::DB::new(&connection);
+    let archiver = archive::Archiver::from_config(config.archive.as_ref().ok_or("No archive configured.")?);
+
+    logging::init(&config);
 
-static MESSAGE_CHECK_INTERVAL_MSEC: u64 = 200;
+    for media in db.read_archived_media() {
+        match archiver.restore(&api, &media.media_type, &media.archive_path, &media.archive_hash) {
+            Ok(file_id) => {
+                info!(file_id:% = file_id; "Restored media_id {} with a new file_id", media.media_id);
+                db.update_media_file_id(media.media_id, &file_id);
+            }
+            Err(e) => error!(error:% = e; "Failed to restore media_id {}", media.media_id)
+        }
+    }
 
-pub struct Config {
-    api_key: String,
-    database_connection: data::Connection,
+    Ok(())
 }
 
-pub fn configure() -> Result<Config, Box<Error>> {
-    dotenv::dotenv()?;
+pub fn export(config: Config, path: &str, with_files: bool) -> Result<(), Box<dyn Error>> {
+    let connection = data::Connection::new(config.database_path.clone());
+    let mut db = data::DB::new(&connection);
+    let archiver = config.archive.as_ref().map(archive::Archiver::from_config);
+    let mut out = BufWriter::new(File::create(path)?);
+
+    logging::init(&config);
 
-    let api_key = dotenv::var("MEHU_TELEGRAM_APIKEY")?;
-    let database_path = dotenv::var("MEHU_DATASTORE_PATH")?;
-    let database_connection = data::Connection::new(database_path);
+    let count = bundle::export(&mut db, archiver.as_ref(), with_files, &mut out)?;
+    info!("Exported {} media to {}", count, path);
 
-    Ok(Config { api_key, database_connection })
+    Ok(())
 }
 
-pub fn run(config: Config) -> Result<(), Box<Error>> {
-    let client = telegram::Client::new(config.api_key)?;
-    let mut db = data::DB::new(&config.database_connection);
-    let mut cache = HashMap::new();
+pub fn import(config: Config, path: &str) -> Result<(), Box<dyn Error>> {
+    let connection = data::Connection::new(config.database_path.clone());
+    let mut db = data::DB::new(&connection);
+    let archiver = config.archive.as_ref().map(archive::Archiver::from_config);
+    let input = BufReader::new(File::open(path)?);
 
-    env_logger::init();
+    logging::init(&config);
+
+    let summary = bundle::import(&mut db, archiver.as_ref(), input)?;
+    info!("Imported {} media, {} tags and {} files from {}", summary.media, summary.tags, summary.files, path);
+
+    Ok(())
+}
+
+// Replies only make sense after the callback that sent the media was handled, both arrive from the user's private chat.
+fn lane(update: &UpdateMessage) -> Option<Lane> {
+    match *update {
+        UpdateMessage::InlineQuery { .. } => Some(Lane::Parallel),
+        UpdateMessage::ChosenInlineResult { .. } => Some(Lane::Parallel),
+        UpdateMessage::Photo { chat_id, .. } => Some(Lane::Ordered(chat_id)),
+        UpdateMessage::Document { chat_id, .. } => Some(Lane::Ordered(chat_id)),
+        UpdateMessage::CallbackQuery(CallbackCommand::Tag { user_id, .. }) => Some(Lane::Ordered(user_id)),
+        UpdateMessage::CallbackQuery(CallbackCommand::Visibility { user_id, .. }) => Some(Lane::Ordered(user_id)),
+        UpdateMessage::CallbackQuery(CallbackCommand::Moderation { user_id, .. }) => Some(Lane::Ordered(user_id)),
+        UpdateMessage::CallbackQuery(CallbackCommand::Report { user_id, .. }) => Some(Lane::Ordered(user_id)),
+        UpdateMessage::ReplyToMessage { chat_id, .. } => Some(Lane::Ordered(chat_id)),
+        UpdateMessage::Command { chat_id, .. } => Some(Lane::Ordered(chat_id)),
+        UpdateMessage::ChatMember { chat_id, .. } => Some(Lane::Ordered(chat_id)),
+        UpdateMessage::None => None
+    }
+}
+
+fn update_type(update: &UpdateMessage) -> &'static str {
+    match *update {
+        UpdateMessage::InlineQuery { .. } => "inline_query",
+        UpdateMessage::ChosenInlineResult { .. } => "chosen_inline_result",
+        UpdateMessage::Photo { .. } => "photo",
+        UpdateMessage::Document { .. } => "document",
+        UpdateMessage::CallbackQuery(_) => "callback_query",
+        UpdateMessage::ReplyToMessage { .. } => "reply",
+        UpdateMessage::Command { .. } => "command",
+        UpdateMessage::ChatMember { .. } => "chat_member",
+        UpdateMessage::None => "other"
+    }
+}
 
-    loop {
-        match client.receive_update() {
-            UpdateMessage::InlineQuery { inline_query_id, query } => handle_query(&mut db, &client, inline_query_id, query),
-            UpdateMessage::ChosenInlineResult { media_id, query } => handle_chosen_inline_result(&mut db, media_id, query),
-            UpdateMessage::Photo { file_id, tags } => handle_media(&mut db, file_id, tags, data::MediaType::Photo),
-            UpdateMessage::Document { file_id, mime_type, tags } => handle_document(&mut db, file_id, mime_type, tags),
-            UpdateMessage::CallbackQuery(command) => handle_callback_query(&mut db, &mut cache, &client, command),
-            UpdateMessage::ReplyToMessage { ref message_id, ref text } => handle_reply_message(&mut db, &mut cache, message_id, text),
-            UpdateMessage::None => thread::sleep(Duration::from_millis(MESSAGE_CHECK_INTERVAL_MSEC))
+// The offset only moves past an update once it and every update before it are handled.
+fn finish_update(db: &mut data::DB, progress: &telegram::Progress, update_id: i64) {
+    if let Some(offset) = progress.finish(update_id) {
+        db.update_update_offset(offset);
+    }
+}
+
+fn handle_update(db: &mut data::DB, context: &Context, update_id: i64, update: UpdateMessage) {
+    let api = &context.api;
+    let archiver = context.archiver.as_ref().map(|a| a.as_ref());
+    let role = sender(&update).map_or(Role::Admin, |user_id| role(db, &context.config, user_id));
+
+    if role < required_role(&context.config, &update) {
+        info!(update_id; "Ignoring update from a user with role {}", role);
+        return;
+    }
+
+    if let Some((chat_id, user_id)) = group_member(&update) {
+        db.insert_chat_member(chat_id, user_id);
+    }
+
+    match update {
+        UpdateMessage::InlineQuery { inline_query_id, query, user_id } => handle_query(db, api, &context.config, archiver, inline_query_id, query, user_id),
+        UpdateMessage::ChosenInlineResult { media_id, query, user_id } => handle_chosen_inline_result(db, update_id, media_id, query, user_id),
+        UpdateMessage::Photo { file_id, mut tags, chat_id, user_id } => {
+            let upload = upload(db, &context.config, role, &mut tags, chat_id, user_id);
+            handle_media(db, api, &context.config, archiver, file_id, tags, data::MediaType::Photo, upload)
+        }
+        UpdateMessage::Document { file_id, mime_type, mut tags, chat_id, user_id } => {
+            let upload = upload(db, &context.config, role, &mut tags, chat_id, user_id);
+            handle_document(db, api, &context.config, archiver, file_id, mime_type, tags, upload)
         }
+        UpdateMessage::CallbackQuery(command) => handle_callback_query(db, &context.cache, api, &context.config, role, command),
+        UpdateMessage::ReplyToMessage { message_id, ref text, chat_id, .. } => handle_reply_message(db, &context.cache, chat_id, message_id, text),
+        UpdateMessage::Command { command, args, chat_id, user_id } => handle_command(db, api, &context.config, role, &command, &args, chat_id, user_id),
+        UpdateMessage::ChatMember { chat_id, user_id, joined: false } => db.delete_chat_member(chat_id, user_id),
+        UpdateMessage::ChatMember { .. } => (),
+        UpdateMessage::None => ()
+    }
+}
+
+// The user an update comes from, None for updates that are not anybody's doing.
+fn sender(update: &UpdateMessage) -> Option<i64> {
+    match *update {
+        UpdateMessage::InlineQuery { user_id, .. } => Some(user_id),
+        UpdateMessage::ChosenInlineResult { user_id, .. } => Some(user_id),
+        UpdateMessage::Photo { user_id, .. } => Some(user_id),
+        UpdateMessage::Document { user_id, .. } => Some(user_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Tag { user_id, .. }) => Some(user_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Visibility { user_id, .. }) => Some(user_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Moderation { user_id, .. }) => Some(user_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Report { user_id, .. }) => Some(user_id),
+        UpdateMessage::ReplyToMessage { user_id, .. } => Some(user_id),
+        UpdateMessage::Command { user_id, .. } => Some(user_id),
+        UpdateMessage::ChatMember { .. } => None,
+        UpdateMessage::None => None
+    }
+}
+
+// Searching and reporting need a viewer, adding media or tags a contributor. Commands check their own roles. In approval mode
+// viewers may upload as well and admins decide on what they sent.
+fn required_role(config: &Config, update: &UpdateMessage) -> Role {
+    let uploader = if config.approval { Role::Viewer } else { Role::Contributor };
+
+    match *update {
+        UpdateMessage::InlineQuery { .. } => Role::Viewer,
+        UpdateMessage::ChosenInlineResult { .. } => Role::Viewer,
+        UpdateMessage::Photo { .. } => uploader,
+        UpdateMessage::Document { .. } => uploader,
+        UpdateMessage::CallbackQuery(CallbackCommand::Moderation { .. }) => Role::Admin,
+        UpdateMessage::CallbackQuery(CallbackCommand::Report { .. }) => Role::Viewer,
+        UpdateMessage::CallbackQuery(_) => Role::Contributor,
+        UpdateMessage::ReplyToMessage { .. } => Role::Contributor,
+        UpdateMessage::Command { .. } => Role::Viewer,
+        UpdateMessage::ChatMember { .. } => Role::Banned,
+        UpdateMessage::None => Role::Banned
+    }
+}
+
+fn role(db: &mut data::DB, config: &Config, user_id: i64) -> Role {
+    if config.admin_ids.contains(&user_id) {
+        return Role::Admin;
+    }
+
+    db.read_user_role(user_id).unwrap_or(config.default_role)
+}
+
+// Group chats have negative ids, whoever writes in one is a member of it.
+fn group_member(update: &UpdateMessage) -> Option<(i64, i64)> {
+    let member = match *update {
+        UpdateMessage::Photo { chat_id, user_id, .. } => (chat_id, user_id),
+        UpdateMessage::Document { chat_id, user_id, .. } => (chat_id, user_id),
+        UpdateMessage::Command { chat_id, user_id, .. } => (chat_id, user_id),
+        UpdateMessage::ChatMember { chat_id, user_id, joined: true } => (chat_id, user_id),
+        _ => return None
+    };
+
+    if member.0 < 0 { Some(member) } else { None }
+}
+
+// Who uploaded a media and who finds it.
+struct Upload {
+    chat_id: i64,
+    owner_id: i64,
+    visibility: Visibility,
+    shared_with: Vec<i64>,
+    // The uploader picked the visibility with a caption flag, there is no need to ask.
+    flagged: bool,
+    nsfw: bool,
+    // Waits for an admin's approval, see Config::approval.
+    pending: bool,
+}
+
+// A #public, #private or #groups caption flag wins, otherwise media saved in a group with a shared library is
+// shared with the group and everything else is public. #nsfw marks the media NSFW. Flags are removed from `tags`.
+fn upload(db: &mut data::DB, config: &Config, role: Role, tags: &mut Vec<String>, chat_id: i64, user_id: i64) -> Upload {
+    let nsfw = tags.iter().any(|t| t.to_lowercase() == NSFW_FLAG);
+    tags.retain(|t| t.to_lowercase() != NSFW_FLAG);
+
+    let flags: Vec<Visibility> = tags.iter()
+                                     .filter(|t| t.starts_with("#"))
+                                     .filter_map(|t| t[1..].parse::<Visibility>().ok())
+                                     .collect();
+
+    tags.retain(|t| !t.starts_with("#") || t[1..].parse::<Visibility>().is_err());
+
+    let visibility = match flags.last() {
+        Some(visibility) => *visibility,
+        None if chat_id < 0 && db.read_chat_settings(chat_id).shared_library => Visibility::Groups,
+        None => Visibility::Public
+    };
+
+    let shared_with = match visibility {
+        Visibility::Groups if chat_id < 0 => vec![chat_id],
+        Visibility::Groups => db.read_user_groups(user_id),
+        _ => Vec::new()
+    };
+
+    let pending = config.approval && role < Role::Contributor;
+
+    Upload { chat_id, owner_id: user_id, visibility, shared_with, flagged: !flags.is_empty(), nsfw, pending }
+}
+
+fn is_allowed(config: &Config, update: &UpdateMessage) -> bool {
+    match *update {
+        UpdateMessage::InlineQuery { user_id, .. } => config.is_chat_allowed(user_id),
+        UpdateMessage::ChosenInlineResult { user_id, .. } => config.is_chat_allowed(user_id),
+        UpdateMessage::Photo { chat_id, .. } => config.is_chat_allowed(chat_id),
+        UpdateMessage::Document { chat_id, .. } => config.is_chat_allowed(chat_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Tag { user_id, .. }) => config.is_chat_allowed(user_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Visibility { user_id, .. }) => config.is_chat_allowed(user_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Moderation { user_id, .. }) => config.is_chat_allowed(user_id),
+        UpdateMessage::CallbackQuery(CallbackCommand::Report { user_id, .. }) => config.is_chat_allowed(user_id),
+        UpdateMessage::ReplyToMessage { chat_id, .. } => config.is_chat_allowed(chat_id),
+        UpdateMessage::Command { chat_id, .. } => config.is_chat_allowed(chat_id),
+        UpdateMessage::ChatMember { chat_id, .. } => config.is_chat_allowed(chat_id),
+        UpdateMessage::None => true
     }
 }
 
-fn handle_query(db: &mut data::DB, client: &telegram::Client, inline_query_id: String, query: String) {
-    info!("Received inline query {} with id {}", query, inline_query_id);
+fn handle_query(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config, archiver: Option<&archive::Archiver>, inline_query_id: String, query: String, user_id: i64) {
+    info!(inline_query_id:% = inline_query_id, query:% = query, user_id; "Received inline query");
 
-    let results = if query.len() == 0 {
-        db.read_media()
+    let started = Instant::now();
+    let results = if query.is_empty() {
+        db.read_media(user_id, config.result_limit)
     } else {
-        db.read_media_with_query(query)
+        db.read_media_with_query(user_id, query.clone(), config.result_limit)
     };
 
-    client.answer_inline_query(inline_query_id,
-                               results
-                                   .iter()
-                                   .map(|m| {
-                                       match m {
-                                           &Entity::Media { ref id, ref file_id, ref media_type } => match media_type {
-                                               &MediaType::Photo => AnswerMessage::Photo { file_id: file_id.clone(), media_id: id.clone() },
-                                               &MediaType::Mpeg4Gif => AnswerMessage::Mpeg4Gif { file_id: file_id.clone(), media_id: id.clone() },
-                                               &MediaType::ImageGif => AnswerMessage::Gif { file_id: file_id.clone(), media_id: id.clone() }
-                                           },
-                                           _ => AnswerMessage::None
-                                       }
-                                   }).collect());
+    let suggestion = if results.is_empty() && !query.is_empty() {
+        info!(query:% = query, user_id; "Inline query found nothing");
+        suggest::did_you_mean(&query, &db.read_visible_tags(user_id))
+    } else {
+        None
+    };
+
+    let answered = api.answer_inline_query(inline_query_id.clone(), answer_messages(&results), suggestion);
+
+    metrics::observe_duration(metrics::INLINE_QUERY_DURATION, &[], started.elapsed());
+    metrics::observe(metrics::INLINE_QUERY_RESULTS, &[], results.len() as f64);
+
+    db.insert_usage_event(user_id, UsageEvent::Query { query: &query.to_lowercase(), results: results.len() as i64 });
+
+    let error = match answered {
+        Ok(()) => return,
+        Err(e) => e
+    };
+
+    // One file_id Telegram no longer accepts fails the whole answer, any other failure would just happen again.
+    if !error.is_invalid_file_id() {
+        error!(error:% = error; "Failed to answer inline query");
+        return;
+    }
+
+    let broken = answer_without_broken(api, inline_query_id, results);
+
+    if !broken.is_empty() {
+        handle_broken_media(db, api, config, archiver, broken);
+    }
+}
+
+// Telegram doesn't say which file_id it rejected, so the results that were rejected together are bisected by answering
+// with the first half of them. A rejected half holds a broken file_id and is halved again until a single result is
+// left, which is broken and dropped before all the remaining results are tried again. An accepted answer is what the
+// user gets, Telegram takes no other answer after it, so what it left out can only be checked with getFile. getFile
+// still serves some file_ids that answers reject, so it is only asked about a few of them.
+fn answer_without_broken(api: &telegram::blocking::Api, inline_query_id: String, results: Vec<Entity>) -> Vec<Entity> {
+    let mut kept: Vec<usize> = (0..results.len()).collect();
+    let mut broken = Vec::new();
+    // The first `rejected` kept results were last rejected together.
+    let mut rejected = kept.len();
+
+    let answered = loop {
+        let tried = if rejected == 1 {
+            broken.push(kept.remove(0));
+            kept.len()
+        } else {
+            rejected / 2
+        };
+
+        match api.answer_inline_query(inline_query_id.clone(), answer_messages(kept[..tried].iter().map(|&i| &results[i])), None) {
+            Ok(()) => break tried,
+            Err(ref e) if e.is_invalid_file_id() && tried > 0 => rejected = tried,
+            Err(e) => {
+                error!(error:% = e; "Failed to answer inline query while looking for broken media");
+                kept.clear();
+                break 0;
+            }
+        }
+    };
+
+    let left_out = &kept[answered..];
+
+    // A single result between the accepted answer and the last rejected one must be the broken one.
+    let certain = usize::from(!left_out.is_empty() && rejected == answered + 1);
+    let found = broken.len() + certain;
+
+    broken.extend(&left_out[..certain]);
+    broken.extend(left_out[certain..].iter().take(MAX_FILE_CHECKS).filter(|&&i| is_broken(api, &results[i])));
+
+    if left_out.len() > certain && broken.len() == found {
+        warn!("Answered inline query without {} of {} results, getFile found none of them broken", left_out.len() - certain, results.len());
+    }
+
+    results.into_iter()
+           .enumerate()
+           .filter(|&(i, _)| broken.contains(&i))
+           .map(|(_, m)| m)
+           .collect()
+}
+
+fn answer_messages<'a>(results: impl IntoIterator<Item = &'a Entity>) -> Vec<AnswerMessage> {
+    results.into_iter()
+           .map(|m| {
+               match m {
+                   Entity::Media { id, file_id, media_type } => match *media_type {
+                       MediaType::Photo => AnswerMessage::Photo { file_id: file_id.clone(), media_id: *id },
+                       MediaType::Mpeg4Gif => AnswerMessage::Mpeg4Gif { file_id: file_id.clone(), media_id: *id },
+                       MediaType::ImageGif => AnswerMessage::Gif { file_id: file_id.clone(), media_id: *id }
+                   },
+                   _ => AnswerMessage::None
+               }
+           }).collect()
+}
+
+fn is_broken(api: &telegram::blocking::Api, media: &Entity) -> bool {
+    match media {
+        Entity::Media { file_id, .. } => matches!(api.check_file(file_id.clone()), Err(ref e) if e.is_invalid_file_id()),
+        _ => false
+    }
+}
+
+// Hides the media from results, restores what the archive has and asks the admins to upload the rest again.
+fn handle_broken_media(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config, archiver: Option<&archive::Archiver>, broken: Vec<Entity>) {
+    let mut unrepaired = 0;
+
+    for media in broken {
+        if let Entity::Media { id, file_id, .. } = media {
+            // Concurrent queries find the same media, only the first one handles it.
+            if !db.mark_media_broken(id) {
+                continue;
+            }
+
+            warn!(file_id:% = file_id; "Telegram rejected the file_id of media_id {}, hiding it from results", id);
+
+            if !archiver.is_some_and(|a| repair_media(db, api, a, id)) {
+                unrepaired += 1;
+            }
+        }
+    }
+
+    if unrepaired > 0 {
+        notify_broken_media(db, api, config);
+    }
+}
+
+fn repair_media(db: &mut data::DB, api: &telegram::blocking::Api, archiver: &archive::Archiver, media_id: i64) -> bool {
+    let (media_type, path, hash) = match db.read_stored_media(media_id) {
+        Some(data::StoredMedia { media_type, archive_path: Some(path), archive_hash: Some(hash), .. }) => (media_type, path, hash),
+        _ => return false
+    };
+
+    match archiver.restore(api, &media_type, &path, &hash) {
+        Ok(file_id) => {
+            info!(file_id:% = file_id; "Repaired media_id {} with a new file_id", media_id);
+            db.update_media_file_id(media_id, &file_id);
+            true
+        }
+        Err(e) => {
+            error!(error:% = e; "Failed to repair media_id {} from the archive", media_id);
+            false
+        }
+    }
+}
+
+fn notify_broken_media(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config) {
+    let mut text = "Telegram no longer accepts these media, they are hidden from results until they are uploaded again:".to_string();
+
+    for media in db.read_broken_media() {
+        if let Entity::Media { id, media_type, .. } = media {
+            text.push_str(&format!("\n{} ({:?}): {}", id, media_type, media_tags(db, id).join(" ")));
+        }
+    }
+
+    for admin_id in admin_ids(db, config) {
+        if let Err(e) = api.send_message(admin_id, text.clone()) {
+            error!(error:% = e, admin_id; "Failed to notify an admin of broken media");
+        }
+    }
 }
 
-fn handle_media(db: &mut data::DB, file_id: String, tags: Vec<String>, media_type: data::MediaType) {
-    let media_id = db.insert(data::Entity::Media { id: 0, file_id, media_type });
+fn media_tags(db: &mut data::DB, media_id: i64) -> Vec<String> {
+    db.read_tags_with_mediaid(media_id)
+      .into_iter()
+      .filter_map(|t| match t { Entity::Tag { tag, .. } => Some(tag), _ => None })
+      .collect()
+}
+
+// Admins from the configuration and those granted the role.
+fn admin_ids(db: &mut data::DB, config: &Config) -> Vec<i64> {
+    let mut admin_ids = config.admin_ids.clone();
+    admin_ids.extend(db.read_users_with_role(Role::Admin));
+    admin_ids.sort();
+    admin_ids.dedup();
+
+    admin_ids
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_media(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config, archiver: Option<&archive::Archiver>, file_id: String, tags: Vec<String>, media_type: data::MediaType, upload: Upload) {
+    let media_id = db.insert_media(file_id.clone(), media_type.clone(), upload.owner_id, upload.visibility, &upload.shared_with, upload.nsfw, upload.pending);
 
     for tag in tags {
-        db.insert(data::Entity::Tag { id: 0, media_id, tag, counter: 0 });
+        db.insert(data::Entity::Tag { media_id, tag, counter: 0 });
+    }
+
+    // Media stored before keeps its approval, there is nothing to decide on.
+    if upload.pending && db.is_media_pending(media_id) == Some(true) {
+        request_approval(db, api, config, media_id, &media_type, &file_id, upload.owner_id);
+    }
+
+    // Asking in a group would let anyone answer.
+    if upload.chat_id > 0 && !upload.flagged {
+        if let Err(e) = api.ask_visibility(upload.chat_id, media_id) {
+            error!(error:% = e; "Failed to ask for the visibility of media_id {}", media_id);
+        }
+    }
+
+    if let Some(archiver) = archiver {
+        archive_media(db, api, archiver, media_id, file_id);
     }
 }
 
-fn handle_chosen_inline_result(db: &mut data::DB, media_id: i64, query: String) {
-    info!("Received chosen inline result for query {} with media_id {}", query, media_id);
+fn request_approval(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config, media_id: i64, media_type: &MediaType, file_id: &str, owner_id: i64) {
+    let caption = format!("Media {} from user {} is waiting for approval. Tags: {}", media_id, owner_id, media_tags(db, media_id).join(" "));
+    let nsfw = db.is_media_nsfw(media_id);
 
-    db.increase_tag_counter(media_id, query);
+    for admin_id in admin_ids(db, config) {
+        if let Err(e) = api.ask_approval(admin_id, media_id, media_type, file_id.to_string(), caption.clone(), nsfw) {
+            error!(error:% = e, admin_id; "Failed to ask an admin to approve media_id {}", media_id);
+        }
+    }
 }
 
-fn handle_document(mut db: &mut data::DB, file_id: String, mime_type: String, tags: Vec<String>) {
-    info!("Received document {} with mime_type {}", file_id, mime_type);
+fn archive_media(db: &mut data::DB, api: &telegram::blocking::Api, archiver: &archive::Archiver, media_id: i64, file_id: String) {
+    match archiver.archive(api, file_id) {
+        Ok(archived) => db.update_media_archive(media_id, &archived.path, &archived.hash),
+        Err(e) => error!(error:% = e; "Failed to archive media_id {}", media_id)
+    }
+}
+
+// Stops early on shutdown, what is left is archived on the next start.
+fn archive_unarchived_media(db: &mut data::DB, api: &telegram::blocking::Api, archiver: &archive::Archiver, shutdown: &AtomicBool) {
+    for media in db.read_media_without_archive() {
+        if shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if let Entity::Media { id, file_id, .. } = media {
+            archive_media(db, api, archiver, id, file_id);
+        }
+    }
+}
+
+fn handle_chosen_inline_result(db: &mut data::DB, update_id: i64, media_id: i64, query: String, user_id: i64) {
+    info!(query:% = query, user_id; "Received chosen inline result with media_id {}", media_id);
+
+    db.insert_usage_event(user_id, UsageEvent::Send { media_id, query: &query.to_lowercase() });
+    db.increase_tag_counter(update_id, media_id, query);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_document(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config, archiver: Option<&archive::Archiver>, file_id: String, mime_type: String, tags: Vec<String>, upload: Upload) {
+    info!(file_id:% = file_id; "Received document with mime_type {}", mime_type);
 
     match mime_type.as_ref() {
-        "video/mp4" => handle_media(&mut db, file_id, tags, data::MediaType::Mpeg4Gif),
-        "image/gif" => handle_media(&mut db, file_id, tags, data::MediaType::ImageGif),
+        "video/mp4" => handle_media(db, api, config, archiver, file_id, tags, data::MediaType::Mpeg4Gif, upload),
+        "image/gif" => handle_media(db, api, config, archiver, file_id, tags, data::MediaType::ImageGif, upload),
         _ => ()
     }
 }
 
-fn handle_callback_query(db: &mut data::DB, cache: &mut HashMap<i64, i64>, client: &telegram::Client, command: CallbackCommand) {
+fn handle_callback_query(db: &mut data::DB, cache: &Mutex<HashMap<(i64, i64), i64>>, api: &telegram::blocking::Api, config: &Config, role: Role, command: CallbackCommand) {
     match command {
         CallbackCommand::Tag { media_id, user_id } => {
-            match db.read_media_with_mediaid(media_id) {
-                Entity::Media { ref file_id, ref media_type, .. } => if let Some(message_id) = match media_type {
-                    &MediaType::Photo => client.send_photo(user_id, file_id.clone()),
-                    &MediaType::Mpeg4Gif => client.send_document(user_id, file_id.clone()),
-                    &MediaType::ImageGif => client.send_document(user_id, file_id.clone()),
+            if let Some(Entity::Media { ref file_id, ref media_type, .. }) = db.read_media_with_mediaid(user_id, media_id) {
+                match match *media_type {
+                    MediaType::Photo => api.send_photo(user_id, file_id.clone(), db.is_media_nsfw(media_id)),
+                    MediaType::Mpeg4Gif => api.send_document(user_id, file_id.clone()),
+                    MediaType::ImageGif => api.send_document(user_id, file_id.clone()),
                 } {
-                    cache.insert(message_id, media_id);
-                },
-                _ => ()
+                    Ok(message_id) => { cache.lock().unwrap().insert((user_id, message_id), media_id); }
+                    Err(e) => error!(error:% = e; "Failed to send media_id {} for tagging", media_id)
+                }
+            }
+        }
+        CallbackCommand::Visibility { media_id, user_id, visibility, callback_query_id } => {
+            let text = handle_visibility(db, role, media_id, user_id, visibility);
+
+            if let Err(e) = api.answer_callback_query(callback_query_id, text) {
+                error!(error:% = e; "Failed to answer visibility choice for media_id {}", media_id);
+            }
+        }
+        CallbackCommand::Moderation { media_id, user_id, approved, callback_query_id } => {
+            let text = handle_moderation(db, api, media_id, user_id, approved);
+
+            if let Err(e) = api.answer_callback_query(callback_query_id, text) {
+                error!(error:% = e; "Failed to answer moderation decision for media_id {}", media_id);
+            }
+        }
+        CallbackCommand::Report { media_id, user_id, reason: None, callback_query_id } => {
+            // Results are sent to other chats, the reason is asked in private.
+            let text = match api.ask_report_reason(user_id, media_id) {
+                Ok(_) => "Tell the bot in your private chat what is wrong with this media.",
+                Err(e) => {
+                    error!(error:% = e, user_id; "Failed to ask why a user reports media_id {}", media_id);
+                    "Start a private chat with the bot to report media."
+                }
+            };
+
+            if let Err(e) = api.answer_callback_query(callback_query_id, text.to_string()) {
+                error!(error:% = e; "Failed to answer report of media_id {}", media_id);
+            }
+        }
+        CallbackCommand::Report { media_id, user_id, reason: Some(reason), callback_query_id } => {
+            let text = handle_report(db, api, config, media_id, user_id, reason);
+
+            if let Err(e) = api.answer_callback_query(callback_query_id, text) {
+                error!(error:% = e; "Failed to answer report of media_id {}", media_id);
+            }
+        }
+    }
+}
+
+// Media reported by `report_threshold` users is hidden and the admins are told to look at it with /reports.
+fn handle_report(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config, media_id: i64, user_id: i64, reason: ReportReason) -> String {
+    // Media the reporter can't see is reported the same as media that doesn't exist, its id tells nothing.
+    if !db.is_media_visible(user_id, media_id) {
+        return "This media no longer exists.".to_string();
+    }
+
+    let reports = match db.insert_report(media_id, user_id, reason) {
+        Some(reports) => reports,
+        None => return "You already reported this media.".to_string()
+    };
+
+    info!(user_id; "Media_id {} was reported as {}", media_id, reason);
+
+    // The count can pass the threshold without landing on it, when the threshold was lowered or reports came in at once.
+    if config.report_threshold > 0 && reports >= config.report_threshold && db.hide_media(media_id) {
+        warn!("Hiding media_id {} after {} reports", media_id, reports);
+
+        let text = format!("Media {} is hidden from results after {} reports, see /reports.", media_id, reports);
+
+        for admin_id in admin_ids(db, config) {
+            if let Err(e) = api.send_message(admin_id, text.clone()) {
+                error!(error:% = e, admin_id; "Failed to notify an admin of reported media");
             }
         }
     }
+
+    "Thanks, the admins will take a look.".to_string()
 }
 
-fn handle_reply_message(db: &mut data::DB, cache: &mut HashMap<i64, i64>, message_id: &i64, text: &str) {
-    if cache.contains_key(message_id) {
+// Approved media shows up in results, rejected media is deleted. Every admin gets the buttons, the first one decides.
+fn handle_moderation(db: &mut data::DB, api: &telegram::blocking::Api, media_id: i64, user_id: i64, approved: bool) -> String {
+    let owner_id = db.read_media_owner(media_id).and_then(|o| o);
+
+    // Admins may decide at the same time, only the one whose decision changed the media tells the uploader.
+    let decided = if approved { db.approve_media(media_id) } else { db.reject_media(media_id) };
+
+    if !decided {
+        return match db.is_media_pending(media_id) {
+            Some(_) => "Another admin already approved this media.".to_string(),
+            None => "This media no longer exists.".to_string()
+        };
+    }
+
+    let (text, notice) = if approved {
+        info!(user_id; "Approved media_id {}", media_id);
+        ("Approved, everyone it is shared with can find it now.", format!("Your media {} was approved.", media_id))
+    } else {
+        info!(user_id; "Rejected media_id {}", media_id);
+        ("Rejected and deleted.", format!("Your media {} was rejected.", media_id))
+    };
+
+    if let Some(owner_id) = owner_id {
+        if let Err(e) = api.send_message(owner_id, notice) {
+            error!(error:% = e, owner_id; "Failed to tell the uploader about media_id {}", media_id);
+        }
+    }
+
+    text.to_string()
+}
+
+// Only the uploader or an admin may change who finds a media, "my groups" are the uploader's groups.
+fn handle_visibility(db: &mut data::DB, role: Role, media_id: i64, user_id: i64, visibility: Visibility) -> String {
+    let owner_id = match db.read_media_owner(media_id) {
+        Some(owner_id) if owner_id == Some(user_id) || role >= Role::Admin => owner_id.unwrap_or(user_id),
+        Some(_) => return "Only the uploader can change who finds this media.".to_string(),
+        None => return "This media no longer exists.".to_string()
+    };
+
+    let shared_with = match visibility {
+        Visibility::Groups => db.read_user_groups(owner_id),
+        _ => Vec::new()
+    };
+
+    db.update_media_visibility(media_id, visibility, &shared_with);
+    info!(user_id, visibility:% = visibility; "Changed who finds media_id {}", media_id);
+
+    match visibility {
+        Visibility::Public => "Everyone can find this media now.".to_string(),
+        Visibility::Groups => format!("Members of {} group(s) can find this media now.", shared_with.len()),
+        Visibility::Private => "Only you can find this media now.".to_string()
+    }
+}
+
+fn handle_reply_message(db: &mut data::DB, cache: &Mutex<HashMap<(i64, i64), i64>>, chat_id: i64, message_id: i64, text: &str) {
+    let media_id = cache.lock().unwrap().remove(&(chat_id, message_id));
+
+    if let Some(media_id) = media_id {
         for s in text.split(" ") {
-            db.insert(Entity::Tag { id: 0, media_id: cache.get(message_id).unwrap().clone(), tag: s.to_string(), counter: 0 });
+            db.insert(Entity::Tag { media_id, tag: s.to_string(), counter: 0 });
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_command(db: &mut data::DB, api: &telegram::blocking::Api, config: &Config, role: Role, command: &str, args: &[String], chat_id: i64, user_id: i64) {
+    info!(args:? = args, chat_id, user_id; "Received command {}", command);
+
+    let reply = match command {
+        "start" => start_command(api, args, chat_id),
+        "library" => library_command(db, api, args, chat_id, user_id),
+        "role" => role_command(db, config, args, user_id),
+        "nsfw" => Some(nsfw_command(db, role, args, user_id)),
+        "safesearch" => Some(safe_search_command(db, args, chat_id, user_id)),
+        "misses" if role < Role::Contributor => Some("Only contributors can use /misses.".to_string()),
+        "misses" => Some(misses_command(db, args, user_id)),
+        "grant" | "revoke" | "delete" | "reports" | "stats" if role < Role::Admin => Some(format!("Only bot admins can use /{}.", command)),
+        "grant" => Some(grant_command(db, config, args)),
+        "revoke" => Some(revoke_command(db, config, args)),
+        "delete" => Some(delete_command(db, args)),
+        "reports" => Some(reports_command(db, args)),
+        "stats" => Some(stats_command(db, args)),
+        _ => None
+    };
+
+    if let Some(text) = reply {
+        if let Err(e) = api.send_message(chat_id, text) {
+            error!(error:% = e, chat_id; "Failed to answer command {}", command);
+        }
+    }
+}
+
+// `/library [shared|public]` shows or sets where media saved in a group goes, only group administrators may set it.
+fn library_command(db: &mut data::DB, api: &telegram::blocking::Api, args: &[String], chat_id: i64, user_id: i64) -> Option<String> {
+    if chat_id >= 0 {
+        return Some("Libraries are set per group, send /library in the group.".to_string());
+    }
+
+    let shared_library = match args.first().map(|a| a.as_str()) {
+        Some("shared") => true,
+        Some("public") => false,
+        Some(_) => return Some("Use /library shared or /library public.".to_string()),
+        None => {
+            let library = if db.read_chat_settings(chat_id).shared_library { "this group's library" } else { "the public library" };
+            return Some(format!("Media saved here goes to {}. Change it with /library shared or /library public.", library));
+        }
+    };
+
+    match api.is_chat_admin(chat_id, user_id) {
+        Ok(true) => (),
+        Ok(false) => return Some("Only group administrators can change the library.".to_string()),
+        Err(e) => {
+            error!(error:% = e, user_id, chat_id; "Failed to look up a chat member");
+            return None;
+        }
+    }
+
+    db.update_chat_shared_library(chat_id, shared_library);
+
+    if shared_library {
+        Some("Media saved here now goes to this group's library, only its members can find it.".to_string())
+    } else {
+        Some("Media saved here now goes to the public library.".to_string())
+    }
+}
+
+// `/role [user_id]` shows the role of the sender or of another user.
+fn role_command(db: &mut data::DB, config: &Config, args: &[String], user_id: i64) -> Option<String> {
+    match args.first().map(|a| a.parse::<i64>()) {
+        None => Some(format!("Your role is {}.", role(db, config, user_id))),
+        Some(Ok(other)) => Some(format!("The role of {} is {}.", other, role(db, config, other))),
+        Some(Err(_)) => Some("Use /role or /role <user_id>.".to_string())
+    }
+}
+
+// `/grant <user_id> <role>`
+fn grant_command(db: &mut data::DB, config: &Config, args: &[String]) -> String {
+    let (user_id, role) = match (args.first().map(|a| a.parse::<i64>()), args.get(1).map(|a| a.parse::<Role>())) {
+        (Some(Ok(user_id)), Some(Ok(role))) => (user_id, role),
+        (_, Some(Err(e))) => return e,
+        _ => return "Use /grant <user_id> <admin|contributor|viewer|banned>.".to_string()
+    };
+
+    if config.admin_ids.contains(&user_id) {
+        return format!("{} is an admin in the configuration, their role can't be changed here.", user_id);
+    }
+
+    db.update_user_role(user_id, role);
+    info!(user_id; "Granted role {}", role);
+
+    format!("{} is now {}.", user_id, role)
+}
+
+// `/revoke <user_id>` returns the user to the default role.
+fn revoke_command(db: &mut data::DB, config: &Config, args: &[String]) -> String {
+    let user_id = match args.first().map(|a| a.parse::<i64>()) {
+        Some(Ok(user_id)) => user_id,
+        _ => return "Use /revoke <user_id>.".to_string()
+    };
+
+    if config.admin_ids.contains(&user_id) {
+        return format!("{} is an admin in the configuration, their role can't be changed here.", user_id);
+    }
+
+    if !db.delete_user_role(user_id) {
+        return format!("{} has no role to revoke, they are {}.", user_id, config.default_role);
+    }
+
+    info!(user_id; "Revoked a role");
+
+    format!("{} is {} again.", user_id, config.default_role)
+}
+
+// `/delete <media_id>` removes a media and its tags from the library.
+fn delete_command(db: &mut data::DB, args: &[String]) -> String {
+    let media_id = match args.first().map(|a| a.parse::<i64>()) {
+        Some(Ok(media_id)) => media_id,
+        _ => return "Use /delete <media_id>.".to_string()
+    };
+
+    if !db.delete_media(media_id) {
+        return format!("There is no media {}.", media_id);
+    }
+
+    info!("Deleted media_id {}", media_id);
+
+    format!("Deleted media {}.", media_id)
+}
+
+// `/reports` lists media with open reports, `/reports keep <media_id>` dismisses them and shows the media again and
+// `/reports delete <media_id>` removes the media.
+fn reports_command(db: &mut data::DB, args: &[String]) -> String {
+    let media_id = match (args.first().map(|a| a.as_str()), args.get(1).map(|a| a.parse::<i64>())) {
+        (None, _) => return list_reports(db),
+        (Some("keep"), Some(Ok(media_id))) | (Some("delete"), Some(Ok(media_id))) => media_id,
+        _ => return "Use /reports, /reports keep <media_id> or /reports delete <media_id>.".to_string()
+    };
+
+    if args[0] == "delete" {
+        return delete_command(db, &args[1..]);
+    }
+
+    if !db.resolve_reports(media_id) {
+        return format!("Media {} has no open reports.", media_id);
+    }
+
+    info!("Resolved the reports of media_id {}", media_id);
+
+    format!("Resolved the reports of media {}, it shows up in results again.", media_id)
+}
+
+fn list_reports(db: &mut data::DB) -> String {
+    let reports = db.read_reports();
+
+    if reports.is_empty() {
+        return "There are no open reports.".to_string();
+    }
+
+    let mut text = "Open reports:".to_string();
+
+    for report in reports {
+        let reasons: Vec<String> = report.reasons.iter().map(|&(reason, count)| format!("{} {}", reason, count)).collect();
+        let hidden = if report.hidden { ", hidden" } else { "" };
+
+        text.push_str(&format!("\n{} ({:?}{}): {} - {}", report.media_id, report.media_type, hidden, reasons.join(", "),
+                               media_tags(db, report.media_id).join(" ")));
+    }
+
+    text
+}
+
+// `/nsfw <media_id> [on|off]` lets the uploader or an admin mark a media NSFW.
+fn nsfw_command(db: &mut data::DB, role: Role, args: &[String], user_id: i64) -> String {
+    let (media_id, nsfw) = match (args.first().map(|a| a.parse::<i64>()), args.get(1).map(|a| a.as_str())) {
+        (Some(Ok(media_id)), None) | (Some(Ok(media_id)), Some("on")) => (media_id, true),
+        (Some(Ok(media_id)), Some("off")) => (media_id, false),
+        _ => return "Use /nsfw <media_id> [on|off].".to_string()
+    };
+
+    // Media the sender may not change answers like media that doesn't exist, so ids don't reveal what others uploaded.
+    let owner_id = db.read_media_owner(media_id).filter(|_| role >= Role::Admin || db.is_media_visible(user_id, media_id));
+
+    match owner_id {
+        Some(owner_id) if owner_id == Some(user_id) || role >= Role::Admin => (),
+        _ => return format!("There is no media {} of yours.", media_id)
+    }
+
+    db.update_media_nsfw(media_id, nsfw);
+    info!("Media_id {} is now nsfw = {}", media_id, nsfw);
+
+    if nsfw {
+        format!("Media {} is marked NSFW.", media_id)
+    } else {
+        format!("Media {} is no longer marked NSFW.", media_id)
+    }
+}
+
+// `/safesearch [on|off]` shows or sets whether NSFW media is left out of the sender's inline results.
+fn safe_search_command(db: &mut data::DB, args: &[String], chat_id: i64, user_id: i64) -> String {
+    if chat_id != user_id {
+        return "Safe search is a personal setting, send /safesearch to the bot in a private chat.".to_string();
+    }
+
+    let safe_search = match args.first().map(|a| a.as_str()) {
+        Some("on") => true,
+        Some("off") => false,
+        Some(_) => return "Use /safesearch on or /safesearch off.".to_string(),
+        None => {
+            let state = if db.read_chat_settings(user_id).safe_search { "on" } else { "off" };
+            return format!("Safe search is {}.", state);
+        }
+    };
+
+    db.update_chat_safe_search(user_id, safe_search);
+
+    if safe_search {
+        "Safe search is on, NSFW media is hidden from your results.".to_string()
+    } else {
+        "Safe search is off, NSFW media shows up in your results.".to_string()
+    }
+}
+
+// `/stats [days]` reports usage of the last week or of the given number of days.
+fn stats_command(db: &mut data::DB, args: &[String]) -> String {
+    match stats::parse_days(args.first().map(|a| a.as_str())) {
+        Some(days) => stats::to_text(&stats::report(db, days)),
+        None => format!("Use /stats or /stats <days>, with days from 1 to {}.", stats::MAX_DAYS)
+    }
+}
+
+// `/misses [days]` lists frequent queries that found nothing, with the tag each may have meant.
+fn misses_command(db: &mut data::DB, args: &[String], user_id: i64) -> String {
+    match stats::parse_days(args.first().map(|a| a.as_str())) {
+        Some(days) => {
+            // Suggesting a tag the user can't see would reveal private and group media.
+            let tags = db.read_visible_tags(user_id);
+            stats::misses_to_text(&stats::misses(db, days, &tags), days)
+        }
+        None => format!("Use /misses or /misses <days>, with days from 1 to {}.", stats::MAX_DAYS)
+    }
+}
+
+// A "Did you mean" button above inline results opens the private chat with `/start suggest-<tag>`.
+fn start_command(api: &telegram::blocking::Api, args: &[String], chat_id: i64) -> Option<String> {
+    let tag = args.first().and_then(|a| telegram::suggestion_from_start(a))?;
+
+    if let Err(e) = api.offer_search(chat_id, tag) {
+        error!(error:% = e, chat_id; "Failed to offer a suggested search");
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use std::collections::HashSet;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use super::*;
+    use self::serde_json::{json, json_internal, Value};
+
+    static INVALID_FILE_ID: &str = "Bad Request: wrong file identifier/HTTP URL specified";
+    static QUERY_ANSWERED: &str = "Bad Request: query is too old and response timeout expired or query ID is invalid";
+
+    // A fake Bot API rejecting answers and getFile calls that name a broken file_id. Like Telegram it accepts one
+    // answer per inline query, the file_ids of that answer are kept.
+    struct FakeApi {
+        broken: HashSet<String>,
+        answer: Mutex<Option<Vec<String>>>,
+    }
+
+    impl FakeApi {
+        fn serve(broken: HashSet<String>) -> (String, Arc<FakeApi>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let fake = Arc::new(FakeApi { broken, answer: Mutex::new(None) });
+            let server = fake.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let server = server.clone();
+                    thread::spawn(move || server.handle(stream.unwrap()));
+                }
+            });
+
+            (url, fake)
+        }
+
+        fn handle(&self, stream: TcpStream) {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+
+            loop {
+                let mut request_line = String::new();
+
+                if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                    return;
+                }
+
+                let mut length = 0;
+
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+
+                    if header.trim().is_empty() {
+                        break;
+                    }
+
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let method = request_line.split(' ').nth(1).and_then(|p| p.rsplit('/').next()).unwrap_or("").to_string();
+                let (status, response) = self.respond(&method, &serde_json::from_slice(&body).unwrap());
+                let response = response.to_string();
+
+                write!(stream, "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, response.len(), response).unwrap();
+            }
+        }
+
+        fn respond(&self, method: &str, body: &Value) -> (u16, Value) {
+            match method {
+                "answerInlineQuery" => {
+                    let file_ids: Vec<String> = body["results"].as_array().unwrap()
+                                                               .iter()
+                                                               .map(|r| r["photo_file_id"].as_str().unwrap().to_string())
+                                                               .collect();
+                    let mut answer = self.answer.lock().unwrap();
+
+                    if answer.is_some() {
+                        rejected(QUERY_ANSWERED)
+                    } else if file_ids.iter().any(|f| self.broken.contains(f)) {
+                        rejected(INVALID_FILE_ID)
+                    } else {
+                        *answer = Some(file_ids);
+                        (200, json!({ "ok": true, "result": true }))
+                    }
+                }
+                "getFile" => {
+                    let file_id = body["file_id"].as_str().unwrap();
+
+                    if self.broken.contains(file_id) {
+                        rejected(INVALID_FILE_ID)
+                    } else {
+                        (200, json!({ "ok": true, "result": { "file_id": file_id } }))
+                    }
+                }
+                _ => (404, json!({ "ok": false, "error_code": 404, "description": "Not Found" }))
+            }
+        }
+    }
+
+    fn rejected(description: &str) -> (u16, Value) {
+        (400, json!({ "ok": false, "error_code": 400, "description": description }))
+    }
+
+    fn file_id(i: usize) -> String {
+        format!("file-{}", i)
+    }
+
+    // Looks for the `broken` ones among `count` photos, returns the media_ids found and the file_ids of the answer.
+    fn find_broken(count: usize, broken: &[usize]) -> (Vec<i64>, Vec<String>) {
+        let (url, fake) = FakeApi::serve(broken.iter().map(|&i| file_id(i)).collect());
+        let api = telegram::blocking::Api::new(&url, "123:secret".to_string()).unwrap();
+        let results = (0..count).map(|i| Entity::Media { id: i as i64, file_id: file_id(i), media_type: MediaType::Photo }).collect();
+
+        let found = answer_without_broken(&api, "query".to_string(), results)
+            .into_iter()
+            .map(|m| match m {
+                Entity::Media { id, .. } => id,
+                _ => unreachable!()
+            })
+            .collect();
+        let answer = fake.answer.lock().unwrap().take().expect("No answer was accepted.");
+
+        (found, answer)
+    }
+
+    fn assert_answer_without(answer: &[String], broken: &[usize]) {
+        for &i in broken {
+            assert!(!answer.contains(&file_id(i)), "answer {:?} holds broken {}", answer, file_id(i));
+        }
+    }
+
+    #[test]
+    fn finds_nothing_when_no_result_is_rejected() {
+        let (found, answer) = find_broken(8, &[]);
+
+        assert!(found.is_empty());
+        assert!(!answer.is_empty());
+    }
+
+    #[test]
+    fn finds_one_broken_result() {
+        let (found, answer) = find_broken(8, &[5]);
+
+        assert_eq!(found, vec![5]);
+        assert_answer_without(&answer, &[5]);
+    }
+
+    #[test]
+    fn finds_two_broken_results() {
+        let (found, answer) = find_broken(8, &[1, 6]);
+
+        assert_eq!(found, vec![1, 6]);
+        assert_answer_without(&answer, &[1, 6]);
+    }
+
+    #[test]
+    fn finds_every_result_broken() {
+        let (found, answer) = find_broken(8, &[0, 1, 2, 3, 4, 5, 6, 7]);
+
+        assert_eq!(found, vec![0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(answer.is_empty());
+    }
+
+    #[test]
+    fn records_a_single_rejected_result_as_broken() {
+        let (found, answer) = find_broken(1, &[0]);
+
+        assert_eq!(found, vec![0]);
+        assert!(answer.is_empty());
+    }
+
+    #[test]
+    fn replies_only_tag_media_sent_to_the_same_chat() {
+        let conn = data::Connection::new(":memory:".to_string());
+        let mut db = data::DB::new(&conn);
+        let media_id = db.insert_media("photo".to_string(), MediaType::Photo, 1, Visibility::Public, &[], false, false);
+        let cache = Mutex::new(HashMap::new());
+        cache.lock().unwrap().insert((1, 10), media_id);
+
+        handle_reply_message(&mut db, &cache, 2, 10, "cat");
+        assert!(db.read_tags_with_mediaid(media_id).is_empty());
+
+        handle_reply_message(&mut db, &cache, 1, 10, "cat dog");
+        assert_eq!(db.read_tags_with_mediaid(media_id).len(), 2);
+    }
+
+    #[test]
+    fn safe_search_is_on_until_turned_off_in_a_private_chat() {
+        let conn = data::Connection::new(":memory:".to_string());
+        let mut db = data::DB::new(&conn);
+        let nsfw = db.insert_media("nsfw".to_string(), MediaType::Photo, 1, Visibility::Public, &[], true, false);
+        db.insert(Entity::Tag { media_id: nsfw, tag: "cat".to_string(), counter: 0 });
+
+        assert_eq!(safe_search_command(&mut db, &[], 2, 2), "Safe search is on.");
+        assert!(db.read_media(2, 10).is_empty());
+
+        safe_search_command(&mut db, &["off".to_string()], -100, 2);
+        assert!(db.read_media(2, 10).is_empty());
+
+        safe_search_command(&mut db, &["off".to_string()], 2, 2);
+        assert_eq!(safe_search_command(&mut db, &[], 2, 2), "Safe search is off.");
+        assert_eq!(db.read_media(2, 10).len(), 1);
+    }
+
+    #[test]
+    fn nsfw_command_answers_alike_for_missing_and_foreign_media() {
+        let conn = data::Connection::new(":memory:".to_string());
+        let mut db = data::DB::new(&conn);
+        let public = db.insert_media("public".to_string(), MediaType::Photo, 1, Visibility::Public, &[], false, false);
+        let private = db.insert_media("private".to_string(), MediaType::Photo, 1, Visibility::Private, &[], false, false);
+
+        for &media_id in &[public, private, 99] {
+            assert_eq!(nsfw_command(&mut db, Role::Contributor, &[media_id.to_string()], 2), format!("There is no media {} of yours.", media_id));
         }
 
-        cache.remove(message_id);
+        assert!(!db.is_media_nsfw(public));
+        assert_eq!(nsfw_command(&mut db, Role::Contributor, &[public.to_string()], 1), format!("Media {} is marked NSFW.", public));
+        assert!(db.is_media_nsfw(public));
+        assert_eq!(nsfw_command(&mut db, Role::Admin, &[private.to_string()], 3), format!("Media {} is marked NSFW.", private));
+        assert_eq!(nsfw_command(&mut db, Role::Admin, &["99".to_string()], 3), "There is no media 99 of yours.");
     }
 }
\ No newline at end of file
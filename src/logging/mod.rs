@@ -0,0 +1,255 @@
+extern crate chrono;
+extern crate hmac;
+extern crate rand;
+extern crate serde_json;
+extern crate sha2;
+
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use log::Record;
+use log::kv::{self, Key, Value, VisitSource};
+use self::chrono::Utc;
+use self::hmac::{Hmac, Mac};
+use self::sha2::Sha256;
+use crate::config::Config;
+
+static REDACTED: &str = "[redacted]";
+
+// Fields that identify users or carry what they wrote. Numbers are replaced by a keyed hash so that lines about
+// the same user can still be matched, anything else by REDACTED. File ids point at what users uploaded and Telegram
+// errors may quote the message they were about, so both are kept out of the message and logged as fields.
+static PII_FIELDS: &[&str] = &["user_id", "chat_id", "owner_id", "admin_id", "query", "text", "args", "tag", "body", "file_id", "error"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s.to_lowercase().as_ref() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{} is not one of text or json", s))
+        }
+    }
+}
+
+// Structured fields are given as `info!(update_id = id; "message")`, the API key never reaches the log.
+pub fn init(config: &Config) {
+    let format = config.log_format;
+    let api_key = config.api_key.clone();
+    let pii_key = if config.log_scrub_pii {
+        Some(config.log_pseudonym_key.as_ref().map(|k| k.as_bytes().to_vec()).unwrap_or_else(|| rand::random::<[u8; 32]>().to_vec()))
+    } else {
+        None
+    };
+
+    env_logger::Builder::new()
+        .filter(None, config.log_level)
+        .format(move |buf, record| writeln!(buf, "{}", line(record, format, pii_key.as_deref(), api_key.as_ref())))
+        .init();
+}
+
+// PII fields are scrubbed when there is a `pii_key` to make pseudonyms with.
+fn line(record: &Record, format: LogFormat, pii_key: Option<&[u8]>, api_key: Option<&String>) -> String {
+    let line = match format {
+        LogFormat::Text => text_line(record, pii_key),
+        LogFormat::Json => json_line(record, pii_key),
+    };
+
+    redact_api_key(line, api_key)
+}
+
+fn text_line(record: &Record, pii_key: Option<&[u8]>) -> String {
+    let mut line = format!("{} {:<5} {}: {}", timestamp(), record.level(), record.target(), record.args());
+
+    for (key, value) in fields(record, pii_key) {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+
+    line
+}
+
+fn json_line(record: &Record, pii_key: Option<&[u8]>) -> String {
+    let mut object = serde_json::Map::new();
+    object.insert("timestamp".to_string(), serde_json::Value::String(timestamp()));
+    object.insert("level".to_string(), serde_json::Value::String(record.level().to_string()));
+    object.insert("target".to_string(), serde_json::Value::String(record.target().to_string()));
+    object.insert("message".to_string(), serde_json::Value::String(record.args().to_string()));
+
+    for (key, value) in fields(record, pii_key) {
+        object.insert(key, value.0);
+    }
+
+    serde_json::Value::Object(object).to_string()
+}
+
+fn timestamp() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+// Reqwest errors carry the request URL, which carries the API key.
+fn redact_api_key(line: String, api_key: Option<&String>) -> String {
+    match api_key {
+        Some(key) if !key.is_empty() && line.contains(key.as_str()) => line.replace(key.as_str(), REDACTED),
+        _ => line
+    }
+}
+
+// A field value as it is written out, text lines show it without JSON quoting.
+struct Field(serde_json::Value);
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            serde_json::Value::String(ref s) => write!(f, "{}", s),
+            ref v => write!(f, "{}", v)
+        }
+    }
+}
+
+fn fields(record: &Record, pii_key: Option<&[u8]>) -> Vec<(String, Field)> {
+    let mut collector = Collector { fields: Vec::new(), pii_key };
+
+    // Collecting into a Vec can't fail.
+    let _ = record.key_values().visit(&mut collector);
+
+    collector.fields
+}
+
+struct Collector<'a> {
+    fields: Vec<(String, Field)>,
+    pii_key: Option<&'a [u8]>,
+}
+
+impl<'a, 'kvs> VisitSource<'kvs> for Collector<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let pii_key = self.pii_key.filter(|_| PII_FIELDS.contains(&key.as_str()));
+
+        let value = match (pii_key, value.to_i64(), value.to_u64(), value.to_f64(), value.to_bool()) {
+            (Some(pii_key), Some(n), _, _, _) => serde_json::Value::String(pseudonym(pii_key, n)),
+            (_, Some(n), _, _, _) => serde_json::Value::from(n),
+            (_, _, Some(n), _, _) => serde_json::Value::from(n),
+            (_, _, _, Some(n), _) => serde_json::Value::from(n),
+            (_, _, _, _, Some(b)) => serde_json::Value::Bool(b),
+            (Some(_), _, _, _, _) => serde_json::Value::String(REDACTED.to_string()),
+            _ => serde_json::Value::String(value.to_string())
+        };
+
+        self.fields.push((key.as_str().to_string(), Field(value)));
+
+        Ok(())
+    }
+}
+
+// Stands in for an id without revealing it, the same id always gets the same pseudonym under the same key.
+// Without the key the ids can't be found by hashing every possible id.
+fn pseudonym(key: &[u8], id: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length.");
+    mac.update(id.to_string().as_bytes());
+
+    format!("#{}", mac.finalize().into_bytes().iter().take(8).map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate reqwest;
+
+    use std::net::TcpListener;
+    use log::Level;
+    use super::*;
+
+    static API_KEY: &str = "123456:secret";
+    static PII_KEY: &[u8] = b"pseudonym key";
+
+    fn format(args: fmt::Arguments, fields: &[(&str, Value)], format: LogFormat, pii_key: Option<&[u8]>) -> String {
+        let record = Record::builder().args(args).level(Level::Info).target("mehubot").key_values(&fields).build();
+
+        line(&record, format, pii_key, Some(&API_KEY.to_string()))
+    }
+
+    fn json(line: &str) -> serde_json::Value {
+        serde_json::from_str(line).unwrap()
+    }
+
+    // The error of a call to a port nobody listens on, its message quotes the URL with the API key.
+    fn transport_error() -> reqwest::Error {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        reqwest::blocking::get(format!("http://{}/bot{}/getUpdates", address, API_KEY)).unwrap_err()
+    }
+
+    #[test]
+    fn redacts_the_api_key() {
+        let fields = [("body", Value::from("token=123456:secret"))];
+
+        for &log_format in &[LogFormat::Text, LogFormat::Json] {
+            let line = format(format_args!("Calling /bot{}/getMe", API_KEY), &fields, log_format, None);
+
+            assert!(!line.contains(API_KEY), "{}", line);
+            assert!(line.contains("/bot[redacted]/getMe"), "{}", line);
+            assert!(line.contains("token=[redacted]"), "{}", line);
+        }
+
+        assert_eq!(redact_api_key("no key here".to_string(), Some(&String::new())), "no key here");
+        assert_eq!(redact_api_key(API_KEY.to_string(), None), API_KEY);
+    }
+
+    #[test]
+    fn redacts_the_api_key_from_request_errors() {
+        let error = transport_error();
+
+        assert!(error.to_string().contains(API_KEY));
+
+        let fields = [("error", Value::from_display(&error))];
+
+        for &log_format in &[LogFormat::Text, LogFormat::Json] {
+            let line = format(format_args!("Failed to poll: {}", error), &fields, log_format, None);
+
+            assert!(!line.contains(API_KEY), "{}", line);
+            assert_eq!(line.matches("/bot[redacted]/getUpdates").count(), 2, "{}", line);
+        }
+    }
+
+    #[test]
+    fn scrubs_pii_fields_in_text() {
+        let fields = [("user_id", Value::from(42i64)), ("query", Value::from("cats")), ("update_id", Value::from(7i64))];
+        let line = format(format_args!("Received inline query"), &fields, LogFormat::Text, Some(PII_KEY));
+
+        assert!(line.ends_with(&format!("Received inline query user_id={} query=[redacted] update_id=7", pseudonym(PII_KEY, 42))), "{}", line);
+    }
+
+    #[test]
+    fn scrubs_pii_fields_in_json() {
+        let fields = [("user_id", Value::from(42i64)), ("file_id", Value::from("AgADBAAD")), ("update_id", Value::from(7i64))];
+        let line = json(&format(format_args!("Archived a file"), &fields, LogFormat::Json, Some(PII_KEY)));
+
+        assert_eq!(line["message"], "Archived a file");
+        assert_eq!(line["user_id"], pseudonym(PII_KEY, 42));
+        assert_eq!(line["file_id"], REDACTED);
+        assert_eq!(line["update_id"], 7);
+    }
+
+    #[test]
+    fn keeps_pii_fields_without_scrubbing() {
+        let fields = [("user_id", Value::from(42i64)), ("query", Value::from("cats"))];
+        let line = json(&format(format_args!("Received inline query"), &fields, LogFormat::Json, None));
+
+        assert_eq!(line["user_id"], 42);
+        assert_eq!(line["query"], "cats");
+    }
+
+    #[test]
+    fn pseudonyms_depend_on_the_id_and_the_key() {
+        assert_eq!(pseudonym(PII_KEY, 42), pseudonym(PII_KEY, 42));
+        assert_ne!(pseudonym(PII_KEY, 42), pseudonym(PII_KEY, 43));
+        assert_ne!(pseudonym(PII_KEY, 42), pseudonym(b"other key", 42));
+        assert_eq!(pseudonym(PII_KEY, 42).len(), 17);
+    }
+}
\ No newline at end of file
@@ -1,6 +1,5 @@
 extern crate mehubot;
 
 fn main() {
-    let config = mehubot::configure().unwrap();
-    mehubot::run(config).unwrap();
+    std::process::exit(mehubot::cli::run(std::env::args().skip(1).collect(), &mut std::io::stdout()));
 }
\ No newline at end of file
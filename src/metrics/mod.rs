@@ -0,0 +1,168 @@
+mod server;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub use self::server::serve;
+
+static DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+static RESULT_BUCKETS: &[f64] = &[0.0, 1.0, 5.0, 10.0, 25.0, 50.0];
+
+pub static UPDATES: &str = "mehubot_updates_total";
+pub static INLINE_QUERY_DURATION: &str = "mehubot_inline_query_duration_seconds";
+pub static INLINE_QUERY_RESULTS: &str = "mehubot_inline_query_results";
+pub static DB_STATEMENT_DURATION: &str = "mehubot_db_statement_duration_seconds";
+pub static TELEGRAM_ERRORS: &str = "mehubot_telegram_errors_total";
+pub static TELEGRAM_QUEUED_CALLS: &str = "mehubot_telegram_queued_calls";
+pub static LIBRARY_MEDIA: &str = "mehubot_library_media";
+pub static LIBRARY_TAGS: &str = "mehubot_library_tags";
+
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram(&'static [f64]),
+}
+
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+}
+
+// Everything the bot reports, in the order it is rendered.
+static METRICS: &[Metric] = &[
+    Metric { name: UPDATES, help: "Updates received, by type.", kind: Kind::Counter },
+    Metric { name: INLINE_QUERY_DURATION, help: "Time to answer an inline query.", kind: Kind::Histogram(DURATION_BUCKETS) },
+    Metric { name: INLINE_QUERY_RESULTS, help: "Results returned for an inline query.", kind: Kind::Histogram(RESULT_BUCKETS) },
+    Metric { name: DB_STATEMENT_DURATION, help: "Time spent running a database statement, by statement.", kind: Kind::Histogram(DURATION_BUCKETS) },
+    Metric { name: TELEGRAM_ERRORS, help: "Failed bot API calls, by method and error code.", kind: Kind::Counter },
+    Metric { name: TELEGRAM_QUEUED_CALLS, help: "Bot API calls waiting for the rate limiter.", kind: Kind::Gauge },
+    Metric { name: LIBRARY_MEDIA, help: "Media in the library.", kind: Kind::Gauge },
+    Metric { name: LIBRARY_TAGS, help: "Distinct tags in the library.", kind: Kind::Gauge },
+];
+
+// Nothing is recorded until the endpoint is started.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REGISTRY: Mutex<BTreeMap<Series, Value>> = Mutex::new(BTreeMap::new());
+
+// A metric name and its label pairs.
+type Series = (&'static str, Vec<(&'static str, String)>);
+
+enum Value {
+    Number(f64),
+    // Cumulative count per bucket bound, then the sum and count of all observations.
+    Histogram(Vec<u64>, f64, u64),
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn increment(name: &'static str, labels: &[(&'static str, &str)]) {
+    update(name, labels, |value| if let &mut Value::Number(ref mut n) = value {
+        *n += 1.0;
+    });
+}
+
+pub fn set(name: &'static str, labels: &[(&'static str, &str)], number: f64) {
+    update(name, labels, |value| *value = Value::Number(number));
+}
+
+pub fn observe(name: &'static str, labels: &[(&'static str, &str)], observed: f64) {
+    let buckets = match metric(name).kind {
+        Kind::Histogram(buckets) => buckets,
+        _ => return
+    };
+
+    update(name, labels, |value| {
+        if let &mut Value::Number(_) = value {
+            *value = Value::Histogram(vec![0; buckets.len()], 0.0, 0);
+        }
+
+        if let &mut Value::Histogram(ref mut counts, ref mut sum, ref mut count) = value {
+            for (bound, bucket) in buckets.iter().zip(counts.iter_mut()) {
+                if observed <= *bound {
+                    *bucket += 1;
+                }
+            }
+
+            *sum += observed;
+            *count += 1;
+        }
+    });
+}
+
+pub fn observe_duration(name: &'static str, labels: &[(&'static str, &str)], duration: Duration) {
+    observe(name, labels, duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+}
+
+// The Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+
+    for metric in METRICS {
+        let kind = match metric.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        };
+
+        writeln!(out, "# HELP {} {}", metric.name, metric.help).unwrap();
+        writeln!(out, "# TYPE {} {}", metric.name, kind).unwrap();
+
+        for (&(name, ref labels), value) in registry.iter().filter(|&(&(name, _), _)| name == metric.name) {
+            match (value, &metric.kind) {
+                (&Value::Number(n), _) => writeln!(out, "{}{} {}", name, format_labels(labels, None), n).unwrap(),
+                (&Value::Histogram(ref counts, sum, count), &Kind::Histogram(buckets)) => {
+                    for (bound, bucket) in buckets.iter().zip(counts) {
+                        writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some(&bound.to_string())), bucket).unwrap();
+                    }
+
+                    writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), count).unwrap();
+                    writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), sum).unwrap();
+                    writeln!(out, "{}_count{} {}", name, format_labels(labels, None), count).unwrap();
+                }
+                _ => ()
+            }
+        }
+    }
+
+    out
+}
+
+fn update<F>(name: &'static str, labels: &[(&'static str, &str)], f: F)
+    where F: FnOnce(&mut Value)
+{
+    if !is_enabled() {
+        return;
+    }
+
+    let series = (name, labels.iter().map(|&(k, v)| (k, v.to_string())).collect());
+    let mut registry = REGISTRY.lock().unwrap();
+
+    f(registry.entry(series).or_insert(Value::Number(0.0)));
+}
+
+fn metric(name: &str) -> &'static Metric {
+    METRICS.iter().find(|m| m.name == name).expect("Unknown metric.")
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels.iter()
+                                       .map(|&(k, ref v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+                                       .collect();
+
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+
+    if pairs.is_empty() { String::new() } else { format!("{{{}}}", pairs.join(",")) }
+}
\ No newline at end of file
@@ -0,0 +1,152 @@
+extern crate serde_json;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+use crate::data;
+use crate::health;
+
+// A client can hold the endpoint for this long per read or write, and send this much of a request.
+static SOCKET_TIMEOUT_SEC: u64 = 5;
+static MAX_REQUEST_BYTES: u64 = 8192;
+static CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+static JSON_CONTENT_TYPE: &str = "application/json";
+
+// Serves GET /metrics, /health and /ready on `address` from a thread of its own, requests are answered one at a time.
+// Library gauges are read from the database at `database_path` on every scrape, over a connection of its own so that
+// a database that fails to open fails the scrape and not the thread.
+pub fn serve(address: &str, database_path: String, ready_threshold: Duration) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    info!("Serving metrics and health checks on http://{}", listener.local_addr()?);
+
+    super::enable();
+
+    thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|s| handle(&database_path, ready_threshold, s));
+
+            if let Err(e) = result {
+                warn!("Failed to answer a metrics request: {}", e);
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+fn handle(database_path: &str, ready_threshold: Duration, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT_SEC)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(SOCKET_TIMEOUT_SEC)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // The headers are of no interest but have to be read before answering.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match with_db(database_path, |db| db.read_library_stats()) {
+            Some(Ok(stats)) => {
+                super::set(super::LIBRARY_MEDIA, &[], stats.media as f64);
+                super::set(super::LIBRARY_TAGS, &[], stats.tags as f64);
+
+                ("200 OK", CONTENT_TYPE, super::render())
+            }
+            Some(Err(e)) => {
+                warn!(error:% = e; "Failed to read library stats for metrics");
+                ("500 Internal Server Error", CONTENT_TYPE, "Failed to read library stats\n".to_string())
+            }
+            None => ("500 Internal Server Error", CONTENT_TYPE, "Failed to open the database\n".to_string())
+        },
+        // Liveness, the main loop is still turning.
+        (Some("GET"), Some("/health")) => {
+            let liveness = health::liveness();
+            let status = if liveness.alive { "200 OK" } else { "503 Service Unavailable" };
+
+            (status, JSON_CONTENT_TYPE, json(&liveness))
+        }
+        // Readiness, the database answers and Telegram was reached lately.
+        (Some("GET"), Some("/ready")) => {
+            let database = with_db(database_path, |db| db.is_reachable()).unwrap_or(false);
+            let readiness = health::readiness(database, ready_threshold);
+            let status = if readiness.ready { "200 OK" } else { "503 Service Unavailable" };
+
+            (status, JSON_CONTENT_TYPE, json(&readiness))
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", CONTENT_TYPE, "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", CONTENT_TYPE, "Method not allowed\n".to_string())
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, content_type, body.len(), body)?;
+
+    stream.flush()
+}
+
+// Runs `f` against the database, None when it can't be opened. Opening panics on errors, which is fine for the bot
+// but would end the server thread.
+fn with_db<T>(database_path: &str, f: impl FnOnce(&mut data::DB) -> T) -> Option<T> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let connection = data::Connection::new(database_path.to_string());
+        let mut db = data::DB::new(&connection);
+
+        f(&mut db)
+    }));
+
+    if result.is_err() {
+        error!("Failed to open the database for a metrics request");
+    }
+
+    result.ok()
+}
+
+fn json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).expect("Failed to serialize health status.") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use super::*;
+
+    // A path SQLite can't create, its directory is missing.
+    static UNOPENABLE_DATABASE: &str = "/nonexistent/mehubot/database.sqlite";
+
+    fn get(database_path: &str, path: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        write!(client, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        handle(database_path, Duration::from_secs(60), stream).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        response
+    }
+
+    #[test]
+    fn scrapes_the_library() {
+        let path = env::temp_dir().join(format!("mehubot-metrics-{}.sqlite", process::id()));
+
+        assert!(get(&path.to_string_lossy(), "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn failing_database_fails_the_scrape_and_readiness() {
+        assert!(get(UNOPENABLE_DATABASE, "/metrics").starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(get(UNOPENABLE_DATABASE, "/ready").starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(get(UNOPENABLE_DATABASE, "/nothing").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
\ No newline at end of file
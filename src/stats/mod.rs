@@ -0,0 +1,168 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::data::{self, DailyActivity, MediaUses, MissedQuery, TagUses};
+use crate::suggest;
+
+static SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+static TOP_LIMIT: i64 = 10;
+static MISSES_LIMIT: i64 = 25;
+static DEFAULT_DAYS: i64 = 7;
+
+pub static MAX_DAYS: i64 = 3650;
+
+// Usage of the last `days` days, built from the recorded usage events.
+#[derive(Serialize)]
+pub struct UsageReport {
+    pub days: i64,
+    pub active_users: i64,
+    pub top_media: Vec<MediaUses>,
+    pub top_tags: Vec<TagUses>,
+    pub missed_queries: Vec<MissedQuery>,
+    pub growth: Vec<DailyActivity>,
+}
+
+// A query that found nothing and the tag it may have meant.
+#[derive(Serialize)]
+pub struct Miss {
+    pub query: String,
+    pub misses: i64,
+    pub users: i64,
+    pub suggestion: Option<String>,
+}
+
+// The number of days a report covers, a week when not given and None when out of range.
+pub fn parse_days(arg: Option<&str>) -> Option<i64> {
+    match arg.map(|a| a.parse::<i64>()) {
+        None => Some(DEFAULT_DAYS),
+        Some(Ok(days)) if days >= 1 && days <= MAX_DAYS => Some(days),
+        Some(_) => None
+    }
+}
+
+pub fn report(db: &mut data::DB, days: i64) -> UsageReport {
+    let since = now() - days * SECONDS_PER_DAY;
+
+    UsageReport {
+        days,
+        active_users: db.read_active_users(since),
+        top_media: db.read_top_media(since, TOP_LIMIT),
+        top_tags: db.read_top_tags(since, TOP_LIMIT),
+        missed_queries: db.read_missed_queries(since, TOP_LIMIT),
+        growth: db.read_daily_activity(since),
+    }
+}
+
+// The most frequent misses of the last `days` days, so contributors know what to upload. Suggestions only come from
+// `tags`, in the bot the tags the asking user may see.
+pub fn misses(db: &mut data::DB, days: i64, tags: &[String]) -> Vec<Miss> {
+    let since = now() - days * SECONDS_PER_DAY;
+
+    db.read_missed_queries(since, MISSES_LIMIT)
+      .into_iter()
+      .map(|q| Miss { suggestion: suggest::did_you_mean(&q.query, tags), query: q.query, misses: q.misses, users: q.users })
+      .collect()
+}
+
+pub fn to_text(report: &UsageReport) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "Usage in the last {} days, {} active users.", report.days, report.active_users).unwrap();
+
+    writeln!(out, "\nTop media:").unwrap();
+    for m in &report.top_media {
+        writeln!(out, "  {} ({:?}): {} sends", m.media_id, m.media_type, m.uses).unwrap();
+    }
+
+    writeln!(out, "\nTop tags:").unwrap();
+    for t in &report.top_tags {
+        writeln!(out, "  {}: {} sends", t.tag, t.uses).unwrap();
+    }
+
+    writeln!(out, "\nQueries without results:").unwrap();
+    for q in &report.missed_queries {
+        writeln!(out, "  {}: {} times by {} users", q.query, q.misses, q.users).unwrap();
+    }
+
+    writeln!(out, "\nPer day (uploads, queries, sends, active users, new users):").unwrap();
+    for d in &report.growth {
+        writeln!(out, "  {}: {} {} {} {} {}", d.day, d.uploads, d.queries, d.sends, d.active_users, d.new_users).unwrap();
+    }
+
+    out
+}
+
+// One row per value so every section fits the same columns.
+pub fn to_csv(report: &UsageReport) -> String {
+    let mut out = String::from("section,key,metric,value\n");
+    let mut row = |section: &str, key: &str, metric: &str, value: i64| {
+        writeln!(out, "{},{},{},{}", section, csv_field(key), metric, value).unwrap();
+    };
+
+    row("summary", "", "active_users", report.active_users);
+
+    for m in &report.top_media {
+        row("top_media", &m.media_id.to_string(), "sends", m.uses);
+    }
+
+    for t in &report.top_tags {
+        row("top_tags", &t.tag, "sends", t.uses);
+    }
+
+    for q in &report.missed_queries {
+        row("missed_queries", &q.query, "misses", q.misses);
+        row("missed_queries", &q.query, "users", q.users);
+    }
+
+    for d in &report.growth {
+        row("growth", &d.day, "uploads", d.uploads);
+        row("growth", &d.day, "queries", d.queries);
+        row("growth", &d.day, "sends", d.sends);
+        row("growth", &d.day, "active_users", d.active_users);
+        row("growth", &d.day, "new_users", d.new_users);
+    }
+
+    out
+}
+
+pub fn misses_to_text(misses: &[Miss], days: i64) -> String {
+    let mut out = String::new();
+
+    if misses.is_empty() {
+        writeln!(out, "Every query found something in the last {} days.", days).unwrap();
+        return out;
+    }
+
+    writeln!(out, "Queries without results in the last {} days:", days).unwrap();
+
+    for m in misses {
+        match m.suggestion {
+            Some(ref tag) => writeln!(out, "  {}: {} times by {} users, maybe {}", m.query, m.misses, m.users, tag).unwrap(),
+            None => writeln!(out, "  {}: {} times by {} users", m.query, m.misses, m.users).unwrap()
+        }
+    }
+
+    out
+}
+
+pub fn misses_to_csv(misses: &[Miss]) -> String {
+    let mut out = String::from("query,misses,users,suggestion\n");
+
+    for m in misses {
+        writeln!(out, "{},{},{},{}", csv_field(&m.query), m.misses, m.users, csv_field(m.suggestion.as_ref().map_or("", |s| s.as_str()))).unwrap();
+    }
+
+    out
+}
+
+// Queries are free text, quote them when they would break the row.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
\ No newline at end of file
@@ -0,0 +1,118 @@
+// Queries shorter than this are still being typed, suggesting for them is noise.
+static MIN_QUERY_CHARS: usize = 3;
+
+// The tag closest to a query that found nothing, if one is close enough to be a typo of it.
+// Tags are matched by prefix, so a query is compared with whole tags and with tag prefixes of its own length.
+pub fn did_you_mean(query: &str, tags: &[String]) -> Option<String> {
+    let query: Vec<char> = query.trim().to_lowercase().chars().collect();
+
+    if query.len() < MIN_QUERY_CHARS {
+        return None;
+    }
+
+    let max_distance = (query.len() + 2) / 4;
+
+    tags.iter()
+        .filter_map(|tag| {
+            let tag_chars: Vec<char> = tag.chars().collect();
+            let prefix = &tag_chars[..query.len().min(tag_chars.len())];
+            let distance = edit_distance(&query, &tag_chars).min(edit_distance(&query, prefix));
+
+            if distance <= max_distance { Some((distance, tag_chars.len(), tag)) } else { None }
+        })
+        .min()
+        .map(|(_, _, tag)| tag.clone())
+}
+
+// Optimal string alignment distance, a swap of two neighbouring characters counts as one edit like in Damerau.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+
+            rows[i][j] = (rows[i - 1][j] + 1).min(rows[i][j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                rows[i][j] = rows[i][j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        edit_distance(&a, &b)
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn edit_distance_counts_single_edits() {
+        assert_eq!(distance("kissa", "kissa"), 0);
+        assert_eq!(distance("kissa", "kisse"), 1);
+        assert_eq!(distance("kissa", "kissat"), 1);
+        assert_eq!(distance("kissa", "kisa"), 1);
+        assert_eq!(distance("kissa", "kisas"), 1);
+        assert_eq!(distance("kissa", "koira"), 3);
+        assert_eq!(distance("pää", "paa"), 2);
+    }
+
+    #[test]
+    fn edit_distance_of_empty_input_is_the_other_length() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("", "cat"), 3);
+        assert_eq!(distance("cat", ""), 3);
+    }
+
+    #[test]
+    fn suggests_the_closest_tag() {
+        let tags = tags(&["koira", "kissa", "hevonen"]);
+
+        assert_eq!(did_you_mean("kisas", &tags), Some("kissa".to_string()));
+        assert_eq!(did_you_mean(" KOIRA ", &tags), Some("koira".to_string()));
+        assert_eq!(did_you_mean("hevo", &tags), Some("hevonen".to_string()));
+    }
+
+    #[test]
+    fn ties_go_to_the_shorter_then_the_first_tag_alphabetically() {
+        assert_eq!(did_you_mean("cst", &tags(&["cats", "cat"])), Some("cat".to_string()));
+        assert_eq!(did_you_mean("aat", &tags(&["cat", "bat"])), Some("bat".to_string()));
+    }
+
+    #[test]
+    fn tags_further_than_the_max_distance_are_not_suggested() {
+        // Up to five characters allow one edit, six to nine two.
+        assert_eq!(did_you_mean("abxye", &tags(&["abcde"])), None);
+        assert_eq!(did_you_mean("abxyef", &tags(&["abcdef"])), Some("abcdef".to_string()));
+        assert_eq!(did_you_mean("axxyef", &tags(&["abcdef"])), None);
+    }
+
+    #[test]
+    fn short_or_empty_input_suggests_nothing() {
+        let tags = tags(&["cat"]);
+
+        assert_eq!(did_you_mean("", &tags), None);
+        assert_eq!(did_you_mean("   ", &tags), None);
+        assert_eq!(did_you_mean("ca", &tags), None);
+        assert_eq!(did_you_mean("cat", &[]), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,126 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use super::futures::stream::{Stream, StreamExt};
+use super::tokio::runtime::Runtime;
+use super::tokio::time;
+use super::{AnswerMessage, Error, Progress, UpdateMessage};
+use crate::data::MediaType;
+
+type Updates = Pin<Box<dyn Stream<Item = (i64, UpdateMessage)> + Send>>;
+
+// Synchronous facade over the async client for the worker threads and one-off commands.
+// Must not be used from inside the runtime it wraps.
+pub struct Client {
+    updates: Option<Updates>,
+    api: Api,
+}
+
+#[derive(Clone)]
+pub struct Api {
+    client: super::Client,
+    runtime: Arc<Runtime>,
+}
+
+impl Client {
+    pub fn new(api_url: &str, api_key: String, polling_timeout: u16, progress: Progress) -> Result<Client, &'static str> {
+        let api = Api::new(api_url, api_key)?;
+        let updates: Updates = Box::pin(api.client.updates(polling_timeout, progress));
+
+        Ok(Client { updates: Some(updates), api })
+    }
+
+    // Blocks until the next update arrives or `timeout` passes. A getUpdates cut short by the timeout carries on
+    // with the next call, so no updates are lost.
+    pub fn receive_update(&mut self, timeout: Duration) -> Option<(i64, UpdateMessage)> {
+        let updates = self.updates.as_mut()?;
+
+        self.api.runtime.block_on(async { time::timeout(timeout, updates.next()).await }).ok().and_then(|u| u)
+    }
+
+    // Aborts a getUpdates still in flight, later calls to receive_update return None right away.
+    pub fn shutdown(&mut self) {
+        if self.updates.take().is_some() {
+            info!("Stopped polling for updates");
+        }
+    }
+
+    pub fn acknowledge(&self, offset: i64) -> Result<(), Error> {
+        self.api.runtime.block_on(self.api.client.acknowledge(offset))
+    }
+
+    pub fn api(&self) -> &Api {
+        &self.api
+    }
+}
+
+impl Api {
+    pub fn new(api_url: &str, api_key: String) -> Result<Api, &'static str> {
+        let client = super::Client::new(api_url, api_key)?;
+        let runtime = Runtime::new().map_err(|_| "Failed to start the async runtime.")?;
+
+        Ok(Api { client, runtime: Arc::new(runtime) })
+    }
+
+    pub fn answer_inline_query(&self, inline_query_id: String, messages: Vec<AnswerMessage>, suggestion: Option<String>) -> Result<(), Error> {
+        self.runtime.block_on(self.client.answer_inline_query(inline_query_id, messages, suggestion))
+    }
+
+    pub fn send_photo(&self, chat_id: i64, photo: String, has_spoiler: bool) -> Result<i64, Error> {
+        self.runtime.block_on(self.client.send_photo(chat_id, photo, has_spoiler))
+    }
+
+    pub fn send_document(&self, chat_id: i64, document: String) -> Result<i64, Error> {
+        self.runtime.block_on(self.client.send_document(chat_id, document))
+    }
+
+    pub fn send_message(&self, chat_id: i64, text: String) -> Result<i64, Error> {
+        self.runtime.block_on(self.client.send_message(chat_id, text))
+    }
+
+    pub fn ask_visibility(&self, chat_id: i64, media_id: i64) -> Result<i64, Error> {
+        self.runtime.block_on(self.client.ask_visibility(chat_id, media_id))
+    }
+
+    pub fn ask_approval(&self, chat_id: i64, media_id: i64, media_type: &MediaType, file_id: String, caption: String, has_spoiler: bool) -> Result<i64, Error> {
+        self.runtime.block_on(self.client.ask_approval(chat_id, media_id, media_type, file_id, caption, has_spoiler))
+    }
+
+    pub fn ask_report_reason(&self, chat_id: i64, media_id: i64) -> Result<i64, Error> {
+        self.runtime.block_on(self.client.ask_report_reason(chat_id, media_id))
+    }
+
+    pub fn offer_search(&self, chat_id: i64, tag: String) -> Result<i64, Error> {
+        self.runtime.block_on(self.client.offer_search(chat_id, tag))
+    }
+
+    pub fn answer_callback_query(&self, callback_query_id: String, text: String) -> Result<(), Error> {
+        self.runtime.block_on(self.client.answer_callback_query(callback_query_id, text))
+    }
+
+    pub fn is_chat_admin(&self, chat_id: i64, user_id: i64) -> Result<bool, Error> {
+        self.runtime.block_on(self.client.is_chat_admin(chat_id, user_id))
+    }
+
+    pub fn check_file(&self, file_id: String) -> Result<(), Error> {
+        self.runtime.block_on(self.client.check_file(file_id))
+    }
+
+    pub fn download_file(&self, file_id: String) -> Result<Vec<u8>, Error> {
+        self.runtime.block_on(self.client.download_file(file_id))
+    }
+
+    pub fn upload_photo(&self, chat_id: i64, photo: Vec<u8>) -> Result<String, Error> {
+        self.runtime.block_on(self.client.upload_photo(chat_id, photo))
+    }
+
+    pub fn upload_document(&self, chat_id: i64, document: Vec<u8>, file_name: String) -> Result<String, Error> {
+        self.runtime.block_on(self.client.upload_document(chat_id, document, file_name))
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,89 @@
+use std::error;
+use std::fmt;
+use super::{reqwest, serde_json};
+
+#[derive(Debug)]
+pub enum Error {
+    // The request could not be sent or its response not read.
+    Transport(reqwest::Error),
+    // A failed response that is not from the bot API itself, e.g. from a proxy or for a file download.
+    Http { status: u16, body: String },
+    // A response that does not match what the bot API documents.
+    Json(serde_json::Error),
+    // The bot API refused the call, `retry_after` is set when it was rate limited.
+    Api { code: u16, description: String, retry_after: Option<u64> },
+}
+
+impl Error {
+    // The API or HTTP status code, "transport" or "json" when there is none.
+    pub fn code(&self) -> String {
+        match *self {
+            Error::Transport(_) => "transport".to_string(),
+            Error::Http { status, .. } => status.to_string(),
+            Error::Json(_) => "json".to_string(),
+            Error::Api { code, .. } => code.to_string(),
+        }
+    }
+
+    // The request named a file_id Telegram doesn't know, or no longer serves.
+    pub fn is_invalid_file_id(&self) -> bool {
+        match self {
+            &Error::Api { code: 400, ref description, .. } => {
+                let description = description.to_lowercase();
+                description.contains("file_id") || description.contains("file identifier")
+            }
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            &Error::Http { status, ref body } => write!(f, "HTTP status {}: {}", status, body),
+            Error::Json(e) => write!(f, "unexpected response: {}", e),
+            &Error::Api { code, ref description, .. } => write!(f, "Telegram error {}: {}", code, description),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(code: u16, description: &str) -> Error {
+        Error::Api { code, description: description.to_string(), retry_after: None }
+    }
+
+    #[test]
+    fn bad_requests_about_the_file_are_invalid_file_ids() {
+        assert!(api_error(400, "Bad Request: wrong file_id or the file is temporarily unavailable").is_invalid_file_id());
+        assert!(api_error(400, "Bad Request: wrong file identifier/HTTP URL specified").is_invalid_file_id());
+        assert!(api_error(400, "Bad Request: invalid file_id").is_invalid_file_id());
+        assert!(api_error(400, "Bad Request: WRONG FILE IDENTIFIER").is_invalid_file_id());
+    }
+
+    #[test]
+    fn other_errors_are_not_invalid_file_ids() {
+        assert!(!api_error(400, "Bad Request: query is too old and response timeout expired or query ID is invalid").is_invalid_file_id());
+        assert!(!api_error(400, "Bad Request: RESULT_ID_DUPLICATE").is_invalid_file_id());
+        assert!(!api_error(403, "Forbidden: bot was blocked by the user").is_invalid_file_id());
+        assert!(!api_error(429, "Too Many Requests: retry after 5").is_invalid_file_id());
+        assert!(!Error::Http { status: 400, body: "wrong file_id".to_string() }.is_invalid_file_id());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,211 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use super::tokio;
+use crate::metrics;
+
+// Telegram asks bots to stay below 30 messages per second overall, one per second in a private chat and
+// 20 per minute in a group.
+static GLOBAL_PER_SEC: f64 = 30.0;
+static GLOBAL_BURST: f64 = 30.0;
+static PRIVATE_CHAT_PER_SEC: f64 = 1.0;
+static GROUP_CHAT_PER_SEC: f64 = 20.0 / 60.0;
+static CHAT_BURST: f64 = 1.0;
+static MAX_IDLE_CHATS: usize = 1000;
+
+// Token buckets for outbound calls. Calls over the limit wait for their turn instead of being dropped.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    queued: AtomicUsize,
+    max_queued: AtomicUsize,
+}
+
+struct Buckets {
+    global: Bucket,
+    chats: HashMap<i64, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    burst: f64,
+    per_sec: f64,
+    updated: Instant,
+}
+
+// Counts a call as queued for as long as it waits, even if the wait is cancelled.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        let buckets = Buckets { global: Bucket::new(GLOBAL_BURST, GLOBAL_PER_SEC, Instant::now()), chats: HashMap::new() };
+
+        RateLimiter { buckets: Mutex::new(buckets), queued: AtomicUsize::new(0), max_queued: AtomicUsize::new(0) }
+    }
+
+    // Waits until `method` may be sent, `chat_id` being the chat it posts to, if any.
+    pub async fn acquire(&self, method: &str, chat_id: Option<i64>) {
+        let delay = self.reserve(chat_id, Instant::now());
+
+        if delay == Duration::from_secs(0) {
+            return;
+        }
+
+        let queued = Queued::new(&self.queued);
+        let depth = queued.depth();
+
+        if depth > self.max_queued.fetch_max(depth, Ordering::SeqCst) {
+            info!("Outbound queue grew to {} calls", depth);
+        }
+
+        debug!("Delaying {} by {} ms, {} calls queued", method, delay.as_millis(), depth);
+
+        tokio::time::sleep(delay).await;
+    }
+
+    fn reserve(&self, chat_id: Option<i64>, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.chats.len() > MAX_IDLE_CHATS {
+            buckets.chats.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        let global = buckets.global.reserve(now);
+        let chat = match chat_id {
+            Some(chat_id) => buckets.chats.entry(chat_id).or_insert_with(|| Bucket::for_chat(chat_id, now)).reserve(now),
+            None => Duration::from_secs(0)
+        };
+
+        cmp::max(global, chat)
+    }
+}
+
+impl Bucket {
+    fn new(burst: f64, per_sec: f64, now: Instant) -> Bucket {
+        Bucket { tokens: burst, burst, per_sec, updated: now }
+    }
+
+    // Group chats have negative ids.
+    fn for_chat(chat_id: i64, now: Instant) -> Bucket {
+        Bucket::new(CHAT_BURST, if chat_id < 0 { GROUP_CHAT_PER_SEC } else { PRIVATE_CHAT_PER_SEC }, now)
+    }
+
+    // Takes a token, going into debt when there is none, and returns how long until the debt is paid off.
+    fn reserve(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_sec)
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= self.burst
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.updated = now;
+    }
+}
+
+impl<'a> Queued<'a> {
+    fn new(queued: &'a AtomicUsize) -> Queued<'a> {
+        let depth = queued.fetch_add(1, Ordering::SeqCst) + 1;
+        metrics::set(metrics::TELEGRAM_QUEUED_CALLS, &[], depth as f64);
+
+        Queued(queued)
+    }
+
+    fn depth(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl<'a> Drop for Queued<'a> {
+    fn drop(&mut self) {
+        let depth = self.0.fetch_sub(1, Ordering::SeqCst) - 1;
+        metrics::set(metrics::TELEGRAM_QUEUED_CALLS, &[], depth as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_queues() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(3.0, 2.0, now);
+
+        for _ in 0..3 {
+            assert_eq!(bucket.reserve(now), Duration::from_secs(0));
+        }
+
+        assert_eq!(bucket.reserve(now), secs(0.5));
+        assert_eq!(bucket.reserve(now), secs(1.0));
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(2.0, 4.0, now);
+
+        bucket.reserve(now);
+        bucket.reserve(now);
+        assert_eq!(bucket.reserve(now), secs(0.25));
+
+        // The queued call has waited its turn and one more token came in since.
+        assert_eq!(bucket.reserve(now + secs(0.5)), Duration::from_secs(0));
+        assert_eq!(bucket.reserve(now + secs(0.5)), secs(0.25));
+
+        // Idling longer than the burst needs does not save up more.
+        let later = now + secs(60.0);
+        assert!(bucket.is_full(later));
+        bucket.reserve(later);
+        bucket.reserve(later);
+        assert_eq!(bucket.reserve(later), secs(0.25));
+    }
+
+    #[test]
+    fn chats_are_limited_separately() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert_eq!(limiter.reserve(Some(1), now), Duration::from_secs(0));
+        assert_eq!(limiter.reserve(Some(1), now), secs(1.0 / PRIVATE_CHAT_PER_SEC));
+        assert_eq!(limiter.reserve(Some(2), now), Duration::from_secs(0));
+
+        assert_eq!(limiter.reserve(Some(-1), now), Duration::from_secs(0));
+        assert_eq!(limiter.reserve(Some(-1), now), secs(1.0 / GROUP_CHAT_PER_SEC));
+
+        // Calls without a chat only wait for the global limit.
+        assert_eq!(limiter.reserve(None, now), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn global_limit_applies_across_chats() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        for chat_id in 0..GLOBAL_BURST as i64 {
+            assert_eq!(limiter.reserve(Some(chat_id), now), Duration::from_secs(0));
+        }
+
+        // A fresh chat still has its own token but the global bucket is empty.
+        assert_eq!(limiter.reserve(Some(1000), now), secs(1.0 / GLOBAL_PER_SEC));
+        assert_eq!(limiter.reserve(None, now), secs(2.0 / GLOBAL_PER_SEC));
+    }
+}
\ No newline at end of file
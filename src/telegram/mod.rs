@@ -1,47 +1,89 @@
+extern crate base64;
+extern crate futures;
+extern crate rand;
 extern crate reqwest;
 extern crate serde;
 extern crate serde_json;
+extern crate tokio;
 
-use std::sync::mpsc::{Sender, Receiver, TryRecvError};
-use std::sync::mpsc;
-use std::thread;
-use std::time::Duration;
-use self::reqwest::header::ContentType;
-
-static LONG_POLLING_TIMEOUT: u16 = 600;
-static TAG_MEDIA_MESSAGE: &'static str = "How would you tag this media?";
+pub mod blocking;
+mod error;
+mod limiter;
+mod progress;
+mod request;
 
-struct HttpPollClient {
-    get_updates_url: String,
-    get_updates_latest_id: Option<i64>,
-    client: reqwest::Client,
-}
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use self::futures::stream::{self, Stream};
+use self::serde::de::DeserializeOwned;
+use self::reqwest::header::CONTENT_TYPE;
+use self::limiter::RateLimiter;
+use self::request::Retry;
+use crate::data::{MediaType, ReportReason, Visibility};
+use crate::metrics;
+
+pub use self::error::Error;
+pub use self::progress::Progress;
+
+static TAG_MEDIA_MESSAGE: &str = "How would you tag this media?";
+static VISIBILITY_MESSAGE: &str = "Who can find this media?";
+static VISIBILITY_CALLBACK_PREFIX: &str = "visibility";
+static MODERATION_CALLBACK_PREFIX: &str = "moderation";
+static REPORT_MESSAGE: &str = "What is wrong with this media?";
+static REPORT_CALLBACK_PREFIX: &str = "report";
+static APPROVE_DECISION: &str = "approve";
+static REJECT_DECISION: &str = "reject";
+// Start parameters are limited to 64 letters, digits, _ and -.
+static SUGGESTION_START_PREFIX: &str = "suggest-";
+static MAX_START_PARAMETER_LEN: usize = 64;
+static JSON_CONTENT_TYPE: &str = "application/json";
+// Labels file downloads in logs and metrics like a bot API method, the file path stays out of both.
+static FILE_DOWNLOAD_METHOD: &str = "getFileContent";
+static POLL_ERROR_DELAY_SEC: u64 = 30;
+static IN_FLIGHT_RECHECK_MSEC: u64 = 250;
 
 struct HttpClient {
+    get_updates_url: String,
+    get_me_url: String,
+    get_chat_member_url: String,
     answer_inline_query_url: String,
     send_photo_url: String,
     send_document_url: String,
+    send_message_url: String,
+    answer_callback_query_url: String,
+    get_file_url: String,
+    file_download_url: String,
     client: reqwest::Client,
+    limiter: RateLimiter,
 }
 
+// Async bot API client, cheap to clone and share between tasks.
+#[derive(Clone)]
 pub struct Client {
-    receiver: Receiver<api::Update>,
-    sender: Sender<bool>,
-    http_client: HttpClient,
+    http_client: Arc<HttpClient>,
 }
 
 pub enum UpdateMessage {
     None,
-    InlineQuery { inline_query_id: String, query: String },
-    ChosenInlineResult { media_id: i64, query: String },
-    Photo { file_id: String, tags: Vec<String> },
-    Document { file_id: String, mime_type: String, tags: Vec<String> },
+    InlineQuery { inline_query_id: String, query: String, user_id: i64 },
+    ChosenInlineResult { media_id: i64, query: String, user_id: i64 },
+    Photo { file_id: String, tags: Vec<String>, chat_id: i64, user_id: i64 },
+    Document { file_id: String, mime_type: String, tags: Vec<String>, chat_id: i64, user_id: i64 },
     CallbackQuery(CallbackCommand),
-    ReplyToMessage { message_id: i64, text: String },
+    ReplyToMessage { message_id: i64, text: String, chat_id: i64, user_id: i64 },
+    // A command meant for this bot, without the leading slash and @username.
+    Command { command: String, args: Vec<String>, chat_id: i64, user_id: i64 },
+    // Any other group message, telling who is in the group.
+    ChatMember { chat_id: i64, user_id: i64, joined: bool },
 }
 
 pub enum CallbackCommand {
-    Tag { media_id: i64, user_id: i64 }
+    Tag { media_id: i64, user_id: i64 },
+    Visibility { media_id: i64, user_id: i64, visibility: Visibility, callback_query_id: String },
+    Moderation { media_id: i64, user_id: i64, approved: bool, callback_query_id: String },
+    // The report button on a result has no reason yet, the buttons asking for one do.
+    Report { media_id: i64, user_id: i64, reason: Option<ReportReason>, callback_query_id: String },
 }
 
 pub enum AnswerMessage {
@@ -56,6 +98,7 @@ mod api {
     pub struct User {
         pub id: i64,
         pub first_name: String,
+        pub username: Option<String>,
     }
 
     #[derive(Deserialize)]
@@ -87,7 +130,10 @@ mod api {
     #[derive(Serialize)]
     pub struct InlineKeyboardButton {
         pub text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
         pub callback_data: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub switch_inline_query: Option<String>,
     }
 
     #[derive(Deserialize)]
@@ -101,6 +147,15 @@ mod api {
     pub struct AnswerInlineQuery {
         pub inline_query_id: String,
         pub results: Vec<Answer>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub button: Option<InlineQueryResultsButton>,
+    }
+
+    // Shown above the results, opens a private chat with the bot that starts with /start <start_parameter>.
+    #[derive(Serialize)]
+    pub struct InlineQueryResultsButton {
+        pub text: String,
+        pub start_parameter: String,
     }
 
     #[derive(Serialize)]
@@ -147,10 +202,33 @@ mod api {
         pub callback_query: Option<CallbackQuery>,
     }
 
+    // Envelope of every bot API response, `result` is only present when ok is true.
     #[derive(Deserialize)]
-    pub struct GetUpdatesResponse {
+    pub struct ApiResponse<T> {
         pub ok: bool,
-        pub result: Vec<Update>,
+        pub result: Option<T>,
+        pub error_code: Option<u16>,
+        pub description: Option<String>,
+        pub parameters: Option<ResponseParameters>,
+    }
+
+    impl<T> ApiResponse<T> {
+        pub fn into_result(self) -> Result<T, super::Error> {
+            if !self.ok {
+                return Err(super::Error::Api {
+                    code: self.error_code.unwrap_or(0),
+                    description: self.description.unwrap_or_default(),
+                    retry_after: self.parameters.and_then(|p| p.retry_after),
+                });
+            }
+
+            self.result.ok_or(super::Error::Json(super::serde::de::Error::custom("ok response without a result")))
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub struct ResponseParameters {
+        pub retry_after: Option<u64>,
     }
 
     #[derive(Serialize)]
@@ -162,8 +240,6 @@ mod api {
     #[derive(Deserialize)]
     pub struct PhotoSize {
         pub file_id: String,
-        pub width: u32,
-        pub height: u32,
     }
 
     #[derive(Deserialize)]
@@ -172,12 +248,6 @@ mod api {
         pub mime_type: String,
     }
 
-    #[derive(Deserialize)]
-    pub struct MessageResponse {
-        pub ok: bool,
-        pub result: MinimalMessage,
-    }
-
     #[derive(Deserialize)]
     pub struct Message {
         pub message_id: i64,
@@ -185,9 +255,10 @@ mod api {
         pub caption: Option<String>,
         pub from: Option<User>,
         pub chat: Chat,
-        pub reply_to_message: Option<MinimalMessage>,
+        pub reply_to_message: Option<Box<Message>>,
         pub text: Option<String>,
         pub document: Option<Document>,
+        pub left_chat_member: Option<User>,
     }
 
     #[derive(Deserialize)]
@@ -195,17 +266,42 @@ mod api {
         pub message_id: i64
     }
 
+    #[derive(Deserialize)]
+    pub struct UploadedMessage {
+        pub photo: Option<Vec<PhotoSize>>,
+        pub document: Option<Document>,
+    }
+
+    #[derive(Serialize)]
+    pub struct GetFile {
+        pub file_id: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct File {
+        pub file_id: String,
+        pub file_path: Option<String>,
+    }
+
     #[derive(Serialize)]
     pub struct ForceReply {
         pub force_reply: bool,
     }
 
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    pub enum ReplyMarkup {
+        ForceReply(ForceReply),
+        InlineKeyboard(InlineKeyboardMarkup),
+    }
+
     #[derive(Serialize)]
     pub struct SendPhoto {
         pub chat_id: i64,
         pub photo: String,
         pub caption: String,
-        pub reply_markup: Option<ForceReply>,
+        pub reply_markup: Option<ReplyMarkup>,
+        pub has_spoiler: bool,
     }
 
     #[derive(Serialize)]
@@ -213,253 +309,398 @@ mod api {
         pub chat_id: i64,
         pub document: String,
         pub caption: String,
-        pub reply_markup: Option<ForceReply>,
+        pub reply_markup: Option<ReplyMarkup>,
+    }
+
+    #[derive(Serialize)]
+    pub struct GetChatMember {
+        pub chat_id: i64,
+        pub user_id: i64,
+    }
+
+    #[derive(Deserialize)]
+    pub struct ChatMember {
+        pub status: String,
+    }
+
+    #[derive(Serialize)]
+    pub struct SendMessage {
+        pub chat_id: i64,
+        pub text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub reply_markup: Option<InlineKeyboardMarkup>,
+    }
+
+    #[derive(Serialize)]
+    pub struct AnswerCallbackQuery {
+        pub callback_query_id: String,
+        pub text: String,
     }
 }
 
 impl Client {
-    pub fn new(api_key: String) -> Result<Client, &'static str> {
-        if api_key.len() == 0 {
+    pub fn new(api_url: &str, api_key: String) -> Result<Client, &'static str> {
+        if api_key.is_empty() {
             return Err("API key required.");
         }
 
-        let (tx, receiver): (Sender<api::Update>, Receiver<api::Update>) = mpsc::channel();
-        let (sender, rx): (Sender<bool>, Receiver<bool>) = mpsc::channel();
-        let answer_inline_query_url = format!("https://api.telegram.org/bot{}/answerInlineQuery", api_key);
-        let send_photo_url = format!("https://api.telegram.org/bot{}/sendPhoto", api_key);
-        let send_document_url = format!("https://api.telegram.org/bot{}/sendDocument", api_key);
-        let http_client = HttpClient { answer_inline_query_url, send_photo_url, send_document_url, client: reqwest::Client::new() };
+        let http_client = HttpClient {
+            get_updates_url: format!("{}/bot{}/getUpdates", api_url, api_key),
+            get_me_url: format!("{}/bot{}/getMe", api_url, api_key),
+            get_chat_member_url: format!("{}/bot{}/getChatMember", api_url, api_key),
+            answer_inline_query_url: format!("{}/bot{}/answerInlineQuery", api_url, api_key),
+            send_photo_url: format!("{}/bot{}/sendPhoto", api_url, api_key),
+            send_document_url: format!("{}/bot{}/sendDocument", api_url, api_key),
+            send_message_url: format!("{}/bot{}/sendMessage", api_url, api_key),
+            answer_callback_query_url: format!("{}/bot{}/answerCallbackQuery", api_url, api_key),
+            get_file_url: format!("{}/bot{}/getFile", api_url, api_key),
+            file_download_url: format!("{}/file/bot{}/", api_url, api_key),
+            client: reqwest::Client::new(),
+            limiter: RateLimiter::new(),
+        };
+
+        Ok(Client { http_client: Arc::new(http_client) })
+    }
+
+    // Long polls getUpdates, starting the next poll as soon as the previous batch is consumed. Yields each update
+    // with its update_id and never ends. Updates are in flight from the moment they are fetched, every one must be
+    // finished in `progress`, Telegram keeps delivering it until then.
+    // Only the bot itself should call this, one-off jobs would swallow updates meant for a running bot.
+    pub fn updates(&self, polling_timeout: u16, progress: Progress) -> impl Stream<Item = (i64, UpdateMessage)> {
+        let state = (self.http_client.clone(), progress, VecDeque::new(), None);
+
+        stream::unfold(state, move |(http_client, progress, mut pending, mut username): (Arc<HttpClient>, Progress, VecDeque<api::Update>, Option<String>)| async move {
+            loop {
+                // Group messages can only be told apart by the bot's username.
+                let name = match username {
+                    Some(ref name) => name.clone(),
+                    None => match http_client.get_me().await {
+                        Ok(me) => {
+                            username = Some(me.username.unwrap_or_default());
+                            continue;
+                        }
+                        Err(e) => {
+                            error!(error:% = e; "Failed to look up the bot's username, trying again in {} seconds", POLL_ERROR_DELAY_SEC);
+                            tokio::time::sleep(Duration::from_secs(POLL_ERROR_DELAY_SEC)).await;
+                            continue;
+                        }
+                    }
+                };
+
+                if let Some(update) = pending.pop_front() {
+                    return Some(((update.update_id, process_update(update, &name)), (http_client, progress, pending, username)));
+                }
 
-        thread::spawn(move || {
-            let get_updates_url = format!("https://api.telegram.org/bot{}/getUpdates", api_key);
-            let mut http_client = HttpPollClient { get_updates_url, get_updates_latest_id: None, client: reqwest::Client::new() };
+                match http_client.get_updates(progress.offset(), polling_timeout).await {
+                    Ok(updates) => {
+                        crate::health::polled();
 
-            http_client.poll_server(tx, rx);
-        });
+                        let fresh: Vec<api::Update> = updates.into_iter()
+                                                             .filter(|u| progress.is_new(u.update_id))
+                                                             .collect();
 
-        Ok(Client { receiver, sender, http_client })
+                        // Buffered updates hold the offset back too, or stopping before they are yielded would lose them.
+                        for update in &fresh {
+                            progress.start(update.update_id);
+                        }
+
+                        // Unfinished updates make getUpdates return at once, wait for them instead of spinning.
+                        if fresh.is_empty() && progress.is_busy() {
+                            let _ = tokio::time::timeout(Duration::from_millis(IN_FLIGHT_RECHECK_MSEC), progress.wait()).await;
+                        }
+
+                        pending.extend(fresh);
+                    }
+                    Err(e) => {
+                        error!(error:% = e; "Polling for updates gave up, trying again in {} seconds", POLL_ERROR_DELAY_SEC);
+                        tokio::time::sleep(Duration::from_secs(POLL_ERROR_DELAY_SEC)).await;
+                    }
+                }
+            }
+        })
     }
 
-    pub fn receive_update(&self) -> UpdateMessage {
-        match self.receiver.try_recv() {
-            Ok(u) => process_update(u),
-            Err(e) if e == TryRecvError::Empty => UpdateMessage::None,
-            Err(e) => panic!(e)
-        }
+    // Tells Telegram that every update before `offset` has been handled, so they are not delivered again.
+    pub async fn acknowledge(&self, offset: i64) -> Result<(), Error> {
+        self.http_client.get_updates(Some(offset), 0).await?;
+
+        Ok(())
     }
 
-    pub fn answer_inline_query(&self, inline_query_id: String, messages: Vec<AnswerMessage>) {
-        self.http_client.answer_inline_query(inline_query_id, messages);
+    // A `suggestion` is offered above the results as a tag to search for instead.
+    pub async fn answer_inline_query(&self, inline_query_id: String, messages: Vec<AnswerMessage>, suggestion: Option<String>) -> Result<(), Error> {
+        self.http_client.answer_inline_query(inline_query_id, messages, suggestion.and_then(|s| build_suggestion_button(&s))).await
     }
 
-    pub fn send_photo(&self, chat_id: i64, photo: String) -> Option<i64> {
-        self.http_client.send_photo(chat_id, photo)
+    // A spoiler photo stays blurred until it is tapped.
+    pub async fn send_photo(&self, chat_id: i64, photo: String, has_spoiler: bool) -> Result<i64, Error> {
+        self.http_client.send_photo(chat_id, photo, TAG_MEDIA_MESSAGE.to_string(), Some(force_reply()), has_spoiler).await
     }
 
-    pub fn send_document(&self, chat_id: i64, document: String) -> Option<i64> {
-        self.http_client.send_document(chat_id, document)
+    pub async fn send_document(&self, chat_id: i64, document: String) -> Result<i64, Error> {
+        self.http_client.send_document(chat_id, document, TAG_MEDIA_MESSAGE.to_string(), Some(force_reply())).await
     }
-}
 
-impl HttpPollClient {
-    pub fn poll_server(&mut self, tx: Sender<api::Update>, rx: Receiver<bool>) {
-        let mut shutdown = false;
-
-        while !shutdown {
-            match rx.try_recv() {
-                Ok(b) => shutdown = b,
-                Err(e) if e == TryRecvError::Disconnected => shutdown = true,
-                Err(_) => {
-                    for update in self.get_updates() {
-                        match tx.send(update) {
-                            Ok(_) => (),
-                            Err(_) => shutdown = true
-                        }
-                    }
-                }
-            }
+    pub async fn send_message(&self, chat_id: i64, text: String) -> Result<i64, Error> {
+        self.http_client.send_message(chat_id, text, None).await
+    }
 
-            thread::sleep(Duration::from_secs(1));
-        }
+    // Offers buttons for choosing who finds `media_id`, they come back as CallbackCommand::Visibility.
+    pub async fn ask_visibility(&self, chat_id: i64, media_id: i64) -> Result<i64, Error> {
+        self.http_client.send_message(chat_id, VISIBILITY_MESSAGE.to_string(), Some(build_visibility_keyboard(media_id))).await
+    }
+
+    // Offers buttons for why `media_id` is reported, they come back as CallbackCommand::Report with a reason.
+    pub async fn ask_report_reason(&self, chat_id: i64, media_id: i64) -> Result<i64, Error> {
+        self.http_client.send_message(chat_id, REPORT_MESSAGE.to_string(), Some(build_report_keyboard(media_id))).await
     }
 
-    fn get_updates(&mut self) -> Vec<api::Update> {
-        let url = reqwest::Url::parse(&self.get_updates_url).expect("Could not parse get_updates_url.");
-        if let Some(id) = self.get_updates_latest_id {
-            self.get_updates_latest_id = Some(id + 1);
+    // Sends media waiting for approval with Approve and Reject buttons, they come back as CallbackCommand::Moderation.
+    pub async fn ask_approval(&self, chat_id: i64, media_id: i64, media_type: &MediaType, file_id: String, caption: String, has_spoiler: bool) -> Result<i64, Error> {
+        let keyboard = Some(api::ReplyMarkup::InlineKeyboard(build_moderation_keyboard(media_id)));
+
+        match media_type {
+            &MediaType::Photo => self.http_client.send_photo(chat_id, file_id, caption, keyboard, has_spoiler).await,
+            &MediaType::Mpeg4Gif | &MediaType::ImageGif => self.http_client.send_document(chat_id, file_id, caption, keyboard).await
         }
+    }
 
-        let body = serde_json::to_string(&api::GetUpdates { timeout: LONG_POLLING_TIMEOUT, offset: self.get_updates_latest_id }).expect("Could not serialize GetUpdates");
-
-        let mut response = match self.client
-                                     .post(url)
-                                     .header(ContentType::json())
-                                     .body(body)
-                                     .send() {
-            Ok(r) => r,
-            Err(e) => {
-                warn!("POST to getUpdates failed: {}", e);
-                return Vec::new();
-            }
-        };
+    // Answers the /start of a suggestion button with a button that searches for `tag` in a chat of the user's choice.
+    pub async fn offer_search(&self, chat_id: i64, tag: String) -> Result<i64, Error> {
+        self.http_client.send_message(chat_id, format!("Search for {}:", tag), Some(build_search_keyboard(tag))).await
+    }
 
-        let body = match response.text() {
-            Ok(b) => b,
-            Err(e) => {
-                error!("No response body: {}", e);
-                return Vec::new();
-            }
-        };
+    // Shows `text` to the user who pressed an inline keyboard button.
+    pub async fn answer_callback_query(&self, callback_query_id: String, text: String) -> Result<(), Error> {
+        self.http_client.answer_callback_query(callback_query_id, text).await
+    }
 
-        info!("Response body in get_updates is {}", body);
+    // Whether `user_id` is an owner or administrator of the group `chat_id`.
+    pub async fn is_chat_admin(&self, chat_id: i64, user_id: i64) -> Result<bool, Error> {
+        let member = self.http_client.get_chat_member(chat_id, user_id).await?;
 
-        let response: api::GetUpdatesResponse = match serde_json::from_str(&body) {
-            Ok(r) => r,
-            Err(e) => {
-                error!("Failed to deserialize GetUpdatesResponse: {}", e);
-                error!("{}", body);
-                return Vec::new();
-            }
-        };
+        Ok(member.status == "creator" || member.status == "administrator")
+    }
 
-        if !response.ok {
-            warn!("API call getUpdates returned false ok");
-            return Vec::new();
-        }
+    // Asks Telegram about `file_id` without downloading it, an Err whose is_invalid_file_id() is true means the
+    // file_id can't be sent anymore.
+    pub async fn check_file(&self, file_id: String) -> Result<(), Error> {
+        self.http_client.get_file(file_id).await?;
 
-        if let Some(u) = response.result.last() {
-            self.get_updates_latest_id = Some(u.update_id);
-        }
+        Ok(())
+    }
+
+    pub async fn download_file(&self, file_id: String) -> Result<Vec<u8>, Error> {
+        let file = self.http_client.get_file(file_id).await?;
 
-        response.result
+        // Telegram leaves out the path for files too big for bots to download.
+        let file_path = file.file_path.ok_or(Error::Api {
+            code: 0,
+            description: format!("File {} is not available for download", file.file_id),
+            retry_after: None,
+        })?;
+
+        self.http_client.download_file(&file_path).await
+    }
+
+    pub async fn upload_photo(&self, chat_id: i64, photo: Vec<u8>) -> Result<String, Error> {
+        self.http_client.upload_photo(chat_id, photo).await
+    }
+
+    pub async fn upload_document(&self, chat_id: i64, document: Vec<u8>, file_name: String) -> Result<String, Error> {
+        self.http_client.upload_document(chat_id, document, file_name).await
     }
 }
 
 impl HttpClient {
-    fn answer_inline_query(&self, inline_query_id: String, messages: Vec<AnswerMessage>) {
-        let url = reqwest::Url::parse(&self.answer_inline_query_url).expect("Could not parse answer_inline_query_url.");
+    async fn get_updates(&self, offset: Option<i64>, timeout: u16) -> Result<Vec<api::Update>, Error> {
+        let body = serde_json::to_string(&api::GetUpdates { timeout, offset }).expect("Could not serialize GetUpdates");
+
+        self.post(&self.get_updates_url, "getUpdates", Retry::Idempotent, body).await
+    }
+
+    async fn get_me(&self) -> Result<api::User, Error> {
+        self.post(&self.get_me_url, "getMe", Retry::Idempotent, "{}".to_string()).await
+    }
 
+    async fn get_chat_member(&self, chat_id: i64, user_id: i64) -> Result<api::ChatMember, Error> {
+        let body = serde_json::to_string(&api::GetChatMember { chat_id, user_id }).expect("Could not serialize GetChatMember");
+
+        self.limiter.acquire("getChatMember", None).await;
+
+        self.post(&self.get_chat_member_url, "getChatMember", Retry::Idempotent, body).await
+    }
+
+    async fn answer_inline_query(&self, inline_query_id: String, messages: Vec<AnswerMessage>, button: Option<api::InlineQueryResultsButton>) -> Result<(), Error> {
         let results = messages.iter()
-                              .map(|m| build_answer_message(m))
-                              .filter(|a| a.is_some())
-                              .map(|a| a.unwrap())
+                              .filter_map(build_answer_message)
                               .collect();
 
-        let body = serde_json::to_string(&api::AnswerInlineQuery { inline_query_id, results }).expect("Could not serialize AnswerInlineQuery");
+        let body = serde_json::to_string(&api::AnswerInlineQuery { inline_query_id, results, button }).expect("Could not serialize AnswerInlineQuery");
 
-        info!("Request body in answer_inline_query is {}", body);
+        trace!(body:% = body; "Request body in answer_inline_query");
 
-        match self.client
-                  .post(url)
-                  .header(ContentType::json())
-                  .body(body)
-                  .send() {
-            Ok(_) => (),
-            Err(e) => {
-                error!("POST to answerInlineQuery failed: {}", e);
-                return;
-            }
-        };
-    }
+        self.limiter.acquire("answerInlineQuery", None).await;
 
-    fn send_photo(&self, chat_id: i64, photo: String) -> Option<i64> {
-        let url = reqwest::Url::parse(&self.send_photo_url).expect("Could not parse send_photo_url.");
+        let _: bool = self.post(&self.answer_inline_query_url, "answerInlineQuery", Retry::Idempotent, body).await?;
+
+        Ok(())
+    }
 
+    async fn send_photo(&self, chat_id: i64, photo: String, caption: String, reply_markup: Option<api::ReplyMarkup>, has_spoiler: bool) -> Result<i64, Error> {
         let body = serde_json::to_string(&api::SendPhoto {
             chat_id,
             photo,
-            caption: TAG_MEDIA_MESSAGE.to_string(),
-            reply_markup: Some(api::ForceReply { force_reply: true }),
-        }).expect("Could not serialize AnswerInlineQuery");
-
-        info!("Request body in send_photo is {}", body);
-
-        let response = match self.client
-                                 .post(url)
-                                 .header(ContentType::json())
-                                 .body(body)
-                                 .send() {
-            Ok(r) => r,
-            Err(e) => {
-                error!("POST to sendPhoto failed: {}", e);
-                return None;
-            }
-        };
+            caption,
+            reply_markup,
+            has_spoiler,
+        }).expect("Could not serialize SendPhoto");
 
-        parse_response_message_id(response)
-    }
+        trace!(body:% = body; "Request body in send_photo");
 
-    fn send_document(&self, chat_id: i64, document: String) -> Option<i64> {
-        let url = reqwest::Url::parse(&self.send_document_url).expect("Could not parse send_document_url.");
+        self.limiter.acquire("sendPhoto", Some(chat_id)).await;
 
+        let message: api::MinimalMessage = self.post(&self.send_photo_url, "sendPhoto", Retry::RejectedOnly, body).await?;
+
+        Ok(message.message_id)
+    }
+
+    async fn send_document(&self, chat_id: i64, document: String, caption: String, reply_markup: Option<api::ReplyMarkup>) -> Result<i64, Error> {
         let body = serde_json::to_string(&api::SendDocument {
             chat_id,
             document,
-            caption: TAG_MEDIA_MESSAGE.to_string(),
-            reply_markup: Some(api::ForceReply { force_reply: true }),
-        }).expect("Could not serialize AnswerInlineQuery");
-
-        info!("Request body in send_photo is {}", body);
-
-        let response = match self.client
-                                 .post(url)
-                                 .header(ContentType::json())
-                                 .body(body)
-                                 .send() {
-            Ok(r) => r,
-            Err(e) => {
-                error!("POST to sendDocument failed: {}", e);
-                return None;
-            }
-        };
+            caption,
+            reply_markup,
+        }).expect("Could not serialize SendDocument");
+
+        trace!(body:% = body; "Request body in send_document");
 
-        parse_response_message_id(response)
+        self.limiter.acquire("sendDocument", Some(chat_id)).await;
+
+        let message: api::MinimalMessage = self.post(&self.send_document_url, "sendDocument", Retry::RejectedOnly, body).await?;
+
+        Ok(message.message_id)
+    }
+
+    async fn send_message(&self, chat_id: i64, text: String, reply_markup: Option<api::InlineKeyboardMarkup>) -> Result<i64, Error> {
+        let body = serde_json::to_string(&api::SendMessage { chat_id, text, reply_markup }).expect("Could not serialize SendMessage");
+
+        trace!(body:% = body; "Request body in send_message");
+
+        self.limiter.acquire("sendMessage", Some(chat_id)).await;
+
+        let message: api::MinimalMessage = self.post(&self.send_message_url, "sendMessage", Retry::RejectedOnly, body).await?;
+
+        Ok(message.message_id)
+    }
+
+    async fn answer_callback_query(&self, callback_query_id: String, text: String) -> Result<(), Error> {
+        let body = serde_json::to_string(&api::AnswerCallbackQuery { callback_query_id, text }).expect("Could not serialize AnswerCallbackQuery");
+
+        self.limiter.acquire("answerCallbackQuery", None).await;
+
+        let _: bool = self.post(&self.answer_callback_query_url, "answerCallbackQuery", Retry::Idempotent, body).await?;
+
+        Ok(())
+    }
+
+    async fn post<T: DeserializeOwned>(&self, url: &str, method: &str, retry: Retry, body: String) -> Result<T, Error> {
+        let response = request::send(method, retry, || self.client
+                                                          .post(url)
+                                                          .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+                                                          .body(body.clone())).await?;
+
+        parse_response(method, &response.text().await?)
     }
 }
 
-fn parse_response_message_id(mut response: reqwest::Response) -> Option<i64> {
-    let body = match response.text() {
-        Ok(b) => b,
-        Err(e) => {
-            error!("No response body: {}", e);
-            return None;
-        }
-    };
+impl HttpClient {
+    async fn get_file(&self, file_id: String) -> Result<api::File, Error> {
+        let body = serde_json::to_string(&api::GetFile { file_id }).expect("Could not serialize GetFile");
 
-    info!("Response body in parse_response_message_id is {}", body);
+        trace!(body:% = body; "Request body in get_file");
 
-    let response: api::MessageResponse = match serde_json::from_str(&body) {
-        Ok(r) => r,
-        Err(e) => {
-            error!("Failed to deserialize Message: {}", e);
-            error!("{}", body);
-            return None;
-        }
-    };
+        self.limiter.acquire("getFile", None).await;
 
-    if !response.ok {
-        warn!("MessageResponse not ok");
-        return None;
+        self.post(&self.get_file_url, "getFile", Retry::Idempotent, body).await
+    }
+
+    async fn download_file(&self, file_path: &str) -> Result<Vec<u8>, Error> {
+        let url = format!("{}{}", self.file_download_url, file_path);
+
+        self.limiter.acquire(FILE_DOWNLOAD_METHOD, None).await;
+
+        let response = request::send(FILE_DOWNLOAD_METHOD, Retry::Idempotent, || self.client.get(&url)).await?;
+
+        Ok(response.bytes().await.map_err(|e| Error::Transport(e.without_url()))?.to_vec())
+    }
+
+    async fn upload_photo(&self, chat_id: i64, photo: Vec<u8>) -> Result<String, Error> {
+        let form = || reqwest::multipart::Form::new()
+            .text("chat_id", chat_id.to_string())
+            .part("photo", reqwest::multipart::Part::bytes(photo.clone()).file_name("photo.jpg"));
+
+        self.upload(&self.send_photo_url, "sendPhoto", chat_id, form).await
+    }
+
+    async fn upload_document(&self, chat_id: i64, document: Vec<u8>, file_name: String) -> Result<String, Error> {
+        let form = || reqwest::multipart::Form::new()
+            .text("chat_id", chat_id.to_string())
+            .part("document", reqwest::multipart::Part::bytes(document.clone()).file_name(file_name.clone()));
+
+        self.upload(&self.send_document_url, "sendDocument", chat_id, form).await
+    }
+
+    // Multipart forms can't be sent twice, `form` builds a fresh one for every attempt.
+    async fn upload<F>(&self, url: &str, method: &str, chat_id: i64, form: F) -> Result<String, Error>
+        where F: Fn() -> reqwest::multipart::Form
+    {
+        self.limiter.acquire(method, Some(chat_id)).await;
+
+        let response = request::send(method, Retry::RejectedOnly, || self.client.post(url).multipart(form())).await?;
+
+        let message: api::UploadedMessage = parse_response(method, &response.text().await?)?;
+
+        uploaded_file_id(message)
+    }
+}
+
+fn parse_response<T: DeserializeOwned>(method: &str, body: &str) -> Result<T, Error> {
+    trace!(method, body; "Response body of {}", method);
+
+    let result = serde_json::from_str::<api::ApiResponse<T>>(body).map_err(Error::from).and_then(|r| r.into_result());
+
+    if let Err(ref e) = result {
+        metrics::increment(metrics::TELEGRAM_ERRORS, &[("method", method), ("code", &e.code())]);
     }
 
-    Some(response.result.message_id)
+    result
+}
+
+fn uploaded_file_id(message: api::UploadedMessage) -> Result<String, Error> {
+    let file_id = match message.photo {
+        Some(photos) => photos.last().map(|p| p.file_id.clone()),
+        None => message.document.map(|d| d.file_id)
+    };
+
+    file_id.ok_or(Error::Json(serde::de::Error::custom("uploaded message has no photo or document")))
 }
 
 fn build_answer_message(message: &AnswerMessage) -> Option<api::Answer> {
     match message {
-        &AnswerMessage::Photo { ref file_id, ref media_id } => Some(api::Answer::Photo(api::InlineQueryResultCachedPhoto {
+        AnswerMessage::Photo { file_id, media_id } => Some(api::Answer::Photo(api::InlineQueryResultCachedPhoto {
             _type: "photo".to_string(),
             id: media_id.to_string(),
             photo_file_id: file_id.clone(),
             reply_markup: build_inline_keyboard(media_id),
         })),
-        &AnswerMessage::Mpeg4Gif { ref file_id, ref media_id } => Some(api::Answer::Mpeg4Gif(api::InlineQueryResultCachedMpeg4Gif {
+        AnswerMessage::Mpeg4Gif { file_id, media_id } => Some(api::Answer::Mpeg4Gif(api::InlineQueryResultCachedMpeg4Gif {
             _type: "mpeg4_gif".to_string(),
             id: media_id.to_string(),
             mpeg4_file_id: file_id.clone(),
             reply_markup: build_inline_keyboard(media_id),
         })),
-        &AnswerMessage::Gif { ref file_id, ref media_id } => Some(api::Answer::Gif(api::InlineQueryResultCachedGif {
+        AnswerMessage::Gif { file_id, media_id } => Some(api::Answer::Gif(api::InlineQueryResultCachedGif {
             _type: "gif".to_string(),
             id: media_id.to_string(),
             gif_file_id: file_id.clone(),
@@ -470,47 +711,217 @@ fn build_answer_message(message: &AnswerMessage) -> Option<api::Answer> {
 }
 
 fn build_inline_keyboard(media_id: &i64) -> Option<api::InlineKeyboardMarkup> {
-    let tag_button = api::InlineKeyboardButton { text: "🔖".to_string(), callback_data: Some(media_id.to_string()) };
+    let tag_button = api::InlineKeyboardButton { text: "🔖".to_string(), callback_data: Some(media_id.to_string()), switch_inline_query: None };
+    let report_button = api::InlineKeyboardButton {
+        text: "⚠️".to_string(),
+        callback_data: Some(format!("{}:{}", REPORT_CALLBACK_PREFIX, media_id)),
+        switch_inline_query: None,
+    };
+
+    Some(api::InlineKeyboardMarkup { inline_keyboard: vec![vec![tag_button, report_button]] })
+}
+
+fn build_visibility_keyboard(media_id: i64) -> api::InlineKeyboardMarkup {
+    let button = |text: &str, visibility: Visibility| api::InlineKeyboardButton {
+        text: text.to_string(),
+        callback_data: Some(format!("{}:{}:{}", VISIBILITY_CALLBACK_PREFIX, media_id, visibility)),
+        switch_inline_query: None,
+    };
 
-    let mut inline_keyboard = Vec::new();
-    inline_keyboard.push(tag_button);
+    api::InlineKeyboardMarkup {
+        inline_keyboard: vec![vec![button("🌍 Everyone", Visibility::Public),
+                                   button("👥 My groups", Visibility::Groups),
+                                   button("🔒 Only me", Visibility::Private)]],
+    }
+}
+
+fn build_moderation_keyboard(media_id: i64) -> api::InlineKeyboardMarkup {
+    let button = |text: &str, decision: &str| api::InlineKeyboardButton {
+        text: text.to_string(),
+        callback_data: Some(format!("{}:{}:{}", MODERATION_CALLBACK_PREFIX, media_id, decision)),
+        switch_inline_query: None,
+    };
+
+    api::InlineKeyboardMarkup {
+        inline_keyboard: vec![vec![button("✅ Approve", APPROVE_DECISION), button("❌ Reject", REJECT_DECISION)]],
+    }
+}
+
+fn build_report_keyboard(media_id: i64) -> api::InlineKeyboardMarkup {
+    let button = |text: &str, reason: ReportReason| api::InlineKeyboardButton {
+        text: text.to_string(),
+        callback_data: Some(format!("{}:{}:{}", REPORT_CALLBACK_PREFIX, media_id, reason)),
+        switch_inline_query: None,
+    };
+
+    api::InlineKeyboardMarkup {
+        inline_keyboard: vec![vec![button("🤬 Offensive", ReportReason::Offensive), button("📢 Spam", ReportReason::Spam)],
+                              vec![button("💔 Broken", ReportReason::Broken), button("❓ Other", ReportReason::Other)]],
+    }
+}
+
+fn build_search_keyboard(tag: String) -> api::InlineKeyboardMarkup {
+    let button = api::InlineKeyboardButton { text: format!("🔍 {}", tag), callback_data: None, switch_inline_query: Some(tag) };
+
+    api::InlineKeyboardMarkup { inline_keyboard: vec![vec![button]] }
+}
+
+// None when the tag does not fit in a start parameter.
+fn build_suggestion_button(tag: &str) -> Option<api::InlineQueryResultsButton> {
+    let start_parameter = format!("{}{}", SUGGESTION_START_PREFIX, base64::encode_config(tag, base64::URL_SAFE_NO_PAD));
+
+    if start_parameter.len() > MAX_START_PARAMETER_LEN {
+        return None;
+    }
+
+    Some(api::InlineQueryResultsButton { text: format!("Did you mean {}?", tag), start_parameter })
+}
 
-    let mut v = Vec::new();
-    v.push(inline_keyboard);
+// The tag of a suggestion button from the argument of the /start it sent.
+pub fn suggestion_from_start(parameter: &str) -> Option<String> {
+    if !parameter.starts_with(SUGGESTION_START_PREFIX) {
+        return None;
+    }
 
-    Some(api::InlineKeyboardMarkup { inline_keyboard: v })
+    let decoded = base64::decode_config(&parameter[SUGGESTION_START_PREFIX.len()..], base64::URL_SAFE_NO_PAD).ok()?;
+
+    String::from_utf8(decoded).ok()
+}
+
+// Media sent for tagging asks for the tags in a reply.
+fn force_reply() -> api::ReplyMarkup {
+    api::ReplyMarkup::ForceReply(api::ForceReply { force_reply: true })
 }
 
-fn process_update(update: api::Update) -> UpdateMessage {
+fn process_update(update: api::Update, username: &str) -> UpdateMessage {
     if let Some(q) = update.inline_query {
-        return UpdateMessage::InlineQuery { inline_query_id: q.id, query: q.query };
+        return UpdateMessage::InlineQuery { inline_query_id: q.id, query: q.query, user_id: q.from.id };
     }
 
     if let Some(m) = update.message {
-        return process_message(m);
+        return process_message(m, username);
     }
 
     if let Some(r) = update.chosen_inline_result {
-        let media_id = r.result_id.parse::<i64>().expect("Returned result_id not an integer");
-        return UpdateMessage::ChosenInlineResult { media_id, query: r.query };
+        // Result ids are media_ids, anything else was not sent by this bot and has nothing to count.
+        return match r.result_id.parse::<i64>() {
+            Ok(media_id) => UpdateMessage::ChosenInlineResult { media_id, query: r.query, user_id: r.from.id },
+            Err(_) => {
+                warn!("Chosen inline result has result_id {} that is not a media_id, ignoring it", r.result_id);
+                UpdateMessage::None
+            }
+        };
     }
 
     if let Some(c) = update.callback_query {
-        let media_id = c.data.parse::<i64>().expect("Returned result_id not an integer");
-        return UpdateMessage::CallbackQuery(CallbackCommand::Tag { media_id, user_id: c.from.id });
+        return process_callback_query(c).map_or(UpdateMessage::None, UpdateMessage::CallbackQuery);
     }
 
     UpdateMessage::None
 }
 
-fn process_message(message: api::Message) -> UpdateMessage {
-    let mut tags: Vec<String> = if let Some(caption) = message.caption {
-        caption.split(" ")
-               .map(|s: &str| s.to_string())
-               .collect()
-    } else {
-        Vec::new()
-    };
+// Tag buttons carry just the media_id, visibility buttons "visibility:<media_id>:<visibility>", moderation buttons
+// "moderation:<media_id>:<approve|reject>" and report buttons "report:<media_id>[:<reason>]".
+fn process_callback_query(query: api::CallbackQuery) -> Option<CallbackCommand> {
+    let user_id = query.from.id;
+    let parts: Vec<&str> = query.data.split(':').collect();
+
+    match *parts.as_slice() {
+        [media_id] => Some(CallbackCommand::Tag { media_id: media_id.parse().ok()?, user_id }),
+        [prefix, media_id, visibility] if prefix == VISIBILITY_CALLBACK_PREFIX => Some(CallbackCommand::Visibility {
+            media_id: media_id.parse().ok()?,
+            user_id,
+            visibility: visibility.parse().ok()?,
+            callback_query_id: query.id,
+        }),
+        [prefix, media_id] if prefix == REPORT_CALLBACK_PREFIX => Some(CallbackCommand::Report {
+            media_id: media_id.parse().ok()?,
+            user_id,
+            reason: None,
+            callback_query_id: query.id,
+        }),
+        [prefix, media_id, reason] if prefix == REPORT_CALLBACK_PREFIX => Some(CallbackCommand::Report {
+            media_id: media_id.parse().ok()?,
+            user_id,
+            reason: Some(reason.parse().ok()?),
+            callback_query_id: query.id,
+        }),
+        [prefix, media_id, decision] if prefix == MODERATION_CALLBACK_PREFIX => Some(CallbackCommand::Moderation {
+            media_id: media_id.parse().ok()?,
+            user_id,
+            approved: match decision {
+                d if d == APPROVE_DECISION => true,
+                d if d == REJECT_DECISION => false,
+                _ => return None
+            },
+            callback_query_id: query.id,
+        }),
+        _ => None
+    }
+}
+
+fn process_message(message: api::Message, username: &str) -> UpdateMessage {
+    let chat_id = message.chat.id;
+    let user_id = message.from.as_ref().map_or(chat_id, |u| u.id);
+
+    if let Some((command, args)) = message.text.as_ref().and_then(|t| parse_command(t, username)) {
+        // Under privacy mode a /save reply is the only way media posted in a group reaches the bot.
+        if command == "save" {
+            if let Some(reply) = message.reply_to_message {
+                return process_media(*reply, args, user_id).unwrap_or(UpdateMessage::None);
+            }
+        }
+
+        return UpdateMessage::Command { command, args, chat_id, user_id };
+    }
+
+    if message.chat._type == "private" {
+        return process_private_message(message);
+    }
+
+    if let Some(user) = message.left_chat_member {
+        return UpdateMessage::ChatMember { chat_id, user_id: user.id, joined: false };
+    }
+
+    let mention = format!("@{}", username).to_lowercase();
+
+    // Only media that mentions the bot in its caption is stored, everything else is just chatter.
+    let mentioned = message.caption.as_ref().is_some_and(|c| c.split(" ").any(|w| w.to_lowercase() == mention));
+
+    if mentioned {
+        if let Some(update) = process_media(message, Vec::new(), user_id) {
+            return update;
+        }
+    }
+
+    UpdateMessage::ChatMember { chat_id, user_id, joined: true }
+}
+
+fn process_private_message(message: api::Message) -> UpdateMessage {
+    let chat_id = message.chat.id;
+    let user_id = message.from.as_ref().map_or(chat_id, |u| u.id);
+    let reply = message.reply_to_message.as_ref().map(|r| r.message_id);
+    let text = message.text.clone();
+
+    if let Some(update) = process_media(message, Vec::new(), user_id) {
+        return update;
+    }
+
+    match (reply, text) {
+        (Some(message_id), Some(text)) => UpdateMessage::ReplyToMessage { message_id, text, chat_id, user_id },
+        _ => UpdateMessage::None
+    }
+}
+
+// The media in `message` tagged with its caption, `tags` and its author's name. Mentions are not tags.
+fn process_media(message: api::Message, mut tags: Vec<String>, user_id: i64) -> Option<UpdateMessage> {
+    let chat_id = message.chat.id;
+
+    if let Some(caption) = message.caption {
+        tags.extend(caption.split(" ")
+                           .filter(|s| !s.starts_with("@"))
+                           .map(|s: &str| s.to_string()));
+    }
 
     if let Some(from) = message.from {
         tags.push(from.first_name);
@@ -518,25 +929,156 @@ fn process_message(message: api::Message) -> UpdateMessage {
 
     if let Some(photos) = message.photo {
         if let Some(photo) = photos.last() {
-            return UpdateMessage::Photo { file_id: photo.file_id.clone(), tags };
+            return Some(UpdateMessage::Photo { file_id: photo.file_id.clone(), tags, chat_id, user_id });
         }
     }
 
     if let Some(document) = message.document {
-        return UpdateMessage::Document { file_id: document.file_id.clone(), mime_type: document.mime_type, tags };
+        return Some(UpdateMessage::Document { file_id: document.file_id.clone(), mime_type: document.mime_type, tags, chat_id, user_id });
+    }
+
+    None
+}
+
+// Splits "/command@username args" into the command and its arguments, None when it is not a command or is meant for
+// another bot.
+fn parse_command(text: &str, username: &str) -> Option<(String, Vec<String>)> {
+    if !text.starts_with("/") {
+        return None;
     }
 
-    if let Some(reply) = message.reply_to_message {
-        if let Some(text) = message.text {
-            return UpdateMessage::ReplyToMessage { message_id: reply.message_id, text };
+    let mut words = text[1..].split_whitespace();
+    let mut target = words.next()?.splitn(2, "@");
+    let command = target.next()?.to_lowercase();
+
+    if let Some(name) = target.next() {
+        if !name.eq_ignore_ascii_case(username) {
+            return None;
         }
     }
 
-    UpdateMessage::None
+    Some((command, words.map(|w| w.to_string()).collect()))
 }
 
-impl Drop for Client {
-    fn drop(&mut self) {
-        self.sender.send(true).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &str) -> Result<i64, Error> {
+        serde_json::from_str::<api::ApiResponse<i64>>(body).expect("Failed to parse envelope.").into_result()
+    }
+
+    #[test]
+    fn an_ok_response_gives_its_result() {
+        assert_eq!(response(r#"{ "ok": true, "result": 42 }"#).ok(), Some(42));
+    }
+
+    #[test]
+    fn an_ok_response_without_result_is_unexpected() {
+        match response(r#"{ "ok": true }"#) {
+            Err(Error::Json(_)) => (),
+            r => panic!("Unexpected result {:?}.", r)
+        }
+    }
+
+    #[test]
+    fn a_failed_response_gives_the_api_error() {
+        let body = r#"{ "ok": false, "error_code": 429, "description": "Too Many Requests: retry after 5", "parameters": { "retry_after": 5 } }"#;
+
+        match response(body) {
+            Err(Error::Api { code, description, retry_after }) => {
+                assert_eq!(code, 429);
+                assert_eq!(description, "Too Many Requests: retry after 5");
+                assert_eq!(retry_after, Some(5));
+            }
+            r => panic!("Unexpected result {:?}.", r)
+        }
+
+        match response(r#"{ "ok": false, "result": 42 }"#) {
+            Err(Error::Api { code: 0, ref description, retry_after: None }) if description.is_empty() => (),
+            r => panic!("Unexpected result {:?}.", r)
+        }
+    }
+
+    static USERNAME: &str = "MehuBot";
+    static GROUP: &str = r#""chat": { "id": -100, "type": "group" }"#;
+    static ALICE: &str = r#""from": { "id": 7, "first_name": "Alice" }"#;
+    static PHOTO: &str = r#""photo": [{ "file_id": "small" }, { "file_id": "large" }]"#;
+
+    fn message(fields: &[&str]) -> api::Message {
+        let json = format!("{{ \"message_id\": 1, {} }}", fields.join(", "));
+        serde_json::from_str(&json).expect("Failed to parse message.")
+    }
+
+    fn command(text: &str) -> Option<(String, Vec<String>)> {
+        parse_command(text, USERNAME)
+    }
+
+    fn is_member(update: UpdateMessage, joined: bool) -> bool {
+        match update {
+            UpdateMessage::ChatMember { chat_id: -100, user_id: 7, joined: j } => j == joined,
+            _ => false
+        }
+    }
+
+    #[test]
+    fn commands_are_split_into_name_and_arguments() {
+        assert_eq!(command("/stats"), Some(("stats".to_string(), vec![])));
+        assert_eq!(command("/Tags  cat   dog "), Some(("tags".to_string(), vec!["cat".to_string(), "dog".to_string()])));
+        assert_eq!(command("/tags@mehubot cat"), Some(("tags".to_string(), vec!["cat".to_string()])));
+    }
+
+    #[test]
+    fn only_commands_for_this_bot_are_parsed() {
+        assert_eq!(command("/tags@OtherBot cat"), None);
+        assert_eq!(command("tags"), None);
+        assert_eq!(command("cat /tags"), None);
+        assert_eq!(command("/"), None);
+    }
+
+    #[test]
+    fn group_media_is_stored_only_when_its_caption_mentions_the_bot() {
+        let caption = r#""caption": "@mehubot cute cat""#;
+
+        match process_message(message(&[GROUP, ALICE, PHOTO, caption]), USERNAME) {
+            UpdateMessage::Photo { file_id, tags, chat_id: -100, user_id: 7 } => {
+                assert_eq!(file_id, "large");
+                assert_eq!(tags, vec!["cute", "cat", "Alice"]);
+            }
+            _ => panic!("The mentioned photo was not stored.")
+        }
+
+        let caption = r#""caption": "@MehuBotFan cute cat""#;
+        assert!(is_member(process_message(message(&[GROUP, ALICE, PHOTO, caption]), USERNAME), true));
+        assert!(is_member(process_message(message(&[GROUP, ALICE, PHOTO]), USERNAME), true));
+
+        let left = r#""left_chat_member": { "id": 7, "first_name": "Alice" }"#;
+        assert!(is_member(process_message(message(&[GROUP, r#""from": { "id": 8, "first_name": "Bob" }"#, left]), USERNAME), false));
+    }
+
+    #[test]
+    fn save_replies_store_the_media_replied_to_for_the_sender() {
+        let reply = r#""reply_to_message": { "message_id": 0, "chat": { "id": -100, "type": "group" }, "from": { "id": 8, "first_name": "Bob" }, "photo": [{ "file_id": "large" }], "caption": "cat @someone" }"#;
+
+        match process_message(message(&[GROUP, ALICE, reply, r#""text": "/save@MehuBot funny""#]), USERNAME) {
+            UpdateMessage::Photo { file_id, tags, chat_id: -100, user_id: 7 } => {
+                assert_eq!(file_id, "large");
+                assert_eq!(tags, vec!["funny", "cat", "Bob"]);
+            }
+            _ => panic!("The photo replied to was not stored.")
+        }
+
+        let text_reply = r#""reply_to_message": { "message_id": 0, "chat": { "id": -100, "type": "group" }, "text": "cat" }"#;
+        assert!(matches!(process_message(message(&[GROUP, ALICE, text_reply, r#""text": "/save""#]), USERNAME), UpdateMessage::None));
+
+        assert!(is_member(process_message(message(&[GROUP, ALICE, reply, r#""text": "/save@OtherBot""#]), USERNAME), true));
+
+        match process_message(message(&[GROUP, ALICE, r#""text": "/save funny""#]), USERNAME) {
+            UpdateMessage::Command { command, args, chat_id: -100, user_id: 7 } => {
+                assert_eq!(command, "save");
+                assert_eq!(args, vec!["funny"]);
+            }
+            _ => panic!("A /save without reply was not a command.")
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use super::tokio::sync::Notify;
+
+// Tracks which received updates are still being handled. getUpdates only confirms updates before `offset`,
+// so whatever was in flight when the bot stopped is delivered again.
+#[derive(Clone)]
+pub struct Progress {
+    state: Arc<Mutex<State>>,
+    finished: Arc<Notify>,
+}
+
+struct State {
+    in_flight: BTreeSet<i64>,
+    next: Option<i64>,
+}
+
+impl Progress {
+    // `offset` is where handling left off the last time.
+    pub fn new(offset: Option<i64>) -> Progress {
+        Progress {
+            state: Arc::new(Mutex::new(State { in_flight: BTreeSet::new(), next: offset })),
+            finished: Arc::new(Notify::new()),
+        }
+    }
+
+    // The first update not yet handled.
+    pub fn offset(&self) -> Option<i64> {
+        let state = self.state.lock().unwrap();
+
+        state.in_flight.iter().next().cloned().or(state.next)
+    }
+
+    // Returns the new offset if it moved.
+    pub fn finish(&self, update_id: i64) -> Option<i64> {
+        let mut state = self.state.lock().unwrap();
+        let before = state.in_flight.iter().next().cloned();
+
+        state.in_flight.remove(&update_id);
+        self.finished.notify_one();
+
+        if before == Some(update_id) {
+            state.in_flight.iter().next().cloned().or(state.next)
+        } else {
+            None
+        }
+    }
+
+    // Updates that were handed out already come back until they are finished, those are not new.
+    pub(super) fn is_new(&self, update_id: i64) -> bool {
+        self.state.lock().unwrap().next.is_none_or(|next| update_id >= next)
+    }
+
+    pub(super) fn start(&self, update_id: i64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.in_flight.insert(update_id);
+        state.next = Some(update_id + 1);
+    }
+
+    pub(super) fn is_busy(&self) -> bool {
+        !self.state.lock().unwrap().in_flight.is_empty()
+    }
+
+    pub(super) async fn wait(&self) {
+        self.finished.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_waits_for_the_oldest_unfinished_update() {
+        let progress = Progress::new(None);
+        assert_eq!(progress.offset(), None);
+
+        for update_id in 1..=3 {
+            progress.start(update_id);
+        }
+
+        assert_eq!(progress.finish(3), None);
+        assert_eq!(progress.finish(2), None);
+        assert_eq!(progress.offset(), Some(1));
+        assert!(progress.is_busy());
+
+        assert_eq!(progress.finish(1), Some(4));
+        assert_eq!(progress.offset(), Some(4));
+        assert!(!progress.is_busy());
+    }
+
+    #[test]
+    fn offset_moves_to_the_next_unfinished_update() {
+        let progress = Progress::new(None);
+
+        for update_id in 1..=3 {
+            progress.start(update_id);
+        }
+
+        assert_eq!(progress.finish(1), Some(2));
+        assert_eq!(progress.finish(3), None);
+        assert_eq!(progress.finish(2), Some(4));
+    }
+
+    #[test]
+    fn started_updates_are_not_new() {
+        let progress = Progress::new(None);
+        assert!(progress.is_new(5));
+
+        progress.start(5);
+        assert!(!progress.is_new(4));
+        assert!(!progress.is_new(5));
+        assert!(progress.is_new(6));
+
+        // Redelivered while in flight and after being finished alike.
+        progress.finish(5);
+        assert!(!progress.is_new(5));
+    }
+
+    #[test]
+    fn restart_continues_from_the_persisted_offset() {
+        let progress = Progress::new(Some(10));
+        assert_eq!(progress.offset(), Some(10));
+        assert!(!progress.is_new(9));
+        assert!(progress.is_new(10));
+
+        progress.start(10);
+        progress.start(11);
+        assert_eq!(progress.offset(), Some(10));
+        assert_eq!(progress.finish(10), Some(11));
+        assert_eq!(progress.finish(11), Some(12));
+    }
+}
\ No newline at end of file
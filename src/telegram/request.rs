@@ -0,0 +1,182 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+use super::rand::{self, Rng};
+use super::reqwest::{RequestBuilder, Response};
+use super::serde::de::IgnoredAny;
+use super::{api, serde_json, tokio, Error};
+use crate::metrics;
+
+static RETRY_BASE_DELAY_MSEC: u64 = 500;
+static RETRY_MAX_DELAY_SEC: u64 = 30;
+static RETRY_LIMIT_SEC: u64 = 60;
+
+// Whether a call may be sent again when it is not known if Telegram received it.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Retry {
+    Idempotent,
+    // Only retried when Telegram certainly did not act on it, e.g. it was rate limited or never connected.
+    RejectedOnly,
+}
+
+// Sends the request built by `request` until it succeeds, retrying what `retry` allows with exponential backoff and
+// jitter or after Telegram's `retry_after`, for at most RETRY_LIMIT_SEC in total. Transport errors leave out the URL,
+// it holds the API key and for downloads the file path, `method` says what was called.
+pub async fn send<F>(method: &str, retry: Retry, request: F) -> Result<Response, Error>
+    where F: Fn() -> RequestBuilder
+{
+    let started = Instant::now();
+    let mut attempt = 0;
+
+    loop {
+        let error = match request().send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!(method, attempt, latency_ms = started.elapsed().as_millis() as u64; "Called {}", method);
+                return Ok(response);
+            }
+            Ok(response) => failed_response(response).await,
+            Err(e) => Error::Transport(e.without_url())
+        };
+
+        metrics::increment(metrics::TELEGRAM_ERRORS, &[("method", method), ("code", &error.code())]);
+
+        let delay = match retry_delay(&error, retry, attempt, started.elapsed()) {
+            Some(delay) => delay,
+            None => return Err(error)
+        };
+
+        warn!(error:% = error, method, attempt; "{} failed, retrying in {} ms", method, delay.as_millis());
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Bot API errors come with a JSON description, anything else (a proxy, a file download) is kept as plain HTTP.
+async fn failed_response(response: Response) -> Error {
+    let status = response.status().as_u16();
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(e) => return Error::Transport(e)
+    };
+
+    match serde_json::from_str::<api::ApiResponse<IgnoredAny>>(&body) {
+        Ok(ref envelope) if !envelope.ok => Error::Api {
+            code: envelope.error_code.unwrap_or(status),
+            description: envelope.description.clone().unwrap_or_default(),
+            retry_after: envelope.parameters.as_ref().and_then(|p| p.retry_after),
+        },
+        _ => Error::Http { status, body }
+    }
+}
+
+// How long to wait before sending again, None when the error is final or waiting would exceed RETRY_LIMIT_SEC
+// since the first attempt, `elapsed` ago.
+fn retry_delay(error: &Error, retry: Retry, attempt: u32, elapsed: Duration) -> Option<Duration> {
+    let delay = match error {
+        &Error::Api { retry_after: Some(seconds), .. } => Some(Duration::from_secs(seconds)),
+        &Error::Api { code: 429, .. } | &Error::Http { status: 429, .. } => Some(backoff(attempt)),
+        &Error::Api { code, .. } | &Error::Http { status: code, .. } if code >= 500 && retry == Retry::Idempotent => Some(backoff(attempt)),
+        // A request that never connected can't have reached Telegram.
+        Error::Transport(e) if e.is_connect() => Some(backoff(attempt)),
+        &Error::Transport(_) if retry == Retry::Idempotent => Some(backoff(attempt)),
+        _ => None
+    };
+
+    delay.filter(|delay| elapsed + *delay <= Duration::from_secs(RETRY_LIMIT_SEC))
+}
+
+// Doubles with each attempt up to RETRY_MAX_DELAY_SEC, picking a random point in the upper half so that
+// clients failing together don't retry together.
+fn backoff(attempt: u32) -> Duration {
+    let max = cmp::min(RETRY_BASE_DELAY_MSEC.saturating_mul(1 << cmp::min(attempt, 16)), RETRY_MAX_DELAY_SEC * 1000);
+
+    Duration::from_millis(rand::thread_rng().gen_range(max / 2..=max))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+    use super::super::reqwest;
+
+    fn api_error(code: u16, retry_after: Option<u64>) -> Error {
+        Error::Api { code, description: String::new(), retry_after }
+    }
+
+    // A request that reached a server which hung up without answering.
+    fn sent_transport_error() -> Error {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+        });
+
+        Error::Transport(reqwest::blocking::get(&url).unwrap_err())
+    }
+
+    // A request to a port nobody listens on.
+    fn connect_transport_error() -> Error {
+        let url = format!("http://{}", TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+
+        Error::Transport(reqwest::blocking::get(&url).unwrap_err())
+    }
+
+    #[test]
+    fn honors_retry_after() {
+        let delay = retry_delay(&api_error(429, Some(7)), Retry::RejectedOnly, 5, Duration::from_secs(0));
+
+        assert_eq!(delay, Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        for attempt in 0..20 {
+            let max = cmp::min(RETRY_BASE_DELAY_MSEC << cmp::min(attempt, 16), RETRY_MAX_DELAY_SEC * 1000);
+            let delay = backoff(attempt).as_millis() as u64;
+
+            assert!(delay >= max / 2 && delay <= max, "attempt {} waited {} ms", attempt, delay);
+        }
+
+        assert!(backoff(0) <= Duration::from_millis(RETRY_BASE_DELAY_MSEC));
+        assert!(backoff(40) <= Duration::from_secs(RETRY_MAX_DELAY_SEC));
+        assert!(backoff(40) >= Duration::from_secs(RETRY_MAX_DELAY_SEC / 2));
+    }
+
+    #[test]
+    fn stops_when_the_retry_limit_would_be_exceeded() {
+        let error = api_error(429, Some(10));
+
+        assert!(retry_delay(&error, Retry::Idempotent, 0, Duration::from_secs(RETRY_LIMIT_SEC - 10)).is_some());
+        assert!(retry_delay(&error, Retry::Idempotent, 0, Duration::from_secs(RETRY_LIMIT_SEC - 9)).is_none());
+        assert!(retry_delay(&api_error(429, Some(RETRY_LIMIT_SEC + 1)), Retry::Idempotent, 0, Duration::from_secs(0)).is_none());
+    }
+
+    #[test]
+    fn rejected_only_does_not_resend_what_telegram_may_have_received() {
+        let started = Duration::from_secs(0);
+
+        assert!(retry_delay(&api_error(502, None), Retry::RejectedOnly, 0, started).is_none());
+        assert!(retry_delay(&sent_transport_error(), Retry::RejectedOnly, 0, started).is_none());
+
+        assert!(retry_delay(&api_error(502, None), Retry::Idempotent, 0, started).is_some());
+        assert!(retry_delay(&sent_transport_error(), Retry::Idempotent, 0, started).is_some());
+    }
+
+    #[test]
+    fn rejected_only_retries_what_never_reached_telegram() {
+        let started = Duration::from_secs(0);
+
+        assert!(retry_delay(&api_error(429, None), Retry::RejectedOnly, 0, started).is_some());
+        assert!(retry_delay(&Error::Http { status: 429, body: String::new() }, Retry::RejectedOnly, 0, started).is_some());
+        assert!(retry_delay(&connect_transport_error(), Retry::RejectedOnly, 0, started).is_some());
+    }
+
+    #[test]
+    fn does_not_retry_bad_requests() {
+        assert!(retry_delay(&api_error(400, None), Retry::Idempotent, 0, Duration::from_secs(0)).is_none());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,163 @@
+#[macro_use]
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use self::serde_json::Value;
+
+static UPDATE_ID: i64 = 7;
+static ANSWER_DELAY_MSEC: u64 = 1500;
+static EXIT_TIMEOUT_SEC: u64 = 20;
+
+// A fake Bot API: serves one inline query, holds every other poll open and answers the query slowly,
+// so the bot is still working on it when the signal arrives. Calls are logged in the order they finished.
+struct FakeApi {
+    calls: Arc<Mutex<Vec<(String, Value)>>>,
+    served: Arc<Mutex<bool>>,
+    answering: Sender<()>,
+}
+
+impl FakeApi {
+    fn handle(&self, stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone stream."));
+        let mut stream = stream;
+
+        loop {
+            let mut request_line = String::new();
+
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+
+            let mut length = 0;
+
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).expect("Failed to read header.");
+
+                if header.trim().is_empty() {
+                    break;
+                }
+
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().expect("Invalid content length.");
+                    }
+                }
+            }
+
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).expect("Failed to read body.");
+
+            let method = request_line.split(' ').nth(1).and_then(|p| p.rsplit('/').next()).unwrap_or("").to_string();
+            let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            let result = self.result(&method, &body);
+
+            self.calls.lock().unwrap().push((method, body));
+
+            let response = json!({ "ok": true, "result": result }).to_string();
+            let written = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", response.len(), response);
+
+            // An aborted poll closes the connection before its answer.
+            if written.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn result(&self, method: &str, body: &Value) -> Value {
+        match method {
+            "getMe" => json!({ "id": 42, "first_name": "Mehu", "username": "mehu_bot" }),
+            "getUpdates" if body["timeout"] == 0 => json!([]),
+            "getUpdates" => {
+                let first = !std::mem::replace(&mut *self.served.lock().unwrap(), true);
+
+                if first {
+                    json!([{
+                        "update_id": UPDATE_ID,
+                        "inline_query": { "id": "q1", "from": { "id": 5, "first_name": "U5" }, "query": "cat", "offset": "" }
+                    }])
+                } else {
+                    // Held until the bot gives up on the poll.
+                    thread::sleep(Duration::from_secs(EXIT_TIMEOUT_SEC));
+                    json!([])
+                }
+            }
+            "answerInlineQuery" => {
+                self.answering.send(()).unwrap();
+                thread::sleep(Duration::from_millis(ANSWER_DELAY_MSEC));
+                Value::Bool(true)
+            }
+            _ => Value::Bool(true)
+        }
+    }
+}
+
+#[test]
+fn sigterm_finishes_in_flight_updates_and_acknowledges_them() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind fake API.");
+    let api_url = format!("http://{}", listener.local_addr().unwrap());
+    let (answering, answer_started) = mpsc::channel();
+    let api = Arc::new(FakeApi { calls: Arc::new(Mutex::new(Vec::new())), served: Arc::new(Mutex::new(false)), answering });
+    let calls = api.calls.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let api = api.clone();
+            thread::spawn(move || api.handle(stream));
+        }
+    });
+
+    let dir = env::temp_dir().join(format!("mehubot-shutdown-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("Failed to create test directory.");
+
+    let mut bot = Command::new(env!("CARGO_BIN_EXE_mehubot"))
+        .args(["--api-url", &api_url, "--api-key", "123:test", "--database"])
+        .arg(dir.join("db.sqlite"))
+        .env_remove("MEHU_CONFIG")
+        .current_dir(&dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start the bot.");
+
+    answer_started.recv_timeout(Duration::from_secs(EXIT_TIMEOUT_SEC)).expect("The bot never answered the inline query.");
+
+    let killed = Command::new("kill").args(["-TERM", &bot.id().to_string()]).status().expect("Failed to run kill.");
+    assert!(killed.success());
+
+    let started = Instant::now();
+
+    let status = loop {
+        if let Some(status) = bot.try_wait().expect("Failed to wait for the bot.") {
+            break status;
+        }
+
+        if started.elapsed() > Duration::from_secs(EXIT_TIMEOUT_SEC) {
+            bot.kill().ok();
+            panic!("The bot did not exit after SIGTERM.");
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    fs::remove_dir_all(&dir).ok();
+
+    assert!(status.success(), "The bot exited with {}", status);
+
+    let calls = calls.lock().unwrap();
+    let answered = calls.iter().position(|(method, _)| method == "answerInlineQuery").expect("The in-flight answer was dropped.");
+    let acknowledged = calls.iter()
+                            .position(|(method, body)| method == "getUpdates" && body["timeout"] == 0)
+                            .expect("The bot did not acknowledge the handled update.");
+
+    assert!(answered < acknowledged, "The update was acknowledged before it was handled.");
+    assert_eq!(calls[acknowledged].1["offset"], UPDATE_ID + 1);
+}
\ No newline at end of file